@@ -0,0 +1,184 @@
+#![doc = include_str!("../README.md")]
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+
+use anyhow::{Result, bail};
+use omnia_wasi_keyvalue::Bucket;
+
+/// Maximum number of cached handles per [`Buckets`].
+pub const CAPACITY: usize = 256;
+
+/// Maximum number of distinct names [`intern`] allocates.
+pub const MAX_NAMES: usize = 1024;
+
+static NAMES: LazyLock<Names> = LazyLock::new(|| Names::new(MAX_NAMES));
+
+/// Intern `name`, allocating it the first time it is seen.
+///
+/// # Errors
+///
+/// Interned names live for the rest of the process, so only [`MAX_NAMES`]
+/// are kept; interning any further name fails.
+pub fn intern(name: &str) -> Result<&'static str> {
+    NAMES.intern(name)
+}
+
+/// A bounded set of interned names.
+struct Names {
+    names: Mutex<HashSet<&'static str>>,
+    max: usize,
+}
+
+impl Names {
+    fn new(max: usize) -> Self {
+        Self {
+            names: Mutex::new(HashSet::new()),
+            max,
+        }
+    }
+
+    fn intern(&self, name: &str) -> Result<&'static str> {
+        let mut names = self.names.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(interned) = names.get(name) {
+            return Ok(interned);
+        }
+        if names.len() >= self.max {
+            drop(names);
+            bail!("cannot open bucket {name}: {} distinct bucket names are already open", self.max);
+        }
+        let interned: &'static str = Box::leak(name.to_owned().into_boxed_str());
+        names.insert(interned);
+        drop(names);
+        Ok(interned)
+    }
+}
+
+/// Opened bucket handles, keyed by identifier.
+pub struct Buckets<B: ?Sized = dyn Bucket> {
+    inner: Mutex<Handles<B>>,
+}
+
+struct Handles<B: ?Sized> {
+    entries: HashMap<String, Entry<B>>,
+    /// Monotonic counter recording the order handles were last opened.
+    clock: u64,
+}
+
+struct Entry<B: ?Sized> {
+    bucket: Arc<B>,
+    last_used: u64,
+}
+
+impl<B: ?Sized> Default for Buckets<B> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(Handles {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+}
+
+impl<B: ?Sized> Debug for Buckets<B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let open = self.inner.lock().unwrap_or_else(PoisonError::into_inner).entries.len();
+        f.debug_struct("Buckets").field("open", &open).finish()
+    }
+}
+
+impl<B: ?Sized> Buckets<B> {
+    /// The cached handle for `identifier`, if any.
+    pub fn get(&self, identifier: &str) -> Option<Arc<B>> {
+        let mut handles = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        handles.clock += 1;
+        let clock = handles.clock;
+
+        let entry = handles.entries.get_mut(identifier)?;
+        entry.last_used = clock;
+        let bucket = Arc::clone(&entry.bucket);
+        drop(handles);
+        Some(bucket)
+    }
+
+    /// Cache `bucket` under `identifier`, evicting the least recently used
+    /// handle when full. If another task cached the same identifier first,
+    /// its handle is kept and returned instead.
+    pub fn insert(&self, identifier: &str, bucket: Arc<B>) -> Arc<B> {
+        let mut handles = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        handles.clock += 1;
+        let clock = handles.clock;
+
+        if let Some(entry) = handles.entries.get_mut(identifier) {
+            entry.last_used = clock;
+            return Arc::clone(&entry.bucket);
+        }
+
+        if handles.entries.len() >= CAPACITY
+            && let Some(oldest) =
+                handles.entries.iter().min_by_key(|(_, e)| e.last_used).map(|(id, _)| id.clone())
+        {
+            tracing::debug!("evicting bucket handle: {oldest}");
+            handles.entries.remove(&oldest);
+        }
+
+        handles.entries.insert(
+            identifier.to_owned(),
+            Entry {
+                bucket: Arc::clone(&bucket),
+                last_used: clock,
+            },
+        );
+        bucket
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interned_once() {
+        let names = Names::new(MAX_NAMES);
+        let first = names.intern("orders").unwrap();
+        let second = names.intern(&String::from("orders")).unwrap();
+        assert!(std::ptr::eq(first, second));
+    }
+
+    #[test]
+    fn interning_is_bounded() {
+        let names = Names::new(2);
+        let kept = names.intern("kept").unwrap();
+        names.intern("bounded").unwrap();
+
+        let err = names.intern("one-too-many").unwrap_err();
+        assert!(err.to_string().contains("2 distinct bucket names"), "{err}");
+        // names interned before the cap are still returned
+        assert!(std::ptr::eq(names.intern("kept").unwrap(), kept));
+    }
+
+    #[test]
+    fn first_insert_wins() {
+        let buckets = Buckets::<str>::default();
+        let first = buckets.insert("a", Arc::from("first"));
+        let second = buckets.insert("a", Arc::from("second"));
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let buckets = Buckets::<str>::default();
+        for i in 0..CAPACITY {
+            buckets.insert(&format!("b{i}"), Arc::from("bucket"));
+        }
+        // touch the oldest so `b1` becomes the eviction candidate
+        assert!(buckets.get("b0").is_some());
+        buckets.insert("overflow", Arc::from("bucket"));
+
+        assert!(buckets.get("b0").is_some());
+        assert!(buckets.get("b1").is_none());
+        assert!(buckets.get("overflow").is_some());
+    }
+}
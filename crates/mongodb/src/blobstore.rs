@@ -1,11 +1,16 @@
 use std::fmt::Debug;
 use std::sync::Arc;
 
-use anyhow::{Context, anyhow};
+use anyhow::{Context, anyhow, bail};
 use chrono::Utc;
+use futures::io::{self, AsyncReadExt, AsyncWriteExt};
 use futures::{FutureExt, StreamExt};
-use mongodb::Collection;
-use mongodb::bson::{self, Bson, Document};
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{self, Binary, Bson, Document};
+use mongodb::error::ErrorKind;
+use mongodb::gridfs::GridFsBucket;
+use mongodb::options::{GridFsBucketOptions, IndexOptions, ReturnDocument};
+use mongodb::{Collection, Database, IndexModel};
 use omnia_wasi_blobstore::{
     Bytes, Container, ContainerMetadata, FutureResult, ObjectMetadata, WasiBlobstoreCtx,
 };
@@ -13,63 +18,285 @@ use serde::{Deserialize, Serialize};
 
 use crate::Client;
 
+/// Objects larger than this are stored in `GridFS`, leaving headroom below
+/// MongoDB's 16 MB document limit for the rest of the blob document.
+const INLINE_LIMIT: usize = 15 * 1024 * 1024;
+
+/// Collection recording when each container was created.
+const CONTAINERS: &str = "omnia.containers";
+
+/// Server error code for creating a collection that already exists.
+const NAMESPACE_EXISTS: i32 = 48;
+
 /// A stored blob document in MongoDB.
+///
+/// The object's bytes are held inline as BSON binary `data`, or in the
+/// container's `GridFS` bucket under `file_id` when they exceed
+/// [`INLINE_LIMIT`]. Blobs written before binary storage hold a JSON
+/// document in `data` instead.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Blob {
     name: String,
-    data: Document,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<Bson>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    file_id: Option<Bson>,
     size: u64,
     created_at: u64,
 }
 
+/// A container's creation record in the [`CONTAINERS`] collection. MongoDB
+/// does not track when a collection was created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ContainerRecord {
+    #[serde(rename = "_id")]
+    name: String,
+    created_at: u64,
+}
+
 /// `wasi-blobstore` implementation backed by MongoDB collections.
 impl WasiBlobstoreCtx for Client {
     fn create_container(&self, name: String) -> FutureResult<Arc<dyn Container>> {
         tracing::trace!("creating container: {name}");
-        let client = self.0.clone();
+        let client = self.clone();
 
         async move {
-            let db = client.default_database().ok_or_else(|| anyhow!("No default database"))?;
-            let collection = db.collection::<Blob>(&name);
-            Ok(Arc::new(MongoDbContainer { name, collection }) as Arc<dyn Container>)
+            let (db, collection) = client.resolve(&name)?;
+            match db.create_collection(collection).await {
+                Ok(()) => {}
+                Err(e) if is_error(&e, NAMESPACE_EXISTS) => {}
+                Err(e) => return Err(e).context("creating container"),
+            }
+
+            // object names are unique, which also lets concurrent upserts of
+            // a new name be retried by the server rather than duplicated
+            let index = IndexModel::builder()
+                .keys(bson::doc! { "name": 1 })
+                .options(IndexOptions::builder().unique(true).build())
+                .build();
+            db.collection::<Blob>(collection)
+                .create_index(index)
+                .await
+                .context("indexing container")?;
+
+            let record = client
+                .database()?
+                .collection::<ContainerRecord>(CONTAINERS)
+                .find_one_and_update(
+                    bson::doc! { "_id": &name },
+                    bson::doc! { "$setOnInsert": { "created_at": Utc::now().timestamp() } },
+                )
+                .upsert(true)
+                .return_document(ReturnDocument::After)
+                .await
+                .context("recording container")?;
+            let created_at = record.map_or(0, |r| r.created_at);
+
+            Ok(Arc::new(client.container(name, created_at)?) as Arc<dyn Container>)
         }
         .boxed()
     }
 
     fn get_container(&self, name: String) -> FutureResult<Arc<dyn Container>> {
         tracing::trace!("getting container: {name}");
-        let client = self.0.clone();
+        let client = self.clone();
 
         async move {
-            let db = client.default_database().ok_or_else(|| anyhow!("No default database"))?;
-            let collection = db.collection::<Blob>(&name);
-            Ok(Arc::new(MongoDbContainer { name, collection }) as Arc<dyn Container>)
+            let record = client
+                .database()?
+                .collection::<ContainerRecord>(CONTAINERS)
+                .find_one(bson::doc! { "_id": &name })
+                .await
+                .context("getting container record")?;
+            let created_at = record.map_or(0, |r| r.created_at);
+            Ok(Arc::new(client.container(name, created_at)?) as Arc<dyn Container>)
         }
         .boxed()
     }
 
     fn delete_container(&self, name: String) -> FutureResult<()> {
         tracing::trace!("deleting container: {name}");
-        let client = self.0.clone();
+        let client = self.clone();
 
         async move {
-            let db = client.default_database().ok_or_else(|| anyhow!("No default database"))?;
-            db.collection::<Blob>(&name).drop().await.context("deleting container")
+            let db = client.database()?;
+            let container = client.container(name, 0)?;
+            container.collection.drop().await.context("deleting container")?;
+            container.files.drop().await.context("deleting container files")?;
+            db.collection::<ContainerRecord>(CONTAINERS)
+                .delete_one(bson::doc! { "_id": &container.name })
+                .await
+                .context("deleting container record")?;
+            Ok(())
         }
         .boxed()
     }
 
     fn container_exists(&self, name: String) -> FutureResult<bool> {
         tracing::trace!("checking existence of container: {name}");
-        async move { Ok(true) }.boxed()
+        let client = self.clone();
+
+        async move {
+            let (db, collection) = client.resolve(&name)?;
+            let names = db
+                .list_collection_names()
+                .filter(bson::doc! { "name": collection })
+                .await
+                .context("listing collections")?;
+            Ok(!names.is_empty())
+        }
+        .boxed()
+    }
+}
+
+impl Client {
+    fn container(&self, name: String, created_at: u64) -> anyhow::Result<MongoDbContainer> {
+        if name == CONTAINERS {
+            bail!("container name {name} is reserved");
+        }
+        let (db, collection) = self.resolve(&name)?;
+        Ok(MongoDbContainer {
+            collection: db.collection::<Blob>(collection),
+            files: files(&db, collection),
+            legacy_blobs: self.legacy_blobs,
+            created_at,
+            name,
+        })
     }
 }
 
+/// The `GridFS` bucket holding a container's large objects, stored in the
+/// `{container}.files` and `{container}.chunks` collections.
+fn files(db: &Database, container: &str) -> GridFsBucket {
+    db.gridfs_bucket(GridFsBucketOptions::builder().bucket_name(container.to_owned()).build())
+}
+
+fn is_error(error: &mongodb::error::Error, code: i32) -> bool {
+    matches!(error.kind.as_ref(), ErrorKind::Command(e) if e.code == code)
+}
+
 /// A blobstore container backed by a MongoDB collection.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MongoDbContainer {
     name: String,
     collection: Collection<Blob>,
+    files: GridFsBucket,
+    legacy_blobs: bool,
+    created_at: u64,
+}
+
+impl MongoDbContainer {
+    /// Read `start..=end` of a blob's bytes.
+    async fn read(&self, blob: Blob, start: u64, end: u64) -> anyhow::Result<Vec<u8>> {
+        match (blob.data, blob.file_id) {
+            (Some(Bson::Binary(binary)), _) => slice(binary.bytes, start, end),
+            (Some(Bson::Document(document)), _) if self.legacy_blobs => {
+                slice(legacy_bytes(&document)?, start, end)
+            }
+            (Some(Bson::Document(_)), _) => bail!(
+                "object {} is stored in the legacy JSON format; set MONGODB_LEGACY_BLOBS=true to read it",
+                blob.name
+            ),
+            (None, Some(file_id)) => {
+                let (offset, len) = range(blob.size, start, end)?;
+                let mut stream =
+                    self.files.open_download_stream(file_id).await.context("opening object")?;
+                io::copy(&mut (&mut stream).take(offset), &mut io::sink())
+                    .await
+                    .context("seeking object")?;
+                let mut bytes = Vec::with_capacity(usize::try_from(len)?);
+                stream.take(len).read_to_end(&mut bytes).await.context("reading object")?;
+                Ok(bytes)
+            }
+            _ => bail!("object {} has no data", blob.name),
+        }
+    }
+
+    /// Store `data` inline, or in `GridFS` when it is too large for a document.
+    async fn store(&self, name: &str, data: &[u8]) -> anyhow::Result<Blob> {
+        let size = data.len() as u64;
+        let (data, file_id) = if data.len() > INLINE_LIMIT {
+            let mut upload = self.files.open_upload_stream(name).await.context("opening upload")?;
+            upload.write_all(data).await.context("uploading object")?;
+            upload.close().await.context("finishing upload")?;
+            (None, Some(upload.id().clone()))
+        } else {
+            let binary = Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: data.to_vec(),
+            };
+            (Some(Bson::Binary(binary)), None)
+        };
+
+        Ok(Blob {
+            name: name.to_owned(),
+            data,
+            file_id,
+            size,
+            created_at: u64::try_from(Utc::now().timestamp()).unwrap_or_default(),
+        })
+    }
+
+    /// Remove a replaced or deleted blob's `GridFS` file, if it has one.
+    async fn release(&self, blob: Option<Blob>) -> anyhow::Result<()> {
+        if let Some(file_id) = blob.and_then(|b| b.file_id) {
+            self.files.delete(file_id).await.context("deleting object file")?;
+        }
+        Ok(())
+    }
+}
+
+/// An update writing `blob` over any previous version of the object in one
+/// atomic operation. The object's `created_at` is kept when it is replaced.
+fn upsert(blob: &Blob) -> anyhow::Result<Document> {
+    let mut fields = bson::to_document(blob).context("serializing blob")?;
+    let created_at = fields.remove("created_at").unwrap_or(Bson::Null);
+    let unset = ["data", "file_id"]
+        .into_iter()
+        .filter(|field| !fields.contains_key(*field))
+        .map(|field| (field.to_owned(), Bson::String(String::new())))
+        .collect::<Document>();
+
+    let mut update = bson::doc! {
+        "$set": fields,
+        "$setOnInsert": { "created_at": created_at },
+    };
+    if !unset.is_empty() {
+        update.insert("$unset", unset);
+    }
+    Ok(update)
+}
+
+/// Resolve `start..=end` against an object of `size` bytes as an offset and
+/// length. An `end` of 0 or `u64::MAX` reads to the end of the object.
+fn range(size: u64, start: u64, end: u64) -> anyhow::Result<(u64, u64)> {
+    let unbounded = end == 0 || end == u64::MAX;
+    if !unbounded && end < start {
+        bail!("invalid byte range: end ({end}) < start ({start})");
+    }
+    let to = if unbounded { size } else { end.saturating_add(1).min(size) };
+    let start = start.min(to);
+    Ok((start, to - start))
+}
+
+/// The `start..=end` bytes of an inline object.
+fn slice(mut bytes: Vec<u8>, start: u64, end: u64) -> anyhow::Result<Vec<u8>> {
+    let (offset, len) = range(bytes.len() as u64, start, end)?;
+    // Both bounds are clamped to the object's length, itself a usize.
+    #[allow(clippy::cast_possible_truncation)]
+    let (offset, len) = (offset as usize, len as usize);
+    bytes.truncate(offset + len);
+    bytes.drain(..offset);
+    Ok(bytes)
+}
+
+/// The bytes of a blob written in the legacy JSON format, which stored JSON
+/// strings as `{"_string": ...}` and JSON objects as BSON documents.
+fn legacy_bytes(document: &Document) -> anyhow::Result<Vec<u8>> {
+    match document.get("_string") {
+        Some(Bson::String(s)) => serde_json::to_vec(&s).context("deserializing Document"),
+        _ => serde_json::to_vec(document).context("deserializing Document"),
+    }
 }
 
 impl Container for MongoDbContainer {
@@ -82,28 +309,21 @@ impl Container for MongoDbContainer {
         tracing::trace!("getting container info");
         Ok(ContainerMetadata {
             name: self.name.clone(),
-            created_at: 0,
+            created_at: self.created_at,
         })
     }
 
     /// Get the value associated with the key.
-    fn get_data(&self, name: String, _start: u64, _end: u64) -> FutureResult<Option<Bytes>> {
+    fn get_data(&self, name: String, start: u64, end: u64) -> FutureResult<Option<Bytes>> {
         tracing::trace!("getting object data: {name}");
-        let collection = self.collection.clone();
+        let container = self.clone();
 
         async move {
-            let Some(blob) = collection.find_one(bson::doc! { "name": name }).await? else {
-                return Err(anyhow!("Object not found"));
-            };
-
-            // HACK: blob data is a string not an object
-            let data = match blob.data.get("_string") {
-                Some(Bson::String(s)) => {
-                    serde_json::to_vec(&s).context("deserializing Document")?
-                }
-                _ => serde_json::to_vec(&blob.data).context("deserializing Document")?,
+            let Some(blob) = container.collection.find_one(bson::doc! { "name": name }).await?
+            else {
+                return Ok(None);
             };
-            Ok(Some(data.into()))
+            Ok(Some(container.read(blob, start, end).await?.into()))
         }
         .boxed()
     }
@@ -111,35 +331,17 @@ impl Container for MongoDbContainer {
     /// Set the value associated with the key.
     fn write_data(&self, name: String, data: Bytes) -> FutureResult<()> {
         tracing::trace!("writing object data: {name}");
-        let collection = self.collection.clone();
+        let container = self.clone();
 
         async move {
-            // `put` should update any previous value, so delete first
-            collection.delete_one(bson::doc! { "name": &name }).await?;
-
-            let bytes = data;
-
-            let data = match bytes.first() {
-                Some(b) if *b == b'{' => serde_json::from_slice::<Document>(&bytes)
-                    .context("deserializing into Document")?,
-                Some(_) => {
-                    // HACK: blob data is a string not an object
-                    let stringified = serde_json::from_slice::<String>(&bytes)
-                        .context("deserializing into String")?;
-                    bson::doc! {"_string": stringified}
-                }
-                None => return Err(anyhow!("OutgoingValue is empty")),
-            };
-
-            let blob = Blob {
-                name,
-                data,
-                size: bytes.len() as u64,
-                created_at: u64::try_from(Utc::now().timestamp_millis()).unwrap_or_default(),
-            };
-            collection.insert_one(blob).await?;
-
-            Ok(())
+            let blob = container.store(&name, &data).await?;
+            let previous = container
+                .collection
+                .find_one_and_update(bson::doc! { "name": &name }, upsert(&blob)?)
+                .upsert(true)
+                .await
+                .context("writing object")?;
+            container.release(previous).await
         }
         .boxed()
     }
@@ -167,11 +369,15 @@ impl Container for MongoDbContainer {
 
     /// Delete the value associated with the key.
     fn delete_object(&self, name: String) -> FutureResult<()> {
-        let collection = self.collection.clone();
+        let container = self.clone();
 
         async move {
-            collection.delete_one(bson::doc! { "name": name }).await.context("deleting object")?;
-            Ok(())
+            let deleted = container
+                .collection
+                .find_one_and_delete(bson::doc! { "name": name })
+                .await
+                .context("deleting object")?;
+            container.release(deleted).await
         }
         .boxed()
     }
@@ -197,9 +403,83 @@ impl Container for MongoDbContainer {
                 name: blob.name,
                 container: collection.name().to_string(),
                 size: blob.size,
-                created_at: blob.created_at,
+                created_at: seconds(blob.created_at),
             })
         }
         .boxed()
     }
 }
+
+/// Object times are in seconds. Earlier versions stored milliseconds, which
+/// are larger than any time in seconds for millennia to come.
+const fn seconds(created_at: u64) -> u64 {
+    if created_at >= 100_000_000_000 { created_at / 1000 } else { created_at }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_ranges() {
+        let bytes = b"hello world".to_vec();
+        assert_eq!(slice(bytes.clone(), 0, 0).unwrap(), b"hello world");
+        assert_eq!(slice(bytes.clone(), 6, u64::MAX).unwrap(), b"world");
+        assert_eq!(slice(bytes.clone(), 0, 4).unwrap(), b"hello");
+        assert_eq!(slice(bytes.clone(), 6, 100).unwrap(), b"world");
+        assert_eq!(slice(bytes.clone(), 20, 30).unwrap(), b"");
+        slice(bytes, 5, 2).unwrap_err();
+    }
+
+    #[test]
+    fn legacy_formats() {
+        let string = bson::doc! { "_string": "text" };
+        assert_eq!(legacy_bytes(&string).unwrap(), br#""text""#);
+        let object = bson::doc! { "a": 1 };
+        assert_eq!(legacy_bytes(&object).unwrap(), br#"{"a":1}"#);
+    }
+
+    #[test]
+    fn millisecond_times_read_as_seconds() {
+        assert_eq!(seconds(1_700_000_000), 1_700_000_000);
+        assert_eq!(seconds(1_700_000_000_123), 1_700_000_000);
+    }
+
+    #[test]
+    fn upsert_keeps_created_at() {
+        let blob = Blob {
+            name: "big".to_owned(),
+            data: None,
+            file_id: Some(Bson::Int32(7)),
+            size: 20,
+            created_at: 1000,
+        };
+        assert_eq!(
+            upsert(&blob).unwrap(),
+            bson::doc! {
+                "$set": { "name": "big", "file_id": 7, "size": 20_i64 },
+                "$setOnInsert": { "created_at": 1000_i64 },
+                "$unset": { "data": "" },
+            }
+        );
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let blob = Blob {
+            name: "raw".to_owned(),
+            data: Some(Bson::Binary(Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: vec![0, 159, 146, 150, 255],
+            })),
+            file_id: None,
+            size: 5,
+            created_at: 0,
+        };
+        let stored = bson::to_document(&blob).unwrap();
+        assert!(!stored.contains_key("file_id"), "absent fields are not stored");
+        let blob: Blob = bson::from_document(stored).unwrap();
+        let Some(Bson::Binary(binary)) = blob.data else { panic!("binary data") };
+        assert_eq!(binary.bytes, [0, 159, 146, 150, 255]);
+    }
+}
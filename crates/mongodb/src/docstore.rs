@@ -0,0 +1,254 @@
+//! `wasi-docstore` implementation for MongoDB.
+//!
+//! Collections map to MongoDB collections in the default database, or in
+//! another database when named `db.collection` (see `MONGODB_DATABASES`). A
+//! document's JSON body is stored as the top-level fields of a BSON document
+//! whose `_id` is the document id, so filters and sorts address body fields
+//! directly (see [`filter`]).
+
+mod filter;
+
+use anyhow::{Context, bail};
+use futures::{FutureExt, TryStreamExt};
+use mongodb::bson::{self, Bson, doc};
+use mongodb::error::{ErrorKind, WriteFailure};
+use omnia_wasi_docstore::{
+    Document, FilterTree, FutureResult, QueryOpts, QueryResult, WasiDocStoreCtx,
+};
+use serde_json::Value;
+
+use crate::Client;
+
+/// Largest page a query returns, whatever limit the guest asks for.
+const MAX_PAGE_SIZE: u32 = 1000;
+
+/// Server error code for a unique index violation.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// `wasi-docstore` implementation backed by MongoDB collections.
+impl WasiDocStoreCtx for Client {
+    fn get(&self, collection: String, id: String) -> FutureResult<Option<Document>> {
+        tracing::trace!("getting document {id} from {collection}");
+        let client = self.clone();
+
+        async move {
+            let collection = documents(&client, &collection)?;
+            let found =
+                collection.find_one(doc! { "_id": &id }).await.context("getting document")?;
+            found.map(to_document).transpose()
+        }
+        .boxed()
+    }
+
+    fn insert(&self, collection: String, doc: Document) -> FutureResult<()> {
+        tracing::trace!("inserting document {} into {collection}", doc.id);
+        let client = self.clone();
+
+        async move {
+            let documents = documents(&client, &collection)?;
+            let body = to_bson(&doc)?;
+            match documents.insert_one(body).await {
+                Ok(_) => Ok(()),
+                Err(e) if is_duplicate_key(&e) => {
+                    bail!("document already exists in '{collection}' with id '{}'", doc.id)
+                }
+                Err(e) => Err(e).context("inserting document"),
+            }
+        }
+        .boxed()
+    }
+
+    fn put(&self, collection: String, doc: Document) -> FutureResult<()> {
+        tracing::trace!("putting document {} into {collection}", doc.id);
+        let client = self.clone();
+
+        async move {
+            let documents = documents(&client, &collection)?;
+            let body = to_bson(&doc)?;
+            documents
+                .replace_one(doc! { "_id": &doc.id }, body)
+                .upsert(true)
+                .await
+                .context("putting document")?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete(&self, collection: String, id: String) -> FutureResult<bool> {
+        tracing::trace!("deleting document {id} from {collection}");
+        let client = self.clone();
+
+        async move {
+            let documents = documents(&client, &collection)?;
+            let result =
+                documents.delete_one(doc! { "_id": &id }).await.context("deleting document")?;
+            Ok(result.deleted_count > 0)
+        }
+        .boxed()
+    }
+
+    /// Query documents with optional filtering, sorting, and pagination.
+    ///
+    /// The continuation token is the absolute position of the next page, so
+    /// it is only stable while the matching documents are unchanged. Results
+    /// are ordered by `order_by` and then by id, keeping pages disjoint.
+    fn query(
+        &self, collection: String, filter: Option<FilterTree>, options: QueryOpts,
+    ) -> FutureResult<QueryResult> {
+        tracing::trace!("querying {collection}");
+        let client = self.clone();
+
+        async move {
+            // Clamp rather than trust the guest-supplied page size; an
+            // oversized limit degrades to the host maximum.
+            let limit = options.limit.map_or(MAX_PAGE_SIZE, |l| l.min(MAX_PAGE_SIZE));
+            if limit == 0 {
+                bail!("query limit must be at least 1");
+            }
+            let skip = match options.continuation.as_deref() {
+                Some(token) => parse_continuation(token)?,
+                None => u64::from(options.offset.unwrap_or(0)),
+            };
+
+            let documents = documents(&client, &collection)?;
+            let query = filter.as_ref().map(filter::to_query).transpose()?.unwrap_or_default();
+
+            // Fetch one extra document to learn whether another page follows.
+            let mut found: Vec<_> = documents
+                .find(query)
+                .sort(sort(&options))
+                .skip(skip)
+                .limit(i64::from(limit) + 1)
+                .await
+                .context("querying documents")?
+                .try_collect()
+                .await
+                .context("reading query results")?;
+
+            let continuation = (found.len() > limit as usize).then(|| {
+                found.truncate(limit as usize);
+                (skip + u64::from(limit)).to_string()
+            });
+            let documents = found.into_iter().map(to_document).collect::<anyhow::Result<_>>()?;
+
+            Ok(QueryResult {
+                documents,
+                continuation,
+            })
+        }
+        .boxed()
+    }
+}
+
+fn documents(
+    client: &Client, collection: &str,
+) -> anyhow::Result<mongodb::Collection<bson::Document>> {
+    if collection.is_empty() {
+        bail!("collection must not be empty");
+    }
+    let (db, collection) = client.resolve(collection)?;
+    Ok(db.collection(collection))
+}
+
+/// Sort by the requested fields, with the id as a tiebreaker.
+fn sort(options: &QueryOpts) -> bson::Document {
+    let mut sort = bson::Document::new();
+    for field in &options.order_by {
+        sort.insert(field.field.clone(), if field.descending { -1 } else { 1 });
+    }
+    if !sort.contains_key("_id") {
+        sort.insert("_id", 1);
+    }
+    sort
+}
+
+fn parse_continuation(token: &str) -> anyhow::Result<u64> {
+    token.parse().with_context(|| format!("invalid continuation token: {token:?}"))
+}
+
+/// Convert a document's JSON body into a BSON document keyed by its id.
+fn to_bson(doc: &Document) -> anyhow::Result<bson::Document> {
+    let body: Value = serde_json::from_slice(&doc.data).context("invalid JSON in document body")?;
+    let Value::Object(_) = body else {
+        bail!("document body must be a JSON object");
+    };
+    let mut stored = bson::to_document(&body).context("converting document body to BSON")?;
+    if stored.contains_key("_id") {
+        bail!("document body must not contain '_id'; the document id is stored there");
+    }
+    stored.insert("_id", doc.id.clone());
+    Ok(stored)
+}
+
+/// Convert a stored BSON document back into a document with a JSON body.
+fn to_document(mut stored: bson::Document) -> anyhow::Result<Document> {
+    let id = match stored.remove("_id") {
+        Some(Bson::String(id)) => id,
+        Some(other) => other.to_string(),
+        None => bail!("stored document has no '_id'"),
+    };
+    let body = Bson::Document(stored).into_relaxed_extjson();
+    Ok(Document {
+        id,
+        data: serde_json::to_vec(&body).context("serializing document body")?,
+    })
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use omnia_wasi_docstore::SortField;
+    use serde_json::json;
+
+    use super::*;
+
+    fn document(id: &str, body: &Value) -> Document {
+        Document {
+            id: id.to_owned(),
+            data: serde_json::to_vec(body).unwrap(),
+        }
+    }
+
+    #[test]
+    fn body_round_trip() {
+        let body = json!({ "name": "Alice", "age": 42, "score": 1.5, "tags": ["a"], "addr": { "city": "Paris" } });
+        let stored = to_bson(&document("user-1", &body)).unwrap();
+        assert_eq!(stored.get_str("_id").unwrap(), "user-1");
+
+        let doc = to_document(stored).unwrap();
+        assert_eq!(doc.id, "user-1");
+        assert_eq!(serde_json::from_slice::<Value>(&doc.data).unwrap(), body);
+    }
+
+    #[test]
+    fn body_must_be_object() {
+        to_bson(&document("a", &json!("text"))).unwrap_err();
+        let err = to_bson(&document("a", &json!({ "_id": 1 }))).unwrap_err();
+        assert!(err.to_string().contains("must not contain '_id'"), "{err}");
+    }
+
+    #[test]
+    fn sort_ends_with_id() {
+        let options = QueryOpts {
+            order_by: vec![SortField {
+                field: "age".into(),
+                descending: true,
+            }],
+            ..QueryOpts::default()
+        };
+        assert_eq!(sort(&options), doc! { "age": -1, "_id": 1 });
+    }
+
+    #[test]
+    fn continuation_rejects_junk() {
+        parse_continuation("not-a-number").unwrap_err();
+        assert_eq!(parse_continuation("20").unwrap(), 20);
+    }
+}
@@ -0,0 +1,181 @@
+//! Translation of [`FilterTree`] into MongoDB query documents.
+//!
+//! Every node has a native query operator, so filters are always evaluated
+//! server-side:
+//! - Field paths are dotted, which MongoDB resolves into nested documents.
+//! - `is-null` matches both `null` and missing fields, and `ne` matches
+//!   documents lacking the field, as in the backend-portable filter contract.
+//! - `contains`, `starts-with`, and `ends-with` become anchored regular
+//!   expressions over the escaped pattern, so patterns are matched literally.
+//! - Timestamps are compared as strings, which is how they are stored in the
+//!   JSON document bodies.
+
+use anyhow::bail;
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{Binary, Bson, Document, doc};
+use omnia_wasi_docstore::{ComparisonOp, FilterTree, ScalarValue};
+
+/// Translate a [`FilterTree`] to a MongoDB query document.
+///
+/// # Errors
+///
+/// Returns an error if a field path is empty or targets the document id.
+pub fn to_query(filter: &FilterTree) -> anyhow::Result<Document> {
+    Ok(match filter {
+        FilterTree::Compare { field, op, value } => {
+            doc! { validate_field(field)?: { query_op(*op): to_bson(value) } }
+        }
+        FilterTree::InList { field, values } => {
+            doc! { validate_field(field)?: { "$in": values.iter().map(to_bson).collect::<Vec<_>>() } }
+        }
+        FilterTree::NotInList { field, values } => {
+            doc! { validate_field(field)?: { "$nin": values.iter().map(to_bson).collect::<Vec<_>>() } }
+        }
+        FilterTree::IsNull(field) => doc! { validate_field(field)?: Bson::Null },
+        FilterTree::IsNotNull(field) => doc! { validate_field(field)?: { "$ne": Bson::Null } },
+        FilterTree::Contains { field, pattern } => {
+            doc! { validate_field(field)?: { "$regex": escape(pattern) } }
+        }
+        FilterTree::StartsWith { field, pattern } => {
+            doc! { validate_field(field)?: { "$regex": format!("^{}", escape(pattern)) } }
+        }
+        FilterTree::EndsWith { field, pattern } => {
+            doc! { validate_field(field)?: { "$regex": format!("{}$", escape(pattern)) } }
+        }
+        // MongoDB rejects empty `$and`/`$or` arrays, so the identities are
+        // spelled out: an empty AND matches everything, an empty OR nothing.
+        FilterTree::And(children) if children.is_empty() => doc! {},
+        FilterTree::Or(children) if children.is_empty() => doc! { "$expr": false },
+        FilterTree::And(children) => doc! { "$and": children_to_query(children)? },
+        FilterTree::Or(children) => doc! { "$or": children_to_query(children)? },
+        FilterTree::Not(inner) => doc! { "$nor": [to_query(inner)?] },
+    })
+}
+
+fn children_to_query(children: &[FilterTree]) -> anyhow::Result<Vec<Document>> {
+    children.iter().map(to_query).collect()
+}
+
+/// Field paths address the document body; the id is stored as `_id` and is
+/// not part of it.
+fn validate_field(field: &str) -> anyhow::Result<&str> {
+    if field.is_empty() || field.split('.').any(str::is_empty) {
+        bail!("invalid field path: '{field}'");
+    }
+    if field == "_id" || field.starts_with("_id.") {
+        bail!("invalid field path: '{field}' — the document id cannot be filtered on");
+    }
+    if field.starts_with('$') {
+        bail!("invalid field path: '{field}' — must not start with '$'");
+    }
+    Ok(field)
+}
+
+const fn query_op(op: ComparisonOp) -> &'static str {
+    match op {
+        ComparisonOp::Eq => "$eq",
+        ComparisonOp::Ne => "$ne",
+        ComparisonOp::Gt => "$gt",
+        ComparisonOp::Gte => "$gte",
+        ComparisonOp::Lt => "$lt",
+        ComparisonOp::Lte => "$lte",
+    }
+}
+
+fn to_bson(value: &ScalarValue) -> Bson {
+    match value {
+        ScalarValue::Null => Bson::Null,
+        ScalarValue::Boolean(b) => Bson::Boolean(*b),
+        ScalarValue::Int32(i) => Bson::Int32(*i),
+        ScalarValue::Int64(i) => Bson::Int64(*i),
+        ScalarValue::Float64(f) => Bson::Double(*f),
+        ScalarValue::Str(s) | ScalarValue::Timestamp(s) => Bson::String(s.clone()),
+        ScalarValue::Binary(bytes) => Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: bytes.clone(),
+        }),
+    }
+}
+
+/// Escape regular expression metacharacters so `pattern` matches literally.
+fn escape(pattern: &str) -> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if c.is_ascii_punctuation() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare() {
+        let f = FilterTree::Compare {
+            field: "address.city".into(),
+            op: ComparisonOp::Gte,
+            value: ScalarValue::Int64(3),
+        };
+        assert_eq!(to_query(&f).unwrap(), doc! { "address.city": { "$gte": 3_i64 } });
+    }
+
+    #[test]
+    fn null_checks() {
+        let f = FilterTree::IsNull("zone".into());
+        assert_eq!(to_query(&f).unwrap(), doc! { "zone": Bson::Null });
+        let f = FilterTree::IsNotNull("zone".into());
+        assert_eq!(to_query(&f).unwrap(), doc! { "zone": { "$ne": Bson::Null } });
+    }
+
+    #[test]
+    fn string_matches_are_literal() {
+        let f = FilterTree::StartsWith {
+            field: "name".into(),
+            pattern: "a.b*".into(),
+        };
+        assert_eq!(to_query(&f).unwrap(), doc! { "name": { "$regex": r"^a\.b\*" } });
+        let f = FilterTree::EndsWith {
+            field: "name".into(),
+            pattern: "(x)".into(),
+        };
+        assert_eq!(to_query(&f).unwrap(), doc! { "name": { "$regex": r"\(x\)$" } });
+    }
+
+    #[test]
+    fn logical() {
+        let f = FilterTree::Not(Box::new(FilterTree::Or(vec![
+            FilterTree::InList {
+                field: "status".into(),
+                values: vec![ScalarValue::Str("open".into())],
+            },
+            FilterTree::Contains {
+                field: "tags".into(),
+                pattern: "urgent".into(),
+            },
+        ])));
+        assert_eq!(
+            to_query(&f).unwrap(),
+            doc! { "$nor": [{ "$or": [
+                { "status": { "$in": ["open"] } },
+                { "tags": { "$regex": "urgent" } },
+            ] }] }
+        );
+    }
+
+    #[test]
+    fn empty_logical() {
+        assert_eq!(to_query(&FilterTree::And(vec![])).unwrap(), doc! {});
+        assert_eq!(to_query(&FilterTree::Or(vec![])).unwrap(), doc! { "$expr": false });
+    }
+
+    #[test]
+    fn invalid_fields() {
+        for field in ["", "a..b", "_id", "$where"] {
+            to_query(&FilterTree::IsNull(field.into())).unwrap_err();
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Key-value implementation for the MongoDB backend.
+//!
+//! Buckets map to collections and keys to `_id`. Values are stored as BSON
+//! binary alongside an optional `expires_at` date, which a TTL index on each
+//! bucket uses to remove expired entries. The server's TTL monitor only runs
+//! periodically, so reads also skip entries past their expiry.
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, anyhow, bail};
+use futures::{FutureExt, TryStreamExt};
+use mongodb::bson::spec::BinarySubtype;
+use mongodb::bson::{Binary, Bson, DateTime, Document, doc};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::IndexOptions;
+use mongodb::{Collection, IndexModel};
+use omnia_kv_registry::intern;
+use omnia_wasi_keyvalue::{Bucket, Cas, FutureResult, WasiKeyValueCtx};
+
+use crate::Client;
+
+/// Server error code for a unique index violation.
+const DUPLICATE_KEY: i32 = 11000;
+
+/// `wasi-keyvalue` implementation backed by MongoDB collections.
+impl WasiKeyValueCtx for Client {
+    fn open_bucket(&self, identifier: String) -> FutureResult<Arc<dyn Bucket>> {
+        let client = self.clone();
+        async move { Ok(client.kv_bucket(identifier).await? as Arc<dyn Bucket>) }.boxed()
+    }
+}
+
+impl Client {
+    /// Open a key-value bucket as a [`MongoBucket`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no default database or the bucket's TTL
+    /// index cannot be created.
+    pub async fn kv_bucket(&self, identifier: String) -> anyhow::Result<Arc<MongoBucket>> {
+        tracing::trace!("opening bucket: {identifier}");
+        if let Some(bucket) = self.buckets.get(&identifier) {
+            return Ok(bucket);
+        }
+
+        let (db, collection) = self.resolve(&identifier)?;
+        let collection = db.collection::<Document>(collection);
+        let index = IndexModel::builder()
+            .keys(doc! { "expires_at": 1 })
+            .options(IndexOptions::builder().expire_after(Duration::ZERO).build())
+            .build();
+        collection.create_index(index).await.context("creating TTL index")?;
+
+        let bucket = MongoBucket {
+            name: intern(&identifier)?,
+            collection,
+            ttl: (self.kv_ttl > 0).then(|| Duration::from_secs(self.kv_ttl)),
+        };
+        Ok(self.buckets.insert(&identifier, Arc::new(bucket)))
+    }
+}
+
+/// A key-value bucket backed by a MongoDB collection.
+#[derive(Debug, Clone)]
+pub struct MongoBucket {
+    name: &'static str,
+    collection: Collection<Document>,
+    /// How long written values live, if they expire.
+    ttl: Option<Duration>,
+}
+
+/// Attempts an increment makes before giving up under contention.
+const MAX_ATTEMPTS: usize = 10;
+
+/// Atomic operations on top of MongoDB's single-document atomicity.
+///
+/// A swap is one write whose filter carries the value it expects, so
+/// concurrent writers are serialized by the server. An increment reads the
+/// counter and swaps in the sum, retrying when another writer got there first,
+/// so the sum is checked for overflow before anything is written.
+impl MongoBucket {
+    /// Add `delta` to the integer stored at `key`, treating a missing or
+    /// expired key as 0, and return the new value. Counters are stored as
+    /// 8-byte big-endian values, the encoding other backends use, and
+    /// incrementing refreshes the key's expiry.
+    async fn add(&self, key: &str, delta: i64) -> anyhow::Result<i64> {
+        for _ in 0..MAX_ATTEMPTS {
+            let current = self.current(key).await?;
+            let base = current.as_deref().map(decode_i64).transpose()?.unwrap_or(0);
+            let value = base.checked_add(delta).context("adding delta overflows i64")?;
+            if self.compare_and_swap(key, current.as_deref(), value.to_be_bytes().to_vec()).await? {
+                return Ok(value);
+            }
+            tracing::debug!("increment of {key} lost a race; retrying");
+        }
+        bail!("incrementing {key} failed after {MAX_ATTEMPTS} contended attempts")
+    }
+
+    /// The value `key` holds, if it has not expired.
+    async fn current(&self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let entry = self.collection.find_one(live(key)).await.context("getting key")?;
+        entry.as_ref().map(value_bytes).transpose()
+    }
+
+    /// Write `value` only if `key` currently holds `current`, or is absent
+    /// when `current` is `None`. Returns whether the value was written.
+    async fn compare_and_swap(
+        &self, key: &str, current: Option<&[u8]>, value: Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let Some(current) = current else {
+            // an expired entry still occupies the key until the TTL monitor
+            // removes it, so clear it before claiming the key
+            self.collection
+                .delete_one(doc! { "_id": key, "expires_at": { "$lte": DateTime::now() } })
+                .await
+                .with_context(|| format!("clearing expired {key}"))?;
+            return match self.collection.insert_one(self.entry(key, value)).await {
+                Ok(_) => Ok(true),
+                Err(e) if is_duplicate_key(&e) => Ok(false),
+                Err(e) => Err(e).with_context(|| format!("creating {key}")),
+            };
+        };
+
+        let mut filter = live(key);
+        filter.insert("$and", vec![holds(current)]);
+        let swapped = self
+            .collection
+            .find_one_and_update(filter, self.write(value))
+            .await
+            .with_context(|| format!("swapping {key}"))?;
+        Ok(swapped.is_some())
+    }
+
+    /// When a value written now expires.
+    fn expires_at(&self) -> Option<DateTime> {
+        self.ttl.map(|ttl| DateTime::now().saturating_add_duration(ttl))
+    }
+
+    /// A new entry holding `value`.
+    fn entry(&self, key: &str, value: Vec<u8>) -> Document {
+        let mut entry = doc! { "_id": key, "value": binary(value) };
+        if let Some(expires_at) = self.expires_at() {
+            entry.insert("expires_at", expires_at);
+        }
+        entry
+    }
+
+    /// An update replacing an entry's value and expiry.
+    fn write(&self, value: Vec<u8>) -> Document {
+        let mut set = doc! { "value": binary(value) };
+        let Some(expires_at) = self.expires_at() else {
+            return doc! { "$set": set, "$unset": { "expires_at": "" } };
+        };
+        set.insert("expires_at", expires_at);
+        doc! { "$set": set }
+    }
+}
+
+/// A filter matching entries that have not expired.
+fn unexpired() -> Document {
+    doc! { "$or": [
+        { "expires_at": { "$exists": false } },
+        { "expires_at": { "$gt": DateTime::now() } },
+    ] }
+}
+
+/// A filter matching `key` while it has not expired.
+fn live(key: &str) -> Document {
+    let mut filter = doc! { "_id": key };
+    filter.extend(unexpired());
+    filter
+}
+
+/// A filter matching an entry whose value reads as `current`, including a
+/// counter stored as a BSON integer by earlier versions.
+fn holds(current: &[u8]) -> Document {
+    let Ok(counter) = <[u8; 8]>::try_from(current) else {
+        return doc! { "value": binary(current.to_vec()) };
+    };
+    doc! { "$or": [
+        { "value": binary(current.to_vec()) },
+        { "value": i64::from_be_bytes(counter) },
+    ] }
+}
+
+const fn binary(bytes: Vec<u8>) -> Binary {
+    Binary {
+        subtype: BinarySubtype::Generic,
+        bytes,
+    }
+}
+
+/// The bytes of a stored value. Counters stored as BSON integers by earlier
+/// versions are returned as 8-byte big-endian integers.
+fn value_bytes(entry: &Document) -> anyhow::Result<Vec<u8>> {
+    match entry.get("value") {
+        Some(Bson::Binary(binary)) => Ok(binary.bytes.clone()),
+        Some(Bson::Int64(n)) => Ok(n.to_be_bytes().to_vec()),
+        Some(Bson::Int32(n)) => Ok(i64::from(*n).to_be_bytes().to_vec()),
+        _ => bail!("stored value is not binary"),
+    }
+}
+
+fn decode_i64(value: &[u8]) -> anyhow::Result<i64> {
+    let bytes: [u8; 8] = value.try_into().map_err(|_len| {
+        anyhow!("value is {} bytes, not an 8-byte big-endian integer", value.len())
+    })?;
+    Ok(i64::from_be_bytes(bytes))
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY
+    )
+}
+
+impl Bucket for MongoBucket {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn get(&self, key: String) -> FutureResult<Option<Vec<u8>>> {
+        tracing::trace!("getting key: {key}");
+        let collection = self.collection.clone();
+
+        async move {
+            let entry = collection.find_one(live(&key)).await.context("getting key")?;
+            entry.as_ref().map(value_bytes).transpose()
+        }
+        .boxed()
+    }
+
+    fn set(&self, key: String, value: Vec<u8>) -> FutureResult<()> {
+        tracing::trace!("setting key: {key}");
+        let collection = self.collection.clone();
+        let update = self.write(value);
+
+        async move {
+            collection
+                .update_one(doc! { "_id": key }, update)
+                .upsert(true)
+                .await
+                .context("setting key")?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn delete(&self, key: String) -> FutureResult<()> {
+        tracing::trace!("deleting key: {key}");
+        let collection = self.collection.clone();
+
+        async move {
+            collection.delete_one(doc! { "_id": key }).await.context("deleting key")?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn exists(&self, key: String) -> FutureResult<bool> {
+        tracing::trace!("checking existence of key: {key}");
+        let collection = self.collection.clone();
+
+        async move {
+            let count = collection.count_documents(live(&key)).await.context("checking key")?;
+            Ok(count > 0)
+        }
+        .boxed()
+    }
+
+    fn keys(&self) -> FutureResult<Vec<String>> {
+        tracing::trace!("listing keys");
+        let collection = self.collection.clone();
+
+        async move {
+            let entries: Vec<Document> = collection
+                .find(unexpired())
+                .projection(doc! { "_id": 1 })
+                .await
+                .context("listing keys")?
+                .try_collect()
+                .await
+                .context("reading keys")?;
+            Ok(entries.iter().filter_map(|e| e.get_str("_id").ok().map(str::to_owned)).collect())
+        }
+        .boxed()
+    }
+
+    fn increment(&self, key: String, delta: i64) -> FutureResult<i64> {
+        tracing::trace!("incrementing key: {key}");
+        let bucket = self.clone();
+        async move { bucket.add(&key, delta).await }.boxed()
+    }
+
+    fn swap(&self, cas: Cas, value: Vec<u8>) -> FutureResult<Result<(), Cas>> {
+        tracing::trace!("swapping key: {}", cas.key);
+        let bucket = self.clone();
+
+        async move {
+            if bucket.compare_and_swap(&cas.key, cas.current.as_deref(), value).await? {
+                return Ok(Ok(()));
+            }
+            let current = bucket.current(&cas.key).await?;
+            Ok(Err(Cas { current, ..cas }))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_read_as_big_endian() {
+        assert_eq!(value_bytes(&doc! { "value": 42_i64 }).unwrap(), 42_i64.to_be_bytes());
+        assert_eq!(value_bytes(&doc! { "value": -1_i32 }).unwrap(), (-1_i64).to_be_bytes());
+        assert_eq!(value_bytes(&doc! { "value": binary(vec![0, 255]) }).unwrap(), [0, 255]);
+        value_bytes(&doc! { "value": "text" }).unwrap_err();
+    }
+
+    #[test]
+    fn swap_matches_counters() {
+        let filter = holds(&7_i64.to_be_bytes());
+        let either = filter.get_array("$or").unwrap();
+        assert_eq!(either[1], Bson::Document(doc! { "value": 7_i64 }));
+        assert_eq!(holds(b"abc"), doc! { "value": binary(b"abc".to_vec()) });
+    }
+
+    #[test]
+    fn counters_decode() {
+        assert_eq!(decode_i64(&(-42_i64).to_be_bytes()).unwrap(), -42);
+        let err = decode_i64(b"42").unwrap_err();
+        assert!(err.to_string().contains("not an 8-byte"), "{err}");
+    }
+}
@@ -0,0 +1,303 @@
+//! `wasi-messaging` subscriptions over MongoDB change streams.
+//!
+//! Each collection listed in `MONGODB_WATCH` is watched with a change stream
+//! and every change event becomes a [`Message`] on a topic named after the
+//! collection. Once the host has taken an event and asks for the next one,
+//! the event's resume token becomes due to be saved; saves are batched to at
+//! most one per [`SAVE_INTERVAL`], so a restarted host replays at most that
+//! much. The host does not report when a guest has handled an event, so an
+//! event whose handler was still running when the host stopped is not
+//! replayed. Tokens are kept per consumer name, so deployments watching the
+//! same collection track their own positions. A change stream that fails with
+//! an error the driver cannot resume from ends its collection's messages.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, bail};
+use futures::future::FutureExt;
+use futures::stream::{self, Stream, StreamExt};
+use mongodb::Collection;
+use mongodb::bson::{self, Bson, Document, doc};
+use mongodb::change_stream::ChangeStream;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken};
+use mongodb::error::ErrorKind;
+use mongodb::options::FullDocumentType;
+use omnia_wasi_messaging::{
+    Client, FutureResult, Message, Metadata, RequestOptions, Subscriptions, WasiMessagingCtx,
+};
+
+/// How often the resume token of the last event taken by the host is saved.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Server error codes for a resume token the oplog no longer covers.
+const HISTORY_LOST: [i32; 2] = [280, 286];
+
+/// `wasi-messaging` implementation backed by MongoDB change streams.
+impl WasiMessagingCtx for crate::Client {
+    fn connect(&self) -> FutureResult<Arc<dyn Client>> {
+        let client = self.clone();
+        async move { Ok(Arc::new(client) as Arc<dyn Client>) }.boxed()
+    }
+}
+
+impl Client for crate::Client {
+    fn subscribe(&self) -> FutureResult<Subscriptions> {
+        let client = self.clone();
+
+        async move {
+            if client.watch.is_empty() {
+                bail!("No collections to watch");
+            }
+            let tokens = Tokens {
+                collection: client.database()?.collection(&client.resume_collection),
+                consumer: client.consumer.clone(),
+            };
+
+            let mut streams = vec![];
+            for name in &client.watch {
+                let (db, collection) = client.resolve(name)?;
+                let changes = watch(&db.collection(collection), name, &tokens).await?;
+                let delivery = Delivery {
+                    changes,
+                    name: name.clone(),
+                    tokens: tokens.clone(),
+                    taken: None,
+                    saved_at: Instant::now(),
+                };
+                streams.push(delivery.messages().boxed());
+            }
+            tracing::info!("watching collections: {:?}", client.watch);
+
+            Ok(Box::pin(stream::select_all(streams)) as Subscriptions)
+        }
+        .boxed()
+    }
+
+    fn send(&self, topic: String, _message: Message) -> FutureResult<()> {
+        async move {
+            bail!(
+                "cannot send to {topic}: MongoDB messaging only subscribes to change streams; \
+                 write documents to publish changes"
+            )
+        }
+        .boxed()
+    }
+
+    fn request(
+        &self, topic: String, _message: Message, _options: Option<RequestOptions>,
+    ) -> FutureResult<Message> {
+        async move { bail!("cannot request from {topic}: MongoDB messaging does not support requests") }
+            .boxed()
+    }
+}
+
+/// Open a change stream on `collection`, named `name`, resuming after its saved token if there
+/// is one. When the oplog no longer holds the saved position, the stream
+/// starts from the current time instead and the gap is logged.
+async fn watch(
+    collection: &Collection<Document>, name: &str, tokens: &Tokens,
+) -> anyhow::Result<ChangeStream<ChangeStreamEvent<Document>>> {
+    let watch = || collection.watch().full_document(FullDocumentType::UpdateLookup);
+
+    let Some(token) = tokens.load(name).await? else {
+        return watch().await.with_context(|| format!("watching {name}"));
+    };
+    match watch().resume_after(token).await {
+        Err(e) if history_lost(&e) => {
+            tracing::warn!("resume token for {name} has expired; changes since were missed");
+            watch().await.with_context(|| format!("watching {name}"))
+        }
+        result => result.with_context(|| format!("resuming {name}")),
+    }
+}
+
+/// A change stream being delivered to the host, and the resume token of the
+/// last event the host took.
+struct Delivery {
+    changes: ChangeStream<ChangeStreamEvent<Document>>,
+    name: String,
+    tokens: Tokens,
+    /// Token of the last event taken but not yet saved.
+    taken: Option<ResumeToken>,
+    saved_at: Instant,
+}
+
+impl Delivery {
+    /// Stream the changes as messages. Asking for the next message marks the
+    /// previous one as taken, and its token is saved once [`SAVE_INTERVAL`]
+    /// has passed since the last save, whether or not more events arrive.
+    /// The stream ends, after saving the pending token, when the change stream
+    /// fails with an error the driver could not resume from.
+    fn messages(self) -> impl Stream<Item = Message> {
+        stream::unfold(self, |mut delivery| async move {
+            loop {
+                if delivery.taken.is_some() && delivery.saved_at.elapsed() >= SAVE_INTERVAL {
+                    delivery.save().await;
+                }
+                let next = if delivery.taken.is_some() {
+                    let wait = SAVE_INTERVAL.saturating_sub(delivery.saved_at.elapsed());
+                    match tokio::time::timeout(wait, delivery.changes.next()).await {
+                        Ok(next) => next,
+                        // idle: save the pending token before waiting on
+                        Err(_elapsed) => continue,
+                    }
+                } else {
+                    delivery.changes.next().await
+                };
+
+                match next {
+                    Some(Ok(event)) => {
+                        delivery.taken = Some(event.id.clone());
+                        let message = from_change(&delivery.name, event);
+                        return Some((message, delivery));
+                    }
+                    // the driver resumes after resumable errors itself, so
+                    // polling again would only repeat this one
+                    Some(Err(e)) => {
+                        tracing::error!("change stream on {} ended: {e}", delivery.name);
+                        delivery.save().await;
+                        return None;
+                    }
+                    None => {
+                        delivery.save().await;
+                        return None;
+                    }
+                }
+            }
+        })
+    }
+
+    /// Save the token of the last event taken, if it has not been saved.
+    async fn save(&mut self) {
+        let Some(token) = self.taken.take() else {
+            return;
+        };
+        if let Err(e) = self.tokens.save(&self.name, &token).await {
+            tracing::warn!("failed to save resume token for {}: {e}", self.name);
+        }
+        self.saved_at = Instant::now();
+    }
+}
+
+fn history_lost(error: &mongodb::error::Error) -> bool {
+    matches!(error.kind.as_ref(), ErrorKind::Command(e) if HISTORY_LOST.contains(&e.code))
+}
+
+/// Saved resume tokens, one document per consumer and collection.
+#[derive(Debug, Clone)]
+struct Tokens {
+    collection: Collection<Document>,
+    consumer: String,
+}
+
+impl Tokens {
+    fn id(&self, name: &str) -> String {
+        format!("{}:{name}", self.consumer)
+    }
+
+    async fn load(&self, name: &str) -> anyhow::Result<Option<ResumeToken>> {
+        let saved = self
+            .collection
+            .find_one(doc! { "_id": self.id(name) })
+            .await
+            .context("loading resume token")?;
+        let Some(token) = saved.and_then(|mut d| d.remove("token")) else {
+            return Ok(None);
+        };
+        Ok(Some(bson::from_bson(token).context("decoding resume token")?))
+    }
+
+    async fn save(&self, name: &str, token: &ResumeToken) -> anyhow::Result<()> {
+        let token = bson::to_bson(token).context("encoding resume token")?;
+        self.collection
+            .update_one(doc! { "_id": self.id(name) }, doc! { "$set": { "token": token } })
+            .upsert(true)
+            .await
+            .context("saving resume token")?;
+        Ok(())
+    }
+}
+
+/// Translate a change event on collection `name` into the host's [`Message`].
+///
+/// The payload is the changed document as JSON, empty when the event has
+/// none (deletes, or updates to a document deleted since).
+fn from_change(name: &str, event: ChangeStreamEvent<Document>) -> Message {
+    let payload = event.full_document.map_or_else(Vec::new, |d| {
+        serde_json::to_vec(&Bson::Document(d).into_relaxed_extjson()).unwrap_or_default()
+    });
+
+    let mut metadata = HashMap::from([
+        ("collection".to_owned(), name.to_owned()),
+        ("operation".to_owned(), operation(&event.operation_type)),
+    ]);
+    if let Some(key) = event.document_key {
+        metadata.insert(
+            "document-key".to_owned(),
+            Bson::Document(key).into_relaxed_extjson().to_string(),
+        );
+    }
+    if let Some(ns) = event.ns {
+        metadata.insert("database".to_owned(), ns.db);
+    }
+
+    let mut message = Message::new(payload);
+    name.clone_into(&mut message.topic);
+    message.metadata = Some(Metadata { inner: metadata });
+    message
+}
+
+fn operation(operation: &OperationType) -> String {
+    match operation {
+        OperationType::Insert => "insert",
+        OperationType::Update => "update",
+        OperationType::Replace => "replace",
+        OperationType::Delete => "delete",
+        OperationType::Drop => "drop",
+        OperationType::Rename => "rename",
+        OperationType::DropDatabase => "dropDatabase",
+        OperationType::Invalidate => "invalidate",
+        OperationType::Other(other) => other,
+        _ => "unknown",
+    }
+    .to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(document: &str) -> ChangeStreamEvent<Document> {
+        bson::from_document(doc! {
+            "_id": { "_data": "token" },
+            "operationType": "update",
+            "ns": { "db": "shop", "coll": "orders" },
+            "documentKey": { "_id": "order-1" },
+            "fullDocument": { "_id": "order-1", "status": document },
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn change_to_message() {
+        let message = from_change("orders", event("shipped"));
+        assert_eq!(message.topic, "orders");
+        assert_eq!(message.payload, br#"{"_id":"order-1","status":"shipped"}"#);
+
+        let metadata = message.metadata.unwrap();
+        assert_eq!(metadata.get("operation").unwrap(), "update");
+        assert_eq!(metadata.get("document-key").unwrap(), r#"{"_id":"order-1"}"#);
+        assert_eq!(metadata.get("database").unwrap(), "shop");
+    }
+
+    #[test]
+    fn deletes_have_no_payload() {
+        let mut event = event("gone");
+        event.operation_type = OperationType::Delete;
+        event.full_document = None;
+        let message = from_change("orders", event);
+        assert!(message.payload.is_empty(), "delete payload is empty");
+        assert_eq!(message.metadata.unwrap().get("operation").unwrap(), "delete");
+    }
+}
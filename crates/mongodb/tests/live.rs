@@ -1,15 +1,23 @@
-//! Live blob round-trip for the MongoDB backend, driven through the
-//! `omnia:blobstore` host boundary (`WasiBlobstoreCtx`; containers map to
-//! MongoDB collections).
+//! Live round-trips for the MongoDB backend, driven through the
+//! `omnia:blobstore` (`WasiBlobstoreCtx`), `omnia:docstore`
+//! (`WasiDocStoreCtx`), `omnia:keyvalue` (`WasiKeyValueCtx`), and
+//! `omnia:messaging` (`WasiMessagingCtx`) host boundaries; containers,
+//! collections, and buckets map to MongoDB collections.
 //!
 //! `#[ignore]`d so it never touches the network in CI. Run against a reachable
 //! MongoDB (`MONGODB_URL`, including a default database):
 //! `cargo nextest run -p omnia-mongodb --run-ignored all`.
 
+use std::sync::Arc;
+
 use anyhow::Result;
+use futures::StreamExt;
 use omnia::Backend;
 use omnia_mongodb::Client;
 use omnia_wasi_blobstore::{Container, WasiBlobstoreCtx};
+use omnia_wasi_docstore::{Document, FilterTree, QueryOpts, WasiDocStoreCtx};
+use omnia_wasi_keyvalue::{Cas, WasiKeyValueCtx};
+use omnia_wasi_messaging::WasiMessagingCtx;
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 #[ignore = "live: needs a reachable MongoDB (MONGODB_URL); run with --run-ignored"]
@@ -18,14 +26,128 @@ async fn write_read_delete() -> Result<()> {
 
     let container = format!("omnia-live-{}", std::process::id());
     let store: std::sync::Arc<dyn Container> = client.create_container(container.clone()).await?;
+    assert!(client.container_exists(container.clone()).await?, "container exists after create");
+    assert!(store.info()?.created_at > 0, "creation time is recorded");
 
     let object = "greeting".to_owned();
     store.write_data(object.clone(), b"payload".to_vec().into()).await?;
 
     assert_eq!(store.get_data(object.clone(), 0, 0).await?.as_deref(), Some(b"payload".as_slice()));
+    assert_eq!(store.get_data(object.clone(), 3, 5).await?.as_deref(), Some(b"loa".as_slice()));
     assert!(store.has_object(object.clone()).await?, "object exists after write");
 
     store.delete_object(object).await?;
-    client.delete_container(container).await?;
+    client.delete_container(container.clone()).await?;
+    assert!(!client.container_exists(container).await?, "container is gone after delete");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable MongoDB (MONGODB_URL); run with --run-ignored"]
+async fn insert_query_delete() -> Result<()> {
+    let client = <Client as Backend>::connect().await?;
+
+    let collection = format!("omnia-live-docs-{}", std::process::id());
+    for (id, name) in [("1", "alpha"), ("2", "alpine"), ("3", "beta")] {
+        let doc = Document {
+            id: id.to_owned(),
+            data: format!(r#"{{"name":"{name}"}}"#).into_bytes(),
+        };
+        client.insert(collection.clone(), doc).await?;
+    }
+
+    let got = client.get(collection.clone(), "2".to_owned()).await?.expect("document present");
+    assert_eq!(got.data, br#"{"name":"alpine"}"#, "body round-trips through the boundary");
+
+    let filter = FilterTree::StartsWith {
+        field: "name".to_owned(),
+        pattern: "al".to_owned(),
+    };
+    let options = QueryOpts {
+        limit: Some(1),
+        ..QueryOpts::default()
+    };
+    let page = client.query(collection.clone(), Some(filter.clone()), options).await?;
+    assert_eq!(page.documents.len(), 1);
+
+    let options = QueryOpts {
+        continuation: page.continuation,
+        ..QueryOpts::default()
+    };
+    let rest = client.query(collection.clone(), Some(filter), options).await?;
+    assert_eq!(rest.documents.len(), 1, "second page holds the other match");
+    assert!(rest.continuation.is_none(), "no pages remain");
+
+    for id in ["1", "2", "3"] {
+        assert!(client.delete(collection.clone(), id.to_owned()).await?);
+    }
+    client.delete_container(collection).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable MongoDB (MONGODB_URL); run with --run-ignored"]
+async fn set_swap_increment() -> Result<()> {
+    let client = <Client as Backend>::connect().await?;
+
+    let name = format!("omnia-live-kv-{}", std::process::id());
+    let bucket = client.open_bucket(name.clone()).await?;
+    bucket.set("greeting".to_owned(), b"hello".to_vec()).await?;
+    assert_eq!(bucket.get("greeting".to_owned()).await?.as_deref(), Some(b"hello".as_slice()));
+    assert_eq!(bucket.keys().await?, ["greeting"]);
+
+    let cas = |key: &str, current: Option<&[u8]>| Cas {
+        bucket: Arc::clone(&bucket),
+        key: key.to_owned(),
+        current: current.map(<[u8]>::to_vec),
+    };
+    let Err(fresh) = bucket.swap(cas("greeting", Some(b"stale")), b"bye".to_vec()).await? else {
+        panic!("stale swap succeeded");
+    };
+    assert_eq!(
+        fresh.current.as_deref(),
+        Some(b"hello".as_slice()),
+        "refreshed to the stored value"
+    );
+    bucket.swap(fresh, b"bye".to_vec()).await?.expect("swap against the stored value");
+    assert!(bucket.swap(cas("greeting", None), b"new".to_vec()).await?.is_err(), "key exists");
+
+    assert_eq!(bucket.increment("hits".to_owned(), 2).await?, 2);
+    assert_eq!(bucket.increment("hits".to_owned(), 3).await?, 5);
+    assert_eq!(bucket.get("hits".to_owned()).await?, Some(5_i64.to_be_bytes().to_vec()));
+    let five = 5_i64.to_be_bytes();
+    bucket.swap(cas("hits", Some(&five)), b"reset".to_vec()).await?.expect("counters swap");
+
+    // counters set directly can be incremented, and overflow writes nothing
+    bucket.set("max".to_owned(), i64::MAX.to_be_bytes().to_vec()).await?;
+    bucket.increment("max".to_owned(), 1).await.unwrap_err();
+    assert_eq!(bucket.increment("max".to_owned(), -1).await?, i64::MAX - 1);
+
+    client.delete_container(name).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a MongoDB replica set (MONGODB_URL); run with --run-ignored"]
+async fn change_stream() -> Result<()> {
+    let collection = format!("omnia-live-changes-{}", std::process::id());
+    let mut options = <omnia_mongodb::ConnectOptions as omnia::FromEnv>::from_env()?;
+    options.watch = Some(vec![collection.clone()]);
+    let client = Client::connect_with(options).await?;
+
+    let messaging = client.connect().await?;
+    let mut changes = messaging.subscribe().await?;
+    let doc = Document {
+        id: "order-1".to_owned(),
+        data: br#"{"status":"new"}"#.to_vec(),
+    };
+    client.insert(collection.clone(), doc).await?;
+
+    let message = changes.next().await.expect("change delivered");
+    assert_eq!(message.topic, collection);
+    let metadata = message.metadata.expect("metadata");
+    assert_eq!(metadata.get("operation").map(String::as_str), Some("insert"));
+
+    client.delete_container(collection).await?;
     Ok(())
 }
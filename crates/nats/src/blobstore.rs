@@ -1,15 +1,21 @@
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::io;
 use std::sync::Arc;
 
-use anyhow::Context;
-use async_nats::jetstream;
-use async_nats::jetstream::object_store::{Config, ObjectStore};
-use chrono::Utc;
-use futures::{FutureExt, StreamExt};
+use anyhow::{Context, anyhow, bail};
+use async_nats::datetime::DateTime;
+use async_nats::jetstream::consumer::{DeliverPolicy, pull};
+use async_nats::jetstream::object_store::{
+    self, Config, GetErrorKind, InfoErrorKind, ObjectInfo, ObjectStore,
+};
+use async_nats::{HeaderMap, jetstream};
+use futures::{FutureExt, StreamExt, future};
 use omnia_wasi_blobstore::{
     Bytes, Container, ContainerMetadata, FutureResult, ObjectMetadata, WasiBlobstoreCtx,
 };
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt, Take};
+use tokio_util::io::StreamReader;
 
 use crate::Client;
 
@@ -17,46 +23,42 @@ use crate::Client;
 impl WasiBlobstoreCtx for Client {
     fn create_container(&self, name: String) -> FutureResult<Arc<dyn Container>> {
         tracing::trace!("creating container: {name}");
-        let client = self.inner.clone();
+        let jetstream = jetstream::new(self.inner.clone());
+        let bucket = self.namespace.bucket(&name);
 
         async move {
-            let store = jetstream::new(client)
+            let store = jetstream
                 .create_object_store(Config {
-                    bucket: name.clone(),
+                    bucket: bucket.clone(),
                     ..Default::default()
                 })
                 .await
                 .context("creating object store")?;
-            let metadata = metadata(name);
+            let metadata = metadata(&jetstream, &bucket, name).await?;
 
-            Ok(Arc::new(NatsContainer { metadata, store }) as Arc<dyn Container>)
+            Ok(Arc::new(NatsContainer {
+                metadata,
+                store,
+                jetstream,
+            }) as Arc<dyn Container>)
         }
         .boxed()
     }
 
     fn get_container(&self, name: String) -> FutureResult<Arc<dyn Container>> {
         tracing::trace!("getting container: {name}");
-        let client = self.inner.clone();
-
-        async move {
-            let store = jetstream::new(client)
-                .get_object_store(&name)
-                .await
-                .context("getting object store")?;
-            let metadata = metadata(name);
-
-            Ok(Arc::new(NatsContainer { metadata, store }) as Arc<dyn Container>)
-        }
-        .boxed()
+        let client = self.clone();
+        async move { Ok(Arc::new(client.container(name).await?) as Arc<dyn Container>) }.boxed()
     }
 
     fn delete_container(&self, name: String) -> FutureResult<()> {
         tracing::trace!("deleting container: {name}");
         let client = self.inner.clone();
+        let bucket = self.namespace.bucket(&name);
 
         async move {
             jetstream::new(client)
-                .delete_object_store(&name)
+                .delete_object_store(&bucket)
                 .await
                 .context("issue deleting object store")?;
             Ok(())
@@ -67,26 +69,118 @@ impl WasiBlobstoreCtx for Client {
     fn container_exists(&self, name: String) -> FutureResult<bool> {
         tracing::trace!("checking existence of container: {name}");
         let client = self.inner.clone();
+        let bucket = self.namespace.bucket(&name);
 
         async move {
-            let exists = jetstream::new(client).get_object_store(&name).await.is_ok();
+            let exists = jetstream::new(client).get_object_store(&bucket).await.is_ok();
             Ok(exists)
         }
         .boxed()
     }
 }
 
-fn metadata(name: String) -> ContainerMetadata {
-    ContainerMetadata {
-        name,
-        created_at: u64::try_from(Utc::now().timestamp()).unwrap_or_default(),
+impl Client {
+    /// Open an object store as a [`NatsContainer`], exposing the streaming
+    /// reads and writes the `wasi-blobstore` interface does not.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the object store does not exist or cannot be
+    /// opened.
+    pub async fn container(&self, name: String) -> anyhow::Result<NatsContainer> {
+        let jetstream = jetstream::new(self.inner.clone());
+        let bucket = self.namespace.bucket(&name);
+        let store = jetstream.get_object_store(&bucket).await.context("getting object store")?;
+        let metadata = metadata(&jetstream, &bucket, name).await?;
+        Ok(NatsContainer {
+            metadata,
+            store,
+            jetstream,
+        })
+    }
+}
+
+/// Metadata for container `name`, stored as object store `bucket` and dated
+/// by the creation of its backing stream.
+async fn metadata(
+    jetstream: &jetstream::Context, bucket: &str, name: String,
+) -> anyhow::Result<ContainerMetadata> {
+    let stream = jetstream
+        .get_stream(format!("OBJ_{bucket}"))
+        .await
+        .context("getting object store stream")?;
+    let created_at = unix_secs(stream.cached_info().created);
+    Ok(ContainerMetadata { name, created_at })
+}
+
+fn unix_secs(time: DateTime) -> u64 {
+    u64::try_from(time.timestamp()).unwrap_or_default()
+}
+
+/// Header a headers-only consumer adds with the size of the omitted payload.
+const CHUNK_SIZE: &str = "Nats-Msg-Size";
+
+/// Header holding an object's MIME type.
+const CONTENT_TYPE: &str = "Content-Type";
+
+/// A reader over an object's bytes.
+pub type ObjectReader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Descriptive metadata stored alongside an object.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlobMetadata {
+    /// Short human-readable description.
+    pub description: Option<String>,
+    /// MIME type, stored as the `Content-Type` header.
+    pub content_type: Option<String>,
+    /// Additional headers.
+    pub headers: HashMap<String, String>,
+}
+
+impl BlobMetadata {
+    fn from_info(info: ObjectInfo) -> Self {
+        let mut headers: HashMap<String, String> = info
+            .headers
+            .iter()
+            .flat_map(|headers| headers.iter())
+            .map(|(k, v)| {
+                let v = v.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ");
+                (k.to_string(), v)
+            })
+            .collect();
+        let content_type = headers.remove(CONTENT_TYPE);
+
+        Self {
+            description: info.description,
+            content_type,
+            headers,
+        }
+    }
+
+    fn into_object_metadata(self, name: &str) -> object_store::ObjectMetadata {
+        let mut headers = HeaderMap::new();
+        for (k, v) in &self.headers {
+            headers.insert(k.as_str(), v.as_str());
+        }
+        if let Some(content_type) = &self.content_type {
+            headers.insert(CONTENT_TYPE, content_type.as_str());
+        }
+
+        object_store::ObjectMetadata {
+            name: name.to_owned(),
+            description: self.description,
+            headers: (!headers.is_empty()).then_some(headers),
+            ..object_store::ObjectMetadata::default()
+        }
     }
 }
 
 /// A blobstore container backed by a NATS JetStream object store.
+#[derive(Clone)]
 pub struct NatsContainer {
     metadata: ContainerMetadata,
     store: ObjectStore,
+    jetstream: jetstream::Context,
 }
 
 impl Debug for NatsContainer {
@@ -95,6 +189,207 @@ impl Debug for NatsContainer {
     }
 }
 
+/// Streaming access to objects.
+///
+/// Object store chunks are not indexed by offset, so a range read starting
+/// past the first chunk first scans the headers of the object's chunks, which
+/// carry each chunk's size, to find the chunk holding the first byte. Only
+/// that chunk and the ones after it are downloaded, and reading stops once the
+/// range is complete.
+impl NatsContainer {
+    /// Open a reader over bytes `start..=end` of object `name`, or `None` if
+    /// it does not exist. An `end` of 0 or `u64::MAX` reads to the end of the
+    /// object.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range is invalid or the object cannot be read.
+    pub async fn read_range(
+        &self, name: &str, start: u64, end: u64,
+    ) -> anyhow::Result<Option<Take<ObjectReader>>> {
+        let object = match self.store.get(name).await {
+            Ok(object) => object,
+            Err(e) if e.kind() == GetErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("getting object"),
+        };
+        let (start, len) = range(object.info.size as u64, start, end)?;
+
+        // reading from the start keeps the object's digest check
+        let reader: ObjectReader = if start == 0 {
+            Box::new(object)
+        } else if len == 0 {
+            // past the end: there is no chunk to seek to
+            Box::new(tokio::io::empty())
+        } else {
+            Box::new(self.chunks_from(&object.info, start).await?)
+        };
+        Ok(Some(reader.take(len)))
+    }
+
+    /// Read the object described by `info` from byte `start`, fetching only
+    /// the chunks from the one holding `start` onwards.
+    async fn chunks_from(
+        &self, info: &ObjectInfo, start: u64,
+    ) -> anyhow::Result<impl AsyncRead + Send + Unpin + use<>> {
+        // links resolve to an object that may be in another bucket
+        let stream = self
+            .jetstream
+            .get_stream(format!("OBJ_{}", info.bucket))
+            .await
+            .context("getting object store stream")?;
+        let subject = format!("$O.{}.C.{}", info.bucket, info.nuid);
+
+        let mut headers = stream
+            .create_consumer(pull::OrderedConfig {
+                filter_subject: subject.clone(),
+                headers_only: true,
+                ..pull::OrderedConfig::default()
+            })
+            .await
+            .context("scanning object chunks")?
+            .messages()
+            .await
+            .context("scanning object chunks")?;
+
+        let mut seek = Seek::new(start);
+        let (sequence, offset) = loop {
+            let message = headers
+                .next()
+                .await
+                .context("object changed during read")?
+                .context("scanning object chunks")?;
+            let size = message
+                .headers
+                .as_ref()
+                .and_then(|headers| headers.get(CHUNK_SIZE))
+                .context("chunk size missing")?
+                .as_str()
+                .parse()
+                .context("invalid chunk size")?;
+            let meta = message.info().map_err(|e| anyhow!("reading chunk info: {e}"))?;
+            if let Some(offset) = seek.chunk(size) {
+                break (meta.stream_sequence, offset);
+            }
+            if meta.pending == 0 {
+                bail!("object {} changed during read", info.name);
+            }
+        };
+
+        let chunks = stream
+            .create_consumer(pull::OrderedConfig {
+                filter_subject: subject,
+                deliver_policy: DeliverPolicy::ByStartSequence {
+                    start_sequence: sequence,
+                },
+                ..pull::OrderedConfig::default()
+            })
+            .await
+            .context("reading object chunks")?
+            .messages()
+            .await
+            .context("reading object chunks")?;
+
+        // end after the last chunk rather than waiting for more
+        let chunks = chunks.scan((offset, false), |(skip, done), message| {
+            let chunk = if *done {
+                None
+            } else {
+                Some(message.map_err(io::Error::other).and_then(|message| {
+                    let info = message.info().map_err(|e| io::Error::other(e.to_string()))?;
+                    *done = info.pending == 0;
+                    Ok(message.payload.slice(std::mem::take(skip)..))
+                }))
+            };
+            future::ready(chunk)
+        });
+        Ok(StreamReader::new(Box::pin(chunks)))
+    }
+
+    /// Write object `name` from `reader`, one chunk at a time, returning the
+    /// number of bytes written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the reader fails or the object cannot be written.
+    pub async fn write_stream(
+        &self, name: &str, reader: &mut (impl AsyncRead + Unpin),
+    ) -> anyhow::Result<u64> {
+        self.write_stream_with(name, BlobMetadata::default(), reader).await
+    }
+
+    /// Write object `name` from `reader` as [`Self::write_stream`] does,
+    /// storing `metadata` alongside it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the reader fails or the object cannot be written.
+    pub async fn write_stream_with(
+        &self, name: &str, metadata: BlobMetadata, reader: &mut (impl AsyncRead + Unpin),
+    ) -> anyhow::Result<u64> {
+        let info = self
+            .store
+            .put(metadata.into_object_metadata(name), reader)
+            .await
+            .context("writing object")?;
+        Ok(info.size as u64)
+    }
+
+    /// The description, content type, and headers stored with object `name`,
+    /// or `None` if it does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the object info cannot be read.
+    pub async fn blob_metadata(&self, name: &str) -> anyhow::Result<Option<BlobMetadata>> {
+        Ok(self.info(name).await?.map(BlobMetadata::from_info))
+    }
+
+    /// Info for object `name`, or `None` if it does not exist.
+    async fn info(&self, name: &str) -> anyhow::Result<Option<ObjectInfo>> {
+        match self.store.info(name).await {
+            Ok(info) if info.deleted => Ok(None),
+            Ok(info) => Ok(Some(info)),
+            Err(e) if e.kind() == InfoErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("getting object info"),
+        }
+    }
+}
+
+/// Locates the chunk holding byte `start` from the sizes of successive
+/// chunks.
+#[derive(Debug)]
+struct Seek {
+    start: u64,
+    /// Bytes in the chunks before the current one.
+    passed: u64,
+}
+
+impl Seek {
+    const fn new(start: u64) -> Self {
+        Self { start, passed: 0 }
+    }
+
+    /// The offset of `start` within the next chunk of `size` bytes, if the
+    /// chunk holds it.
+    fn chunk(&mut self, size: u64) -> Option<usize> {
+        let offset = self.start.checked_sub(self.passed)?;
+        self.passed += size;
+        if offset < size { usize::try_from(offset).ok() } else { None }
+    }
+}
+
+/// Resolve `start..=end` against an object of `size` bytes as an offset and
+/// length. An `end` of 0 or `u64::MAX` reads to the end of the object.
+fn range(size: u64, start: u64, end: u64) -> anyhow::Result<(u64, u64)> {
+    let unbounded = end == 0 || end == u64::MAX;
+    if !unbounded && end < start {
+        bail!("invalid byte range: end ({end}) < start ({start})");
+    }
+    let to = if unbounded { size } else { end.saturating_add(1).min(size) };
+    let start = start.min(to);
+    Ok((start, to - start))
+}
+
 impl Container for NatsContainer {
     fn name(&self) -> anyhow::Result<String> {
         tracing::trace!("getting container name");
@@ -105,14 +400,16 @@ impl Container for NatsContainer {
         Ok(self.metadata.clone())
     }
 
-    fn get_data(&self, name: String, _start: u64, _end: u64) -> FutureResult<Option<Bytes>> {
+    fn get_data(&self, name: String, start: u64, end: u64) -> FutureResult<Option<Bytes>> {
         tracing::trace!("getting object data: {name}");
-        let store = self.store.clone();
+        let container = self.clone();
 
         async move {
-            let mut object = store.get(name).await.context("getting object")?;
-            let mut bytes = vec![];
-            object.read_to_end(&mut bytes).await?;
+            let Some(mut reader) = container.read_range(&name, start, end).await? else {
+                return Ok(None);
+            };
+            let mut bytes = Vec::with_capacity(usize::try_from(reader.limit())?);
+            reader.read_to_end(&mut bytes).await.context("reading object")?;
             Ok(Some(bytes.into()))
         }
         .boxed()
@@ -120,10 +417,11 @@ impl Container for NatsContainer {
 
     fn write_data(&self, name: String, data: Bytes) -> FutureResult<()> {
         tracing::trace!("writing object data: {name}");
-        let store = self.store.clone();
+        let container = self.clone();
 
         async move {
-            store.put(name.as_str(), &mut data.as_ref()).await.context("writing object")?;
+            // chunks are published straight from the caller's buffer
+            container.write_stream(&name, &mut data.as_ref()).await?;
             Ok(())
         }
         .boxed()
@@ -157,30 +455,102 @@ impl Container for NatsContainer {
 
     fn has_object(&self, name: String) -> FutureResult<bool> {
         tracing::trace!("checking existence of object: {name}");
-        let store = self.store.clone();
-
-        async move {
-            let _ = store.get(name).await.context("checking object")?;
-            Ok(true)
-        }
-        .boxed()
+        let container = self.clone();
+        async move { Ok(container.info(&name).await?.is_some()) }.boxed()
     }
 
     fn object_info(&self, name: String) -> FutureResult<ObjectMetadata> {
         tracing::trace!("getting object info: {name}");
-        let store = self.store.clone();
-        let metadata = self.metadata.clone();
+        let container = self.clone();
 
         async move {
-            let info = store.info(&name).await.context("getting object info")?;
+            let info =
+                container.info(&name).await?.with_context(|| format!("object {name} not found"))?;
 
+            // the object store keeps only the time an object was last written
             Ok(ObjectMetadata {
-                container: metadata.name.clone(),
+                container: container.metadata.name,
                 name: info.name,
                 size: info.size as u64,
-                created_at: metadata.created_at,
+                created_at: info.modified.map(unix_secs).unwrap_or_default(),
             })
         }
         .boxed()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whole_object() {
+        assert_eq!(range(10, 0, 0).unwrap(), (0, 10));
+        assert_eq!(range(10, 0, u64::MAX).unwrap(), (0, 10));
+    }
+
+    #[test]
+    fn inclusive_end() {
+        assert_eq!(range(10, 5, 6).unwrap(), (5, 2));
+    }
+
+    #[test]
+    fn clamped_to_size() {
+        assert_eq!(range(10, 8, 100).unwrap(), (8, 2));
+        assert_eq!(range(10, 20, 30).unwrap(), (10, 0));
+        assert_eq!(range(10, 10, 0).unwrap(), (10, 0));
+    }
+
+    #[test]
+    fn seek_within_first_chunk() {
+        let mut seek = Seek::new(3);
+        assert_eq!(seek.chunk(4), Some(3));
+    }
+
+    #[test]
+    fn seek_skips_whole_chunks() {
+        // chunks need not be the same size
+        let mut seek = Seek::new(9);
+        assert_eq!(seek.chunk(4), None);
+        assert_eq!(seek.chunk(2), None);
+        assert_eq!(seek.chunk(4), Some(3));
+    }
+
+    #[test]
+    fn seek_past_end() {
+        let mut seek = Seek::new(10);
+        assert_eq!(seek.chunk(4), None);
+        assert_eq!(seek.chunk(6), None);
+    }
+
+    #[test]
+    fn blob_metadata_round_trip() {
+        let metadata = BlobMetadata {
+            description: Some("nightly build".to_owned()),
+            content_type: Some("application/x-tar".to_owned()),
+            headers: HashMap::from([("X-Build".to_owned(), "42".to_owned())]),
+        };
+        let stored = metadata.clone().into_object_metadata("build.tar");
+        let info = ObjectInfo {
+            name: stored.name,
+            description: stored.description,
+            metadata: HashMap::new(),
+            headers: stored.headers,
+            options: None,
+            bucket: "artifacts".to_owned(),
+            nuid: String::new(),
+            size: 0,
+            chunks: 0,
+            modified: None,
+            digest: None,
+            deleted: false,
+        };
+        assert_eq!(BlobMetadata::from_info(info), metadata);
+    }
+
+    #[test]
+    fn reversed_range() {
+        let err = range(10, 6, 5).unwrap_err();
+        assert!(err.to_string().contains("invalid byte range"), "{err}");
+    }
+}
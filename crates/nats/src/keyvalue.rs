@@ -1,57 +1,281 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Context;
+use anyhow::{Context, anyhow, bail};
 use async_nats::jetstream::kv::Config;
+use async_nats::jetstream::stream::{Compression, StorageType};
 use async_nats::jetstream::{self, kv};
-use futures::TryStreamExt;
 use futures::future::FutureExt;
-use omnia_wasi_keyvalue::{Bucket, FutureResult, WasiKeyValueCtx};
+use futures::{StreamExt, TryStreamExt};
+use omnia_kv_registry::intern;
+use omnia_wasi_keyvalue::{Bucket, Cas, FutureResult, WasiKeyValueCtx};
+use omnia_wasi_messaging::{Message, Metadata, Subscriptions};
 
-use crate::Client;
+use crate::{BucketMode, Client, KvOptions, Storage};
 
 /// `wasi-keyvalue` implementation backed by NATS JetStream KV store.
 impl WasiKeyValueCtx for Client {
     fn open_bucket(&self, identifier: String) -> FutureResult<Arc<dyn Bucket>> {
+        let client = self.clone();
+        async move { Ok(client.kv_bucket(identifier).await? as Arc<dyn Bucket>) }.boxed()
+    }
+}
+
+impl Client {
+    /// Open a key-value bucket as a [`KvBucket`], exposing the revision-based
+    /// operations the `wasi-keyvalue` interface does not.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the bucket cannot be opened, created, or updated
+    /// according to its configured [`BucketMode`].
+    pub async fn kv_bucket(&self, identifier: String) -> anyhow::Result<Arc<KvBucket>> {
         tracing::trace!("opening bucket: {identifier}");
-        let client = self.inner.clone();
+        if let Some(bucket) = self.buckets.get(&identifier) {
+            return Ok(bucket);
+        }
 
-        async move {
-            let jetstream = jetstream::new(client);
-            let store = if let Ok(store) = jetstream.get_key_value(&identifier).await {
-                store
-            } else {
-                let result = jetstream
-                    .create_key_value(Config {
-                        bucket: identifier,
-                        history: 1,
-                        max_age: Duration::from_mins(10),
-                        max_bytes: 100 * 1024 * 1024, // 100 MiB
-                        ..Config::default()
-                    })
-                    .await;
-
-                result.context("failed to create bucket")?
+        let options = self.kv.bucket(&identifier);
+        let jetstream = jetstream::new(self.inner.clone());
+        let config = bucket_config(self.namespace.bucket(&identifier), options);
+        let existing = jetstream.get_key_value(&config.bucket).await;
+
+        let store = match (options.mode, existing) {
+            (BucketMode::MustExist, existing) => {
+                existing.with_context(|| format!("bucket {} does not exist", config.bucket))?
+            }
+            (BucketMode::Create, Ok(store)) => store,
+            (BucketMode::Update, Ok(store)) if !drifted(&store, &config) => store,
+            (BucketMode::Update, Ok(_)) => {
+                tracing::info!("updating drifted bucket: {}", config.bucket);
+                jetstream.update_key_value(config).await.context("failed to update bucket")?
+            }
+            (_, Err(_)) => {
+                jetstream.create_key_value(config).await.context("failed to create bucket")?
+            }
+        };
+
+        let bucket = KvBucket {
+            name: intern(&identifier)?,
+            store,
+        };
+        Ok(self.buckets.insert(&identifier, Arc::new(bucket)))
+    }
+}
+
+fn bucket_config(bucket: String, options: &KvOptions) -> Config {
+    Config {
+        bucket,
+        history: options.history,
+        max_age: Duration::from_secs(options.ttl),
+        max_bytes: options.max_bytes,
+        num_replicas: options.replicas,
+        storage: match options.storage {
+            Storage::File => StorageType::File,
+            Storage::Memory => StorageType::Memory,
+        },
+        compression: options.compression,
+        ..Config::default()
+    }
+}
+
+/// Whether the bucket's stream settings differ from `config`.
+fn drifted(store: &kv::Store, config: &Config) -> bool {
+    let current = &store.stream.cached_info().config;
+    let history = current.max_messages_per_subject;
+    let compressed = matches!(current.compression, Some(Compression::S2));
+
+    history != config.history
+        || compressed != config.compression
+        || current.max_age != config.max_age
+        || current.max_bytes != config.max_bytes
+        || current.num_replicas != config.num_replicas
+        || current.storage != config.storage
+}
+
+/// A key-value bucket backed by a NATS JetStream KV store.
+#[derive(Debug, Clone)]
+pub struct KvBucket {
+    name: &'static str,
+    /// The underlying JetStream KV store.
+    pub store: kv::Store,
+}
+
+/// Attempts an increment makes before giving up under contention.
+const MAX_ATTEMPTS: usize = 10;
+
+/// A key's value and the revision it was written at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Versioned {
+    /// Stored value.
+    pub value: Vec<u8>,
+    /// JetStream revision of the write.
+    pub revision: u64,
+}
+
+/// Optimistic concurrency on top of JetStream KV revisions.
+///
+/// Every write to a key is assigned a new revision. Conditional writes pass
+/// the revision they last observed and fail if another writer got there first,
+/// so counters and locks need no server-side lock. The `wasi-keyvalue`
+/// atomics, [`Bucket::increment`] and [`Bucket::swap`], are built on these.
+impl KvBucket {
+    /// Get the current value of `key` along with its revision.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry cannot be read.
+    pub async fn get_versioned(&self, key: &str) -> anyhow::Result<Option<Versioned>> {
+        let entry = self.store.entry(key).await.context("getting entry")?;
+        Ok(entry.filter(|e| e.operation == kv::Operation::Put).map(|e| Versioned {
+            value: e.value.into(),
+            revision: e.revision,
+        }))
+    }
+
+    /// Write `value` only if `key` has no current value. Returns the new
+    /// revision, or `None` if the key already exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the write fails for any other reason.
+    pub async fn create(&self, key: &str, value: Vec<u8>) -> anyhow::Result<Option<u64>> {
+        match self.store.create(key, value.into()).await {
+            Ok(revision) => Ok(Some(revision)),
+            Err(e) if e.kind() == kv::CreateErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e).context("creating key"),
+        }
+    }
+
+    /// Write `value` only if `key` is still at `revision`. Returns the new
+    /// revision, or `None` if the key has been written since.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the write fails for any other reason.
+    pub async fn update(
+        &self, key: &str, revision: u64, value: Vec<u8>,
+    ) -> anyhow::Result<Option<u64>> {
+        match self.store.update(key, value.into(), revision).await {
+            Ok(revision) => Ok(Some(revision)),
+            Err(e) if e.kind() == kv::UpdateErrorKind::WrongLastRevision => Ok(None),
+            Err(e) => Err(e).context("updating key"),
+        }
+    }
+
+    /// Write `value` if `key` holds `current` (no value when `None`), or
+    /// return the value it holds instead.
+    async fn compare_and_swap(
+        &self, key: &str, current: Option<&[u8]>, value: Vec<u8>,
+    ) -> anyhow::Result<Result<(), Option<Vec<u8>>>> {
+        let observed = self.get_versioned(key).await?;
+        let written = match (&observed, current) {
+            (None, None) => self.create(key, value).await?,
+            (Some(observed), Some(current)) if observed.value == current => {
+                self.update(key, observed.revision, value).await?
+            }
+            _ => return Ok(Err(observed.map(|o| o.value))),
+        };
+        if written.is_some() {
+            return Ok(Ok(()));
+        }
+        // written by someone else since it was read
+        Ok(Err(self.get_versioned(key).await?.map(|o| o.value)))
+    }
+
+    /// Add `delta` to the integer stored at `key`, treating a missing key as
+    /// 0, retrying against the latest revision when another writer gets there
+    /// first.
+    async fn add(&self, key: &str, delta: i64) -> anyhow::Result<i64> {
+        for _ in 0..MAX_ATTEMPTS {
+            let current = self.get_versioned(key).await?;
+            let base = current.as_ref().map(|c| decode_i64(&c.value)).transpose()?.unwrap_or(0);
+            let value = base.checked_add(delta).context("adding delta overflows i64")?;
+
+            let written = match current {
+                Some(current) => self.update(key, current.revision, encode_i64(value)).await?,
+                None => self.create(key, encode_i64(value)).await?,
             };
+            if written.is_some() {
+                return Ok(value);
+            }
+            tracing::debug!("increment of {key} lost a race; retrying");
+        }
+        bail!("incrementing {key} failed after {MAX_ATTEMPTS} contended attempts")
+    }
+}
 
-            Ok(Arc::new(KvBucket(store)) as Arc<dyn Bucket>)
+impl KvBucket {
+    /// Stream changes to keys matching `pattern` (all keys when `None`) as
+    /// messages, starting from the next change.
+    ///
+    /// Each message's payload is the new value (empty for deletes), its topic
+    /// the key's subject `$KV.{bucket}.{key}`, and its metadata carries the
+    /// `bucket`, `key`, `revision`, and `operation` (`put`, `delete`, or
+    /// `purge`).
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the watch cannot be started.
+    pub async fn watch(&self, pattern: Option<&str>) -> anyhow::Result<Subscriptions> {
+        let watch = match pattern {
+            Some(pattern) => self.store.watch(pattern).await,
+            None => self.store.watch_all().await,
         }
-        .boxed()
+        .with_context(|| format!("watching bucket {}", self.name))?;
+        tracing::info!("watching bucket {} for {}", self.name, pattern.unwrap_or(">"));
+
+        let name = self.name;
+        let stream = watch.filter_map(move |entry| async move {
+            let entry = entry.map_err(|e| tracing::warn!("issue watching bucket: {e}")).ok()?;
+            Some(from_entry(name, entry))
+        });
+        Ok(Box::pin(stream) as Subscriptions)
     }
 }
 
-/// A key-value bucket backed by a NATS JetStream KV store.
-#[derive(Debug)]
-pub struct KvBucket(pub kv::Store);
+/// Translate a KV change into the host's [`Message`], naming the bucket as
+/// the guest knows it.
+fn from_entry(bucket: &str, entry: kv::Entry) -> Message {
+    let operation = match entry.operation {
+        kv::Operation::Put => "put",
+        kv::Operation::Delete => "delete",
+        kv::Operation::Purge => "purge",
+    };
+
+    let mut message = Message::new(entry.value.to_vec());
+    message.topic = format!("$KV.{bucket}.{}", entry.key);
+    message.metadata = Some(Metadata {
+        inner: HashMap::from([
+            ("bucket".to_owned(), bucket.to_owned()),
+            ("key".to_owned(), entry.key),
+            ("revision".to_owned(), entry.revision.to_string()),
+            ("operation".to_owned(), operation.to_owned()),
+        ]),
+    });
+    message
+}
+
+fn decode_i64(value: &[u8]) -> anyhow::Result<i64> {
+    let bytes: [u8; 8] = value.try_into().map_err(|_len| {
+        anyhow!("value is {} bytes, not an 8-byte big-endian integer", value.len())
+    })?;
+    Ok(i64::from_be_bytes(bytes))
+}
+
+fn encode_i64(value: i64) -> Vec<u8> {
+    value.to_be_bytes().to_vec()
+}
 
 impl Bucket for KvBucket {
     fn name(&self) -> &'static str {
-        Box::leak(self.0.name.clone().into_boxed_str())
+        self.name
     }
 
     fn get(&self, key: String) -> FutureResult<Option<Vec<u8>>> {
         tracing::trace!("getting key: {key}");
-        let store = self.0.clone();
+        let store = self.store.clone();
 
         async move {
             let entry = store.get(key).await.context("getting key")?;
@@ -62,7 +286,7 @@ impl Bucket for KvBucket {
 
     fn set(&self, key: String, value: Vec<u8>) -> FutureResult<()> {
         tracing::trace!("setting key: {key}");
-        let store = self.0.clone();
+        let store = self.store.clone();
 
         async move {
             store.put(key, value.into()).await.context("setting key")?;
@@ -73,7 +297,7 @@ impl Bucket for KvBucket {
 
     fn delete(&self, key: String) -> FutureResult<()> {
         tracing::trace!("deleting key: {key}");
-        let store = self.0.clone();
+        let store = self.store.clone();
 
         async move {
             store.delete(key).await.context("deleting key")?;
@@ -84,7 +308,7 @@ impl Bucket for KvBucket {
 
     fn exists(&self, key: String) -> FutureResult<bool> {
         tracing::trace!("checking existence of key: {key}");
-        let store = self.0.clone();
+        let store = self.store.clone();
 
         async move {
             let entry = store.get(key).await.context("checking key")?;
@@ -94,7 +318,7 @@ impl Bucket for KvBucket {
     }
 
     fn keys(&self) -> FutureResult<Vec<String>> {
-        let store = self.0.clone();
+        let store = self.store.clone();
 
         async move {
             tracing::trace!("listing keys");
@@ -107,4 +331,38 @@ impl Bucket for KvBucket {
         }
         .boxed()
     }
+
+    /// Integers are stored as 8-byte big-endian values.
+    fn increment(&self, key: String, delta: i64) -> FutureResult<i64> {
+        tracing::trace!("incrementing key: {key}");
+        let bucket = self.clone();
+        async move { bucket.add(&key, delta).await }.boxed()
+    }
+
+    fn swap(&self, cas: Cas, value: Vec<u8>) -> FutureResult<Result<(), Cas>> {
+        tracing::trace!("swapping key: {}", cas.key);
+        let bucket = self.clone();
+
+        async move {
+            let swapped = bucket.compare_and_swap(&cas.key, cas.current.as_deref(), value).await?;
+            Ok(swapped.map_err(|current| Cas { current, ..cas }))
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integer_round_trip() {
+        assert_eq!(decode_i64(&encode_i64(-42)).unwrap(), -42);
+    }
+
+    #[test]
+    fn non_integer_value() {
+        let err = decode_i64(b"42").unwrap_err();
+        assert!(err.to_string().contains("not an 8-byte"), "{err}");
+    }
 }
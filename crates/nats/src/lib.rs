@@ -3,19 +3,52 @@
 mod blobstore;
 mod keyvalue;
 mod messaging;
+mod namespace;
+mod service;
 
+use std::collections::HashMap;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use async_nats::AuthError;
+use anyhow::{Context, Result, anyhow, bail};
+use async_nats::{AuthError, Event};
 use omnia::Backend;
+use omnia_kv_registry::Buckets;
+use tokio::sync::OnceCell;
 use tracing::instrument;
 
+pub use crate::blobstore::{BlobMetadata, NatsContainer};
+pub use crate::keyvalue::{KvBucket, Versioned};
+use crate::messaging::Unsettled;
+pub use crate::messaging::{ACK_SUBJECT, DELIVERED};
+use crate::namespace::Namespace;
+use crate::service::Pending;
+
 /// NATS backend client for messaging, key-value, and blobstore.
 #[derive(Debug, Clone)]
 pub struct Client {
     inner: async_nats::Client,
     topics: Option<Vec<String>>,
+    watches: Option<Vec<String>>,
+    /// Queue group shared by replicas subscribing to each topic.
+    queue_groups: Arc<QueueGroups>,
+    /// Durable consumer settings when subscriptions go through JetStream.
+    jetstream: Option<Arc<JetStreamOptions>>,
+    /// Subjects captured by the JetStream stream, looked up on first publish.
+    stream_subjects: Arc<OnceCell<Vec<String>>>,
+    /// Micro service settings when subscriptions serve requests.
+    service: Option<Arc<ServiceOptions>>,
+    /// Service requests awaiting a reply from the guest.
+    pending: Arc<Pending>,
+    /// JetStream messages awaiting settlement by the guest.
+    unsettled: Arc<Unsettled>,
+    /// Tenant subject prefix and topic mappings.
+    namespace: Arc<Namespace>,
+    /// Settings applied to key-value buckets.
+    kv: Arc<KvSettings>,
+    /// Opened key-value buckets, shared by clones of this client.
+    buckets: Arc<Buckets<KvBucket>>,
 }
 
 impl Backend for Client {
@@ -23,47 +56,500 @@ impl Backend for Client {
 
     #[instrument]
     async fn connect_with(options: Self::ConnectOptions) -> Result<Self> {
-        let mut nats_opts = async_nats::ConnectOptions::new();
+        if let Some(jetstream) = &options.jetstream {
+            jetstream.validate()?;
+        }
+
+        let nats_opts = nats_options(&options).await?;
+        let servers: Vec<&str> =
+            options.address.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        let client = nats_opts.connect(servers).await.context("connecting to NATS")?;
 
-        if let Some(jwt) = &options.jwt
-            && let Some(seed) = &options.seed
-        {
+        Ok(Self {
+            inner: client,
+            topics: options.topics,
+            watches: options.watches,
+            queue_groups: Arc::new(QueueGroups::new(
+                options.queue_group,
+                options.queue_groups.as_deref().unwrap_or_default(),
+            )?),
+            jetstream: options.jetstream.map(Arc::new),
+            stream_subjects: Arc::default(),
+            service: options.service.map(Arc::new),
+            pending: Arc::default(),
+            unsettled: Arc::default(),
+            namespace: Arc::new(Namespace::new(
+                options.subject_prefix,
+                options.topic_map.as_deref().unwrap_or_default(),
+            )?),
+            kv: Arc::new(KvSettings {
+                defaults: options.kv,
+                buckets: options.kv_buckets,
+            }),
+            buckets: Arc::default(),
+        })
+    }
+}
+
+/// Build the `async_nats` options: credentials, TLS, reconnect behaviour, and
+/// connection-event logging.
+async fn nats_options(options: &ConnectOptions) -> Result<async_nats::ConnectOptions> {
+    let mut nats_opts = match options.credentials()? {
+        None => async_nats::ConnectOptions::new(),
+        Some(Credentials::File(path)) => async_nats::ConnectOptions::with_credentials_file(path)
+            .await
+            .with_context(|| format!("reading credentials file {path}"))?,
+        Some(Credentials::Jwt { jwt, seed }) => {
             let key_pair = nkeys::KeyPair::from_seed(seed).context("creating KeyPair")?;
             let key_pair = Arc::new(key_pair);
-            nats_opts = nats_opts.jwt(jwt.clone(), move |nonce| {
+            async_nats::ConnectOptions::with_jwt(jwt.to_owned(), move |nonce| {
                 let key_pair = Arc::clone(&key_pair);
                 async move { key_pair.sign(&nonce).map_err(AuthError::new) }
-            });
+            })
+        }
+        Some(Credentials::NKey(seed)) => async_nats::ConnectOptions::with_nkey(seed.to_owned()),
+        Some(Credentials::UserPassword { user, password }) => {
+            async_nats::ConnectOptions::with_user_and_password(user.to_owned(), password.to_owned())
         }
+        Some(Credentials::Token(token)) => async_nats::ConnectOptions::with_token(token.to_owned()),
+    };
 
-        let client = nats_opts.connect(&options.address).await.context("connecting to NATS")?;
+    let tls = &options.tls;
+    if let Some(ca_cert) = &tls.ca_cert {
+        nats_opts = nats_opts.add_root_certificates(ca_cert.into());
+    }
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => {
+            nats_opts = nats_opts.add_client_certificate(cert.into(), key.into());
+        }
+        (None, None) => {}
+        _ => bail!("NATS_TLS_CERT_FILE and NATS_TLS_KEY_FILE must be set together"),
+    }
+    if tls.required {
+        nats_opts = nats_opts.require_tls(true);
+    }
 
-        Ok(Self {
-            inner: client,
-            topics: options.topics,
-        })
+    let reconnect = options.reconnect.clone();
+    nats_opts = nats_opts
+        .connection_timeout(Duration::from_secs(reconnect.connect_timeout))
+        .ping_interval(Duration::from_secs(reconnect.ping_interval))
+        .max_reconnects(reconnect.max_reconnects)
+        .reconnect_delay_callback(move |attempts| reconnect.delay(attempts))
+        .event_callback(|event| async move { log_event(&event) });
+    if options.reconnect.retry_on_initial_connect {
+        nats_opts = nats_opts.retry_on_initial_connect();
+    }
+    Ok(nats_opts)
+}
+
+fn log_event(event: &Event) {
+    match event {
+        Event::Connected | Event::Draining | Event::Closed => tracing::info!("nats {event}"),
+        Event::Disconnected | Event::LameDuckMode | Event::SlowConsumer(_) => {
+            tracing::warn!("nats {event}");
+        }
+        Event::ServerError(_) | Event::ClientError(_) => tracing::error!("nats {event}"),
+    }
+}
+
+/// The single authentication mechanism configured for a connection.
+#[derive(Debug, PartialEq, Eq)]
+enum Credentials<'a> {
+    File(&'a str),
+    Jwt { jwt: &'a str, seed: &'a str },
+    NKey(&'a str),
+    UserPassword { user: &'a str, password: &'a str },
+    Token(&'a str),
+}
+
+impl ConnectOptions {
+    fn credentials(&self) -> Result<Option<Credentials<'_>>> {
+        let mut configured = vec![];
+        if let Some(path) = &self.creds_file {
+            configured.push(Credentials::File(path));
+        }
+        match (&self.jwt, &self.seed) {
+            (Some(jwt), Some(seed)) => configured.push(Credentials::Jwt { jwt, seed }),
+            (Some(_), None) => bail!("NATS_JWT requires NATS_SEED to sign server challenges"),
+            (None, Some(seed)) => configured.push(Credentials::NKey(seed)),
+            (None, None) => {}
+        }
+        match (&self.user, &self.password) {
+            (Some(user), Some(password)) => {
+                configured.push(Credentials::UserPassword { user, password });
+            }
+            (None, None) => {}
+            _ => bail!("NATS_USER and NATS_PASSWORD must be set together"),
+        }
+        if let Some(token) = &self.token {
+            configured.push(Credentials::Token(token));
+        }
+
+        if configured.len() > 1 {
+            bail!("more than one NATS authentication method is configured");
+        }
+        Ok(configured.pop())
+    }
+}
+
+impl ReconnectOptions {
+    /// Delay before reconnect attempt `attempts`: immediate at first, then
+    /// doubling from the base delay up to the maximum.
+    fn delay(&self, attempts: usize) -> Duration {
+        if attempts <= 1 {
+            return Duration::ZERO;
+        }
+        let exp = u32::try_from(attempts - 2).unwrap_or(u32::MAX);
+        let delay = self.delay.saturating_mul(2_u64.saturating_pow(exp));
+        Duration::from_millis(delay.min(self.max_delay))
+    }
+}
+
+/// Key-value bucket settings: defaults plus per-bucket overrides.
+#[derive(Debug)]
+struct KvSettings {
+    defaults: KvOptions,
+    buckets: HashMap<String, KvOptions>,
+}
+
+impl KvSettings {
+    fn bucket(&self, name: &str) -> &KvOptions {
+        self.buckets.get(name).unwrap_or(&self.defaults)
+    }
+}
+
+/// Queue groups for subscription topics: a default plus per-topic overrides.
+#[derive(Debug, Default)]
+struct QueueGroups {
+    default: Option<String>,
+    topics: HashMap<String, String>,
+}
+
+impl QueueGroups {
+    /// Build from a default group and `topic=group` overrides.
+    fn new(default: Option<String>, overrides: &[String]) -> Result<Self> {
+        let topics = overrides
+            .iter()
+            .map(|entry| {
+                let (topic, group) = entry.split_once('=').ok_or_else(|| {
+                    anyhow!("invalid queue group {entry:?}: expected topic=group")
+                })?;
+                Ok((topic.trim().to_owned(), group.trim().to_owned()))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { default, topics })
+    }
+
+    /// The queue group to subscribe to `topic` with, if any. An empty
+    /// per-topic group opts the topic out of the default.
+    fn topic(&self, topic: &str) -> Option<&str> {
+        self.topics
+            .get(topic)
+            .map_or(self.default.as_deref(), |group| Some(group.as_str()))
+            .filter(|group| !group.is_empty())
     }
 }
 
 #[allow(missing_docs)]
 mod config {
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    use anyhow::bail;
     use fromenv::{FromEnv, ParseResult};
 
     /// Connection options for the NATS backend.
-    #[derive(Debug, Clone, FromEnv)]
+    #[derive(Clone, FromEnv)]
     pub struct ConnectOptions {
-        /// NATS server address.
+        /// Comma-separated NATS server addresses, tried in turn for failover.
         #[env(from = "NATS_ADDR", default = "demo.nats.io")]
         pub address: String,
         /// Optional topics for subscription mode.
         #[env(from = "NATS_TOPICS", with = split)]
         pub topics: Option<Vec<String>>,
+        /// Optional key-value buckets to watch in subscription mode, each
+        /// `bucket` or `bucket:pattern`.
+        #[env(from = "NATS_KV_WATCH", with = split)]
+        pub watches: Option<Vec<String>>,
+        /// Optional queue group for subscriptions, so replicas share messages
+        /// rather than each receiving every one.
+        #[env(from = "NATS_QUEUE_GROUP")]
+        pub queue_group: Option<String>,
+        /// Optional per-topic queue groups, each `topic=group`.
+        #[env(from = "NATS_QUEUE_GROUPS", with = split)]
+        pub queue_groups: Option<Vec<String>>,
+        /// Optional prefix applied to every subject and, encoded, to bucket
+        /// and stream names, isolating deployments sharing an account.
+        #[env(from = "NATS_SUBJECT_PREFIX")]
+        pub subject_prefix: Option<String>,
+        /// Optional guest topic to subject mappings, each `topic=subject`.
+        #[env(from = "NATS_TOPIC_MAP", with = split)]
+        pub topic_map: Option<Vec<String>>,
         /// Optional JWT used for NATS authentication.
         #[env(from = "NATS_JWT")]
         pub jwt: Option<String>,
-        /// Optional `NKey` seed used to sign server nonce challenges.
+        /// Optional `NKey` seed used to sign server nonce challenges, with
+        /// `NATS_JWT` or on its own for `NKey` authentication.
         #[env(from = "NATS_SEED")]
         pub seed: Option<String>,
+        /// Optional path to a `.creds` file holding a JWT and `NKey` seed.
+        #[env(from = "NATS_CREDS_FILE")]
+        pub creds_file: Option<String>,
+        /// Optional username for password authentication.
+        #[env(from = "NATS_USER")]
+        pub user: Option<String>,
+        /// Optional password for password authentication.
+        #[env(from = "NATS_PASSWORD")]
+        pub password: Option<String>,
+        /// Optional token for token authentication.
+        #[env(from = "NATS_TOKEN")]
+        pub token: Option<String>,
+        /// TLS settings.
+        #[env(nested)]
+        pub tls: TlsOptions,
+        /// Reconnect and keep-alive settings.
+        #[env(nested)]
+        pub reconnect: ReconnectOptions,
+        /// Optional JetStream durable consumer configuration. When set, topics
+        /// are consumed with at-least-once delivery.
+        #[env(nested)]
+        pub jetstream: Option<JetStreamOptions>,
+        /// Optional micro service configuration. When set, the endpoints are
+        /// served to the guest through subscriptions.
+        #[env(nested)]
+        pub service: Option<ServiceOptions>,
+        /// Default settings for key-value buckets.
+        #[env(nested)]
+        pub kv: KvOptions,
+        /// Per-bucket settings, keyed by bucket name. [`omnia::FromEnv`] loads
+        /// these for the buckets listed in `NATS_KV_BUCKETS`.
+        pub kv_buckets: HashMap<String, KvOptions>,
+    }
+
+    impl std::fmt::Debug for ConnectOptions {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            let redacted = |secret: &Option<String>| secret.as_ref().map(|_| "[REDACTED]");
+            f.debug_struct("ConnectOptions")
+                .field("address", &self.address)
+                .field("topics", &self.topics)
+                .field("watches", &self.watches)
+                .field("queue_group", &self.queue_group)
+                .field("queue_groups", &self.queue_groups)
+                .field("subject_prefix", &self.subject_prefix)
+                .field("topic_map", &self.topic_map)
+                .field("jwt", &self.jwt)
+                .field("seed", &redacted(&self.seed))
+                .field("creds_file", &self.creds_file)
+                .field("user", &self.user)
+                .field("password", &redacted(&self.password))
+                .field("token", &redacted(&self.token))
+                .field("tls", &self.tls)
+                .field("reconnect", &self.reconnect)
+                .field("jetstream", &self.jetstream)
+                .field("service", &self.service)
+                .field("kv", &self.kv)
+                .field("kv_buckets", &self.kv_buckets)
+                .finish()
+        }
+    }
+
+    /// TLS settings. Without a CA file, the server certificate is verified
+    /// against the platform's native trust store.
+    #[derive(Debug, Clone, Default, FromEnv)]
+    pub struct TlsOptions {
+        /// Path to a PEM bundle of CA certificates used to verify the server.
+        #[env(from = "NATS_TLS_CA_FILE")]
+        pub ca_cert: Option<String>,
+        /// Path to a PEM client certificate for mutual TLS.
+        #[env(from = "NATS_TLS_CERT_FILE")]
+        pub client_cert: Option<String>,
+        /// Path to the PEM private key for the client certificate.
+        #[env(from = "NATS_TLS_KEY_FILE")]
+        pub client_key: Option<String>,
+        /// Whether to refuse connections the server does not upgrade to TLS.
+        #[env(from = "NATS_TLS_REQUIRED", default = "false")]
+        pub required: bool,
+    }
+
+    /// Reconnect and keep-alive settings.
+    #[derive(Debug, Clone, FromEnv)]
+    pub struct ReconnectOptions {
+        /// Seconds to wait for a connection to be established.
+        #[env(from = "NATS_CONNECT_TIMEOUT", default = "5")]
+        pub connect_timeout: u64,
+        /// Seconds between pings used to detect a dead connection.
+        #[env(from = "NATS_PING_INTERVAL", default = "60")]
+        pub ping_interval: u64,
+        /// Reconnect attempts before giving up. Unlimited when unset.
+        #[env(from = "NATS_MAX_RECONNECTS")]
+        pub max_reconnects: Option<usize>,
+        /// Milliseconds before the second reconnect attempt, doubling with
+        /// each attempt after.
+        #[env(from = "NATS_RECONNECT_DELAY", default = "100")]
+        pub delay: u64,
+        /// Upper bound in milliseconds on the reconnect delay.
+        #[env(from = "NATS_RECONNECT_DELAY_MAX", default = "4000")]
+        pub max_delay: u64,
+        /// Whether to keep retrying when the first connection attempt fails.
+        #[env(from = "NATS_RETRY_ON_INITIAL_CONNECT", default = "false")]
+        pub retry_on_initial_connect: bool,
+    }
+
+    /// NATS micro service configuration for `wasi-messaging`.
+    #[derive(Debug, Clone, FromEnv)]
+    pub struct ServiceOptions {
+        /// Service name advertised to `$SRV` discovery.
+        #[env(from = "NATS_SERVICE_NAME")]
+        pub name: String,
+        /// Semantic version advertised to `$SRV` discovery.
+        #[env(from = "NATS_SERVICE_VERSION", default = "0.1.0")]
+        pub version: String,
+        /// Optional human-readable description.
+        #[env(from = "NATS_SERVICE_DESCRIPTION")]
+        pub description: Option<String>,
+        /// Comma-separated subjects served as endpoints.
+        #[env(from = "NATS_SERVICE_ENDPOINTS", with = split)]
+        pub endpoints: Vec<String>,
+        /// Queue group shared by service replicas. Defaults to `q`.
+        #[env(from = "NATS_SERVICE_QUEUE_GROUP")]
+        pub queue_group: Option<String>,
+        /// Seconds to wait for the guest's reply before failing a request.
+        #[env(from = "NATS_SERVICE_TIMEOUT", default = "30")]
+        pub timeout: u64,
+    }
+
+    /// JetStream durable consumer configuration for `wasi-messaging`.
+    #[derive(Debug, Clone, FromEnv)]
+    pub struct JetStreamOptions {
+        /// Stream capturing the subscription topics; created when missing.
+        #[env(from = "NATS_JS_STREAM")]
+        pub stream: String,
+        /// Durable consumer shared by all replicas; created when missing.
+        #[env(from = "NATS_JS_CONSUMER", default = "omnia")]
+        pub consumer: String,
+        /// When messages are acknowledged.
+        #[env(from = "NATS_JS_ACK", default = "explicit", with = parse_enum)]
+        pub ack: AckMode,
+        /// Seconds the server waits for an ack before redelivering, so
+        /// handlers must settle messages within it.
+        #[env(from = "NATS_JS_ACK_WAIT", default = "30")]
+        pub ack_wait: u64,
+        /// Deliveries attempted before a message is dead-lettered.
+        #[env(from = "NATS_JS_MAX_DELIVER", default = "5")]
+        pub max_deliver: i64,
+        /// Seconds to wait before redelivering a rejected message, by
+        /// delivery count; the last value repeats.
+        #[env(from = "NATS_JS_BACKOFF", default = "1,5,30", with = split_secs)]
+        pub backoff: Vec<u64>,
+        /// Optional subject messages are forwarded to after `max_deliver`
+        /// failed deliveries.
+        #[env(from = "NATS_JS_DEAD_LETTER")]
+        pub dead_letter: Option<String>,
+    }
+
+    /// When JetStream messages are acknowledged.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AckMode {
+        /// The guest settles each message by sending to the subject in its
+        /// `nats-ack-subject` metadata once handled; messages left unsettled
+        /// are redelivered after the ack wait.
+        Explicit,
+        /// Acknowledge once the message has been handed to the host.
+        Delivery,
+    }
+
+    impl FromStr for AckMode {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> anyhow::Result<Self> {
+            match s {
+                "explicit" => Ok(Self::Explicit),
+                "delivery" => Ok(Self::Delivery),
+                _ => bail!("invalid ack mode {s:?}: expected explicit or delivery"),
+            }
+        }
+    }
+
+    /// JetStream key-value bucket settings.
+    #[derive(Debug, Clone, FromEnv)]
+    pub struct KvOptions {
+        /// How `open_bucket` treats missing or differently configured buckets.
+        #[env(from = "NATS_KV_MODE", default = "create", with = parse_enum)]
+        pub mode: BucketMode,
+        /// Number of historical values kept per key.
+        #[env(from = "NATS_KV_HISTORY", default = "1")]
+        pub history: i64,
+        /// Seconds before a value expires. 0 keeps values indefinitely.
+        #[env(from = "NATS_KV_TTL", default = "0")]
+        pub ttl: u64,
+        /// Maximum bucket size in bytes. -1 is unlimited.
+        #[env(from = "NATS_KV_MAX_BYTES", default = "104857600")]
+        pub max_bytes: i64,
+        /// Number of replicas in a clustered deployment.
+        #[env(from = "NATS_KV_REPLICAS", default = "1")]
+        pub replicas: usize,
+        /// Storage backing the bucket.
+        #[env(from = "NATS_KV_STORAGE", default = "file", with = parse_enum)]
+        pub storage: Storage,
+        /// Whether stored values are S2 compressed.
+        #[env(from = "NATS_KV_COMPRESSION", default = "false")]
+        pub compression: bool,
+    }
+
+    /// How `open_bucket` reconciles a bucket with its configured settings.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum BucketMode {
+        /// Create missing buckets; leave existing ones untouched.
+        Create,
+        /// Fail when the bucket does not exist.
+        MustExist,
+        /// Create missing buckets and update existing ones whose settings have
+        /// drifted from the configuration.
+        Update,
+    }
+
+    impl FromStr for BucketMode {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> anyhow::Result<Self> {
+            match s {
+                "create" => Ok(Self::Create),
+                "must-exist" => Ok(Self::MustExist),
+                "update" => Ok(Self::Update),
+                _ => bail!("invalid bucket mode {s:?}: expected create, must-exist, or update"),
+            }
+        }
+    }
+
+    /// Storage backing a key-value bucket.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Storage {
+        /// Values are persisted to disk.
+        File,
+        /// Values are kept in memory only.
+        Memory,
+    }
+
+    impl FromStr for Storage {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> anyhow::Result<Self> {
+            match s {
+                "file" => Ok(Self::File),
+                "memory" => Ok(Self::Memory),
+                _ => bail!("invalid storage {s:?}: expected file or memory"),
+            }
+        }
+    }
+
+    // The default `FromEnv` parser needs a `std::error::Error`, which
+    // `anyhow::Error` is not.
+    fn parse_enum<T: FromStr<Err = anyhow::Error>>(s: &str) -> ParseResult<T> {
+        Ok(s.parse()?)
+    }
+
+    fn split_secs(s: &str) -> ParseResult<Vec<u64>> {
+        Ok(s.split(',').map(|v| v.trim().parse()).collect::<Result<_, _>>()?)
     }
 
     // The `FromEnv` `with =` hook requires a `ParseResult` return type.
@@ -72,10 +558,216 @@ mod config {
         Ok(s.split(',').map(ToOwned::to_owned).collect())
     }
 }
-pub use config::ConnectOptions;
+pub use config::{
+    AckMode, BucketMode, ConnectOptions, JetStreamOptions, KvOptions, ReconnectOptions,
+    ServiceOptions, Storage, TlsOptions,
+};
 
 impl omnia::FromEnv for ConnectOptions {
     fn from_env() -> Result<Self> {
-        Self::from_env().finalize().context("issue loading connection options")
+        let defaults = KvOptions::from_env().finalize().context("issue loading bucket options")?;
+
+        // optional per-bucket settings: NATS_KV_BUCKETS=sessions
+        let kv_buckets = std::env::var("NATS_KV_BUCKETS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| Ok((name.to_owned(), defaults.with_overrides(name, env_var)?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        Self::from_env()
+            .kv_buckets(kv_buckets)
+            .finalize()
+            .context("issue loading connection options")
+    }
+}
+
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok()
+}
+
+impl JetStreamOptions {
+    fn validate(&self) -> Result<()> {
+        if self.max_deliver < 1 {
+            bail!("NATS_JS_MAX_DELIVER must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+impl KvOptions {
+    /// These settings with any `NATS_KV_*__{BUCKET}` overrides applied, where
+    /// `{BUCKET}` is the bucket name upper-cased with `-` replaced by `_`.
+    fn with_overrides(&self, bucket: &str, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let suffix = bucket.to_ascii_uppercase().replace('-', "_");
+        let var = |name: &str| {
+            let key = format!("{name}__{suffix}");
+            var(&key).map(|value| (key, value))
+        };
+
+        Ok(Self {
+            mode: parse(var("NATS_KV_MODE"))?.unwrap_or(self.mode),
+            history: parse(var("NATS_KV_HISTORY"))?.unwrap_or(self.history),
+            ttl: parse(var("NATS_KV_TTL"))?.unwrap_or(self.ttl),
+            max_bytes: parse(var("NATS_KV_MAX_BYTES"))?.unwrap_or(self.max_bytes),
+            replicas: parse(var("NATS_KV_REPLICAS"))?.unwrap_or(self.replicas),
+            storage: parse(var("NATS_KV_STORAGE"))?.unwrap_or(self.storage),
+            compression: parse(var("NATS_KV_COMPRESSION"))?.unwrap_or(self.compression),
+        })
+    }
+}
+
+/// Parse an optional `(key, value)` environment variable.
+fn parse<T>(var: Option<(String, String)>) -> Result<Option<T>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    var.map(|(key, value)| value.parse().map_err(|e| anyhow!("invalid {key} {value:?}: {e}")))
+        .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> KvOptions {
+        KvOptions {
+            mode: BucketMode::Create,
+            history: 1,
+            ttl: 0,
+            max_bytes: -1,
+            replicas: 1,
+            storage: Storage::File,
+            compression: false,
+        }
+    }
+
+    #[test]
+    fn bucket_overrides() {
+        let vars = HashMap::from([
+            ("NATS_KV_TTL__USER_SESSIONS", "600"),
+            ("NATS_KV_MODE__USER_SESSIONS", "must-exist"),
+            ("NATS_KV_STORAGE__USER_SESSIONS", "memory"),
+        ]);
+        let options = defaults()
+            .with_overrides("user-sessions", |key| vars.get(key).map(ToString::to_string))
+            .unwrap();
+
+        assert_eq!(options.ttl, 600);
+        assert_eq!(options.mode, BucketMode::MustExist);
+        assert_eq!(options.storage, Storage::Memory);
+        assert_eq!(options.history, 1);
+    }
+
+    #[test]
+    fn invalid_override() {
+        let err = defaults()
+            .with_overrides("orders", |key| {
+                (key == "NATS_KV_MODE__ORDERS").then(|| "sometimes".to_owned())
+            })
+            .unwrap_err();
+        assert!(err.to_string().contains("NATS_KV_MODE__ORDERS"), "{err}");
+    }
+
+    fn connect_options() -> ConnectOptions {
+        ConnectOptions {
+            address: "demo.nats.io".to_owned(),
+            topics: None,
+            watches: None,
+            queue_group: None,
+            queue_groups: None,
+            subject_prefix: None,
+            topic_map: None,
+            jwt: None,
+            seed: None,
+            creds_file: None,
+            user: None,
+            password: None,
+            token: None,
+            tls: TlsOptions::default(),
+            reconnect: ReconnectOptions {
+                connect_timeout: 5,
+                ping_interval: 60,
+                max_reconnects: None,
+                delay: 100,
+                max_delay: 4000,
+                retry_on_initial_connect: false,
+            },
+            jetstream: None,
+            service: None,
+            kv: defaults(),
+            kv_buckets: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn nkey_without_jwt() {
+        let options = ConnectOptions {
+            seed: Some("SUASEED".to_owned()),
+            ..connect_options()
+        };
+        assert_eq!(options.credentials().unwrap(), Some(Credentials::NKey("SUASEED")));
+    }
+
+    #[test]
+    fn conflicting_credentials() {
+        let options = ConnectOptions {
+            token: Some("s3cr3t".to_owned()),
+            creds_file: Some("user.creds".to_owned()),
+            ..connect_options()
+        };
+        let err = options.credentials().unwrap_err();
+        assert!(err.to_string().contains("more than one"), "{err}");
+    }
+
+    #[test]
+    fn user_without_password() {
+        let options = ConnectOptions {
+            user: Some("app".to_owned()),
+            ..connect_options()
+        };
+        let err = options.credentials().unwrap_err();
+        assert!(err.to_string().contains("must be set together"), "{err}");
+    }
+
+    #[test]
+    fn secrets_redacted() {
+        let options = ConnectOptions {
+            user: Some("app".to_owned()),
+            password: Some("s3cr3t".to_owned()),
+            ..connect_options()
+        };
+        assert!(!format!("{options:?}").contains("s3cr3t"));
+    }
+
+    #[test]
+    fn reconnect_backoff() {
+        let reconnect = connect_options().reconnect;
+        assert_eq!(reconnect.delay(1), Duration::ZERO);
+        assert_eq!(reconnect.delay(2), Duration::from_millis(100));
+        assert_eq!(reconnect.delay(4), Duration::from_millis(400));
+        assert_eq!(reconnect.delay(100), Duration::from_millis(4000));
+    }
+
+    #[test]
+    fn queue_group_overrides() {
+        let groups = QueueGroups::new(
+            Some("workers".to_owned()),
+            &["orders.*=order-workers".to_owned(), "audit=".to_owned()],
+        )
+        .unwrap();
+
+        assert_eq!(groups.topic("orders.*"), Some("order-workers"));
+        assert_eq!(groups.topic("audit"), None);
+        assert_eq!(groups.topic("events"), Some("workers"));
+        assert_eq!(QueueGroups::default().topic("events"), None);
+    }
+
+    #[test]
+    fn invalid_queue_group() {
+        let err = QueueGroups::new(None, &["orders".to_owned()]).unwrap_err();
+        assert!(err.to_string().contains("expected topic=group"), "{err}");
     }
 }
@@ -1,13 +1,40 @@
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, anyhow};
+use anyhow::{Context, anyhow, bail};
+use async_nats::header::NATS_MESSAGE_ID;
+use async_nats::jetstream::consumer::{AckPolicy, pull};
+use async_nats::jetstream::message::PublishMessage;
+use async_nats::jetstream::{self, AckKind, stream as js_stream};
 use futures::future::FutureExt;
 use futures::stream::{self, StreamExt};
 use omnia_wasi_messaging::{
     Client, FutureResult, Message, Metadata, Reply, RequestOptions, Subscriptions, WasiMessagingCtx,
 };
 
+use crate::namespace::{self, Namespace};
+use crate::{AckMode, JetStreamOptions};
+
+/// Metadata key holding a JetStream message's ack subject.
+pub const ACK_SUBJECT: &str = "nats-ack-subject";
+
+/// Metadata key holding the number of times a JetStream message has been
+/// delivered.
+pub const DELIVERED: &str = "nats-delivered";
+
+/// Prefix of JetStream ack subjects.
+const ACK_PREFIX: &str = "$JS.ACK.";
+/// Payload a guest sends to an ack subject to have the message redelivered.
+const NAK: &[u8] = b"-NAK";
+/// Payload a guest sends to an ack subject to extend its ack wait.
+const IN_PROGRESS: &[u8] = b"+WPI";
+
+/// Attempts a JetStream publish makes before giving up. Retries reuse the
+/// message id, so the stream stores the message at most once.
+const PUBLISH_ATTEMPTS: usize = 3;
+
 /// `wasi-messaging` implementation backed by NATS.
 impl WasiMessagingCtx for crate::Client {
     fn connect(&self) -> FutureResult<Arc<dyn Client>> {
@@ -17,7 +44,7 @@ impl WasiMessagingCtx for crate::Client {
 }
 
 /// Translate an incoming NATS message into the host's [`Message`].
-fn from_nats(msg: async_nats::Message) -> Message {
+pub fn from_nats(msg: async_nats::Message, namespace: &Namespace) -> Message {
     let metadata = msg.headers.as_ref().map(|headers| {
         let mut md = HashMap::new();
         for (k, v) in headers.iter() {
@@ -28,7 +55,7 @@ fn from_nats(msg: async_nats::Message) -> Message {
     });
 
     let mut message = Message::new(msg.payload.to_vec());
-    message.topic = msg.subject.to_string();
+    message.topic = namespace.topic(&msg.subject);
     message.metadata = metadata;
     message.description = msg.description;
     message.reply = msg.reply.map(|r| Reply {
@@ -38,7 +65,7 @@ fn from_nats(msg: async_nats::Message) -> Message {
     message
 }
 
-fn nats_headers(metadata: &Metadata) -> async_nats::HeaderMap {
+pub fn nats_headers(metadata: &Metadata) -> async_nats::HeaderMap {
     let mut headers = async_nats::HeaderMap::new();
     for (k, v) in metadata.iter() {
         headers.insert(k.as_str(), v.as_str());
@@ -51,48 +78,75 @@ impl Client for crate::Client {
         let client = self.clone();
 
         async move {
-            let Some(topics) = client.topics else {
+            let topics = client.topics.clone().unwrap_or_default();
+            let watches = client.watches.clone().unwrap_or_default();
+            if topics.is_empty() && watches.is_empty() && client.service.is_none() {
                 return Err(anyhow!("No topics specified"));
-            };
+            }
 
             let mut subscribers = vec![];
-            for t in &topics {
-                let subscriber = client.inner.subscribe(t.clone()).await?;
-                subscribers.push(subscriber);
+            if let Some(options) = &client.service {
+                subscribers.push(client.serve(options).await?);
+            }
+            if let Some(options) = &client.jetstream
+                && !topics.is_empty()
+            {
+                subscribers.push(client.consume(options, &topics).await?);
+            } else {
+                for t in &topics {
+                    let subject = client.namespace.subject(t);
+                    let subscriber = match client.queue_groups.topic(t) {
+                        Some(group) => {
+                            tracing::debug!("joining queue group {group} for {t}");
+                            client.inner.queue_subscribe(subject, group.to_owned()).await?
+                        }
+                        None => client.inner.subscribe(subject).await?,
+                    };
+                    let namespace = Arc::clone(&client.namespace);
+                    let stream = subscriber.map(move |msg| from_nats(msg, &namespace));
+                    subscribers.push(Box::pin(stream) as Subscriptions);
+                }
             }
-
             tracing::info!("subscribed to {topics:?} topics");
 
+            // watches are `bucket` or `bucket:pattern`
+            for watch in watches {
+                let (bucket, pattern) = match watch.split_once(':') {
+                    Some((bucket, pattern)) => (bucket.to_owned(), Some(pattern)),
+                    None => (watch.clone(), None),
+                };
+                let bucket = client.kv_bucket(bucket).await?;
+                subscribers.push(bucket.watch(pattern).await?);
+            }
+
             // process messages until terminated
-            let stream = stream::select_all(subscribers).map(from_nats);
+            let stream = stream::select_all(subscribers);
             Ok(Box::pin(stream) as Subscriptions)
         }
         .boxed()
     }
 
     fn send(&self, topic: String, message: Message) -> FutureResult<()> {
-        let client = self.inner.clone();
-        async move {
-            match &message.metadata {
-                None => client
-                    .publish(topic, message.payload.into())
-                    .await
-                    .context("failed to publish")?,
-                Some(metadata) => client
-                    .publish_with_headers(topic, nats_headers(metadata), message.payload.into())
-                    .await
-                    .context("failed to publish")?,
+        if self.service.is_some() {
+            let client = self.clone();
+            return async move {
+                // replies to service requests go back through their endpoint
+                let Some(message) = client.respond(&topic, message).await else {
+                    return Ok(());
+                };
+                client.publish(topic, message).await
             }
-
-            Ok(())
+            .boxed();
         }
-        .boxed()
+        let client = self.clone();
+        async move { client.publish(topic, message).await }.boxed()
     }
 
     fn request(
         &self, topic: String, message: Message, options: Option<RequestOptions>,
     ) -> FutureResult<Message> {
         let client = self.inner.clone();
+        let namespace = Arc::clone(&self.namespace);
 
         async move {
             let nats_headers = message.metadata.as_ref().map(nats_headers).unwrap_or_default();
@@ -103,10 +157,403 @@ impl Client for crate::Client {
                 .headers(nats_headers)
                 .timeout(timeout);
 
+            let subject = namespace.subject(&topic);
             let nats_msg =
-                client.send_request(topic, request).await.context("failed to send request")?;
-            Ok(from_nats(nats_msg))
+                client.send_request(subject, request).await.context("failed to send request")?;
+            Ok(from_nats(nats_msg, &namespace))
         }
         .boxed()
     }
 }
+
+impl crate::Client {
+    /// Publish to `topic`, through JetStream when the subject is captured by
+    /// the stream.
+    async fn publish(&self, topic: String, message: Message) -> anyhow::Result<()> {
+        if topic.starts_with(ACK_PREFIX) {
+            return self.settle(topic, message.payload).await;
+        }
+        let subject = self.namespace.subject(&topic);
+        if let Some(options) = &self.jetstream
+            && self.captured(options, &subject).await
+        {
+            return self.publish_durable(subject, message).await;
+        }
+
+        match &message.metadata {
+            None => self
+                .inner
+                .publish(subject, message.payload.into())
+                .await
+                .context("failed to publish")?,
+            Some(metadata) => self
+                .inner
+                .publish_with_headers(subject, nats_headers(metadata), message.payload.into())
+                .await
+                .context("failed to publish")?,
+        }
+        Ok(())
+    }
+
+    /// Whether the stream stores `subject`, so a publish should wait for the
+    /// stream's ack. Reply inboxes and system subjects never go to the stream.
+    async fn captured(&self, options: &JetStreamOptions, subject: &str) -> bool {
+        if namespace::reserved(subject) {
+            return false;
+        }
+        let lookup = self
+            .stream_subjects
+            .get_or_try_init(|| async {
+                let jetstream = jetstream::new(self.inner.clone());
+                let stream = jetstream.get_stream(self.namespace.stream(&options.stream)).await?;
+                anyhow::Ok(stream.cached_info().config.subjects.clone())
+            })
+            .await;
+        match lookup {
+            Ok(subjects) => subjects.iter().any(|s| subject_matches(s, subject)),
+            Err(e) => {
+                // until the stream exists, assume it captures the topics it
+                // will be created with
+                tracing::debug!("looking up stream {}: {e}", options.stream);
+                self.topics
+                    .iter()
+                    .flatten()
+                    .any(|t| subject_matches(&self.namespace.subject(t), subject))
+            }
+        }
+    }
+
+    /// Settle a JetStream message on behalf of the guest. A `-NAK` is given
+    /// the configured backoff for the message's delivery count; an empty
+    /// payload acknowledges it, and anything else is passed on as sent. Only
+    /// messages this client handed to the guest and that are still unsettled
+    /// can be settled, so a guest cannot settle other consumers' messages.
+    async fn settle(&self, subject: String, payload: Vec<u8>) -> anyhow::Result<()> {
+        if !self.unsettled.settle(&subject, payload != IN_PROGRESS) {
+            bail!("cannot settle {subject}: no unsettled message was delivered with it");
+        }
+        let payload = if payload == NAK {
+            let delays = self.jetstream.as_ref().map(|o| o.backoff.as_slice()).unwrap_or_default();
+            let delivered = delivered(&subject).unwrap_or(1);
+            AckKind::Nak(backoff(delays, delivered)).into()
+        } else if payload.is_empty() {
+            AckKind::Ack.into()
+        } else {
+            payload.into()
+        };
+        self.inner.publish(subject, payload).await.context("settling message")?;
+        Ok(())
+    }
+}
+
+/// At-least-once messaging through JetStream.
+///
+/// Topics are captured by a stream and read through a durable pull consumer
+/// shared by all replicas. The host does not report when a guest has handled
+/// a message, so in [`AckMode::Explicit`] the guest settles it by sending to
+/// its ack subject; messages it does not settle are redelivered after the ack
+/// wait. Messages redelivered more than `max_deliver` times are forwarded to
+/// the dead-letter subject and terminated. The consumer allows one delivery
+/// beyond `max_deliver` so the client sees exhausted messages.
+impl crate::Client {
+    async fn consume(
+        &self, options: &Arc<JetStreamOptions>, topics: &[String],
+    ) -> anyhow::Result<Subscriptions> {
+        let subjects: Vec<String> = topics.iter().map(|t| self.namespace.subject(t)).collect();
+        let name = self.namespace.stream(&options.stream);
+        let jetstream = jetstream::new(self.inner.clone());
+        let stream = jetstream
+            .get_or_create_stream(js_stream::Config {
+                name: name.clone(),
+                subjects: subjects.clone(),
+                ..js_stream::Config::default()
+            })
+            .await
+            .with_context(|| format!("opening stream {name}"))?;
+
+        let consumer = stream
+            .get_or_create_consumer(
+                &options.consumer,
+                pull::Config {
+                    durable_name: Some(options.consumer.clone()),
+                    ack_policy: AckPolicy::Explicit,
+                    ack_wait: Duration::from_secs(options.ack_wait),
+                    max_deliver: options.max_deliver + 1,
+                    filter_subjects: subjects,
+                    ..pull::Config::default()
+                },
+            )
+            .await
+            .with_context(|| format!("opening consumer {}", options.consumer))?;
+        let messages = consumer.messages().await.context("consuming stream")?;
+        tracing::info!("consuming {topics:?} through {}", options.consumer);
+
+        let client = self.clone();
+        let options = Arc::clone(options);
+        let stream = messages.filter_map(move |msg| {
+            let client = client.clone();
+            let options = Arc::clone(&options);
+            async move {
+                let msg = msg.map_err(|e| tracing::warn!("issue consuming stream: {e}")).ok()?;
+                client.accept(&options, msg).await
+            }
+        });
+        Ok(Box::pin(stream) as Subscriptions)
+    }
+
+    /// Translate a consumed message for the host, dead-lettering it instead
+    /// once its deliveries are exhausted.
+    async fn accept(&self, options: &JetStreamOptions, msg: jetstream::Message) -> Option<Message> {
+        let delivered = msg.info().map_or(1, |info| info.delivered);
+        if delivered > options.max_deliver {
+            self.dead_letter(options, &msg).await;
+            return None;
+        }
+
+        let ack_subject = msg.reply.as_ref().map(ToString::to_string);
+        let mut message = from_nats(msg.message.clone(), &self.namespace);
+        // the reply subject is JetStream's ack subject, not a reply topic
+        message.reply = None;
+        let metadata = message.metadata.get_or_insert_with(Metadata::new);
+        metadata.insert(DELIVERED.to_owned(), delivered.to_string());
+        if let Some(ack_subject) = ack_subject {
+            if options.ack == AckMode::Explicit {
+                let expiry = Duration::from_secs(options.ack_wait);
+                self.unsettled.insert(ack_subject.clone(), expiry);
+            }
+            metadata.insert(ACK_SUBJECT.to_owned(), ack_subject);
+        }
+
+        if options.ack == AckMode::Delivery
+            && let Err(e) = msg.ack().await
+        {
+            tracing::warn!("failed to ack message on {}: {e}", msg.subject);
+        }
+        Some(message)
+    }
+
+    async fn dead_letter(&self, options: &JetStreamOptions, msg: &jetstream::Message) {
+        tracing::warn!("message on {} exceeded {} deliveries", msg.subject, options.max_deliver);
+
+        if let Some(dead_letter) = &options.dead_letter {
+            let subject = self.namespace.subject(dead_letter);
+            let mut headers = msg.headers.clone().unwrap_or_default();
+            headers.insert("Nats-Original-Subject", msg.subject.as_str());
+            if let Err(e) =
+                self.inner.publish_with_headers(subject, headers, msg.payload.clone()).await
+            {
+                tracing::error!("failed to dead-letter message on {}: {e}", msg.subject);
+            }
+        }
+        if let Err(e) = msg.ack_with(AckKind::Term).await {
+            tracing::warn!("failed to terminate message on {}: {e}", msg.subject);
+        }
+    }
+
+    /// Publish to a stream, retrying with the same `Nats-Msg-Id` until the
+    /// server acknowledges the write.
+    async fn publish_durable(&self, subject: String, message: Message) -> anyhow::Result<()> {
+        let jetstream = jetstream::new(self.inner.clone());
+        let headers = message.metadata.as_ref().map(nats_headers).unwrap_or_default();
+        // honour an id the guest supplied so its own retries are deduplicated
+        let id = headers.get(NATS_MESSAGE_ID).map_or_else(message_id, ToString::to_string);
+        let publish = PublishMessage::build()
+            .payload(message.payload.into())
+            .headers(headers)
+            .message_id(&id);
+
+        let mut attempt = 1;
+        loop {
+            let result = match jetstream.send_publish(subject.clone(), publish.clone()).await {
+                Ok(ack) => ack.await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(ack) => {
+                    if ack.duplicate {
+                        tracing::debug!("message {id} already stored in {}", ack.stream);
+                    }
+                    return Ok(());
+                }
+                Err(e) if attempt < PUBLISH_ATTEMPTS => {
+                    tracing::warn!("publish of {id} to {subject} failed, retrying: {e}");
+                    attempt += 1;
+                }
+                Err(e) => return Err(e).context("failed to publish"),
+            }
+        }
+    }
+
+    /// Acknowledge a consumed message from host code, so it is not
+    /// redelivered. Guests do the same by sending to the message's
+    /// [`ACK_SUBJECT`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the message did not come from a JetStream consumer
+    /// or the acknowledgement cannot be sent.
+    pub async fn ack(&self, message: &Message) -> anyhow::Result<()> {
+        self.acknowledge(message, AckKind::Ack).await
+    }
+
+    /// Reject a consumed message from host code, so it is redelivered after
+    /// the configured backoff for its delivery count. Guests do the same by
+    /// sending `-NAK` to the message's [`ACK_SUBJECT`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if JetStream is not enabled, the message did not come
+    /// from a JetStream consumer, or the rejection cannot be sent.
+    pub async fn nak(&self, message: &Message) -> anyhow::Result<()> {
+        let options = self.jetstream.as_ref().context("JetStream is not enabled")?;
+        let delivered = message
+            .metadata
+            .as_ref()
+            .and_then(|md| md.get(DELIVERED))
+            .and_then(|d| d.parse().ok())
+            .unwrap_or(1);
+        self.acknowledge(message, AckKind::Nak(backoff(&options.backoff, delivered))).await
+    }
+
+    async fn acknowledge(&self, message: &Message, kind: AckKind) -> anyhow::Result<()> {
+        let subject = message
+            .metadata
+            .as_ref()
+            .and_then(|md| md.get(ACK_SUBJECT))
+            .context("message has no JetStream ack subject")?;
+        self.unsettled.settle(subject, true);
+        self.inner.publish(subject.clone(), kind.into()).await.context("acknowledging message")?;
+        Ok(())
+    }
+}
+
+/// Ack subjects of messages handed to the guest in [`AckMode::Explicit`] and
+/// not yet settled.
+#[derive(Debug, Default)]
+pub struct Unsettled {
+    subjects: Mutex<HashMap<String, Instant>>,
+}
+
+impl Unsettled {
+    /// Track `subject`, forgetting subjects delivered more than `expiry` ago:
+    /// their messages have been redelivered under new subjects.
+    fn insert(&self, subject: String, expiry: Duration) {
+        let mut subjects = self.subjects.lock().unwrap_or_else(PoisonError::into_inner);
+        subjects.retain(|_, delivered| delivered.elapsed() < expiry);
+        subjects.insert(subject, Instant::now());
+    }
+
+    /// Whether `subject` is unsettled, forgetting it once `done` and
+    /// otherwise restarting its expiry.
+    fn settle(&self, subject: &str, done: bool) -> bool {
+        let mut subjects = self.subjects.lock().unwrap_or_else(PoisonError::into_inner);
+        if done {
+            return subjects.remove(subject).is_some();
+        }
+        subjects.get_mut(subject).map(|delivered| *delivered = Instant::now()).is_some()
+    }
+}
+
+/// Delay before redelivering a message that has been delivered `delivered`
+/// times; the last delay repeats.
+fn backoff(delays: &[u64], delivered: usize) -> Option<Duration> {
+    delays
+        .get(delivered.saturating_sub(1))
+        .or_else(|| delays.last())
+        .map(|secs| Duration::from_secs(*secs))
+}
+
+/// The delivery count in a JetStream ack subject, which is
+/// `$JS.ACK.<stream>.<consumer>.<delivered>...` or, on newer servers,
+/// `$JS.ACK.<domain>.<account>.<stream>.<consumer>.<delivered>...`.
+fn delivered(ack_subject: &str) -> Option<usize> {
+    let tokens: Vec<&str> = ack_subject.strip_prefix(ACK_PREFIX)?.split('.').collect();
+    let index = match tokens.len() {
+        7 => 2,
+        n if n >= 9 => 4,
+        _ => return None,
+    };
+    tokens[index].parse().ok()
+}
+
+/// Whether `subject` matches `pattern`, where `*` matches one token and `>`
+/// matches one or more trailing tokens.
+fn subject_matches(pattern: &str, subject: &str) -> bool {
+    let mut tokens = subject.split('.');
+    for expected in pattern.split('.') {
+        match (expected, tokens.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (expected, Some(token)) if expected == token => {}
+            _ => return false,
+        }
+    }
+    tokens.next().is_none()
+}
+
+/// Message ids only need to be unique within the stream's duplicate window.
+fn message_id() -> String {
+    static PREFIX: LazyLock<String> = LazyLock::new(|| {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        format!("{nanos:x}.{:x}", std::process::id())
+    });
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!("{}.{:x}", *PREFIX, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_repeats_last_delay() {
+        let delays = [1, 5, 30];
+        assert_eq!(backoff(&delays, 1), Some(Duration::from_secs(1)));
+        assert_eq!(backoff(&delays, 3), Some(Duration::from_secs(30)));
+        assert_eq!(backoff(&delays, 9), Some(Duration::from_secs(30)));
+        assert_eq!(backoff(&[], 1), None);
+    }
+
+    #[test]
+    fn delivery_count_from_ack_subject() {
+        assert_eq!(delivered("$JS.ACK.orders.omnia.3.10.4.1700000000000000000.0"), Some(3));
+        assert_eq!(
+            delivered("$JS.ACK._.ACCHASH.orders.omnia.2.10.4.1700000000000000000.0.token"),
+            Some(2)
+        );
+        assert_eq!(delivered("$JS.ACK.orders"), None);
+        assert_eq!(delivered("_INBOX.abc"), None);
+    }
+
+    #[test]
+    fn only_unsettled_subjects_settle() {
+        let unsettled = Unsettled::default();
+        let subject = "$JS.ACK.orders.omnia.1.10.4.1700000000000000000.0";
+        unsettled.insert(subject.to_owned(), Duration::from_secs(30));
+
+        assert!(!unsettled.settle("$JS.ACK.other.omnia.1.1.1.1700000000000000000.0", true));
+        assert!(unsettled.settle(subject, false), "in progress keeps it unsettled");
+        assert!(unsettled.settle(subject, true));
+        assert!(!unsettled.settle(subject, true), "settled once");
+
+        unsettled.insert("$JS.ACK.expired".to_owned(), Duration::ZERO);
+        unsettled.insert(subject.to_owned(), Duration::ZERO);
+        assert!(!unsettled.settle("$JS.ACK.expired", true), "expired subjects are forgotten");
+    }
+
+    #[test]
+    fn subject_wildcards() {
+        assert!(subject_matches("orders.*", "orders.created"));
+        assert!(!subject_matches("orders.*", "orders.created.eu"));
+        assert!(subject_matches("orders.>", "orders.created.eu"));
+        assert!(!subject_matches("orders.>", "orders"));
+        assert!(subject_matches("audit", "audit"));
+        assert!(!subject_matches("audit", "audit.log"));
+        assert!(!subject_matches("orders.*", "_INBOX.abc"));
+    }
+
+    #[test]
+    fn message_ids_unique() {
+        assert_ne!(message_id(), message_id());
+    }
+}
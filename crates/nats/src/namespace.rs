@@ -0,0 +1,160 @@
+//! Tenant namespacing for deployments sharing a NATS account.
+//!
+//! Guest topics are mapped to NATS subjects on the way out and back on the
+//! way in, so guests stay unaware of tenancy. A topic is first rewritten by
+//! the mapping table (exact matches only) and then given the subject prefix.
+//! Key-value and object store buckets and the JetStream stream get a
+//! matching name prefix. Reply inboxes (`_INBOX.`) and system subjects (`$`)
+//! are never rewritten.
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow};
+
+/// Mapping between guest topics and bucket names and their NATS equivalents.
+#[derive(Debug, Default)]
+pub struct Namespace {
+    prefix: Option<String>,
+    /// Guest topic to NATS subject.
+    subjects: HashMap<String, String>,
+    /// NATS subject to guest topic.
+    topics: HashMap<String, String>,
+}
+
+impl Namespace {
+    /// Build from a subject prefix and `topic=subject` mappings.
+    pub fn new(prefix: Option<String>, mappings: &[String]) -> Result<Self> {
+        let subjects = mappings
+            .iter()
+            .map(|entry| {
+                let (topic, subject) = entry.split_once('=').ok_or_else(|| {
+                    anyhow!("invalid topic mapping {entry:?}: expected topic=subject")
+                })?;
+                Ok((topic.trim().to_owned(), subject.trim().to_owned()))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        let topics = subjects.iter().map(|(t, s)| (s.clone(), t.clone())).collect();
+
+        Ok(Self {
+            prefix: prefix.filter(|p| !p.is_empty()),
+            subjects,
+            topics,
+        })
+    }
+
+    /// The NATS subject for guest `topic`.
+    pub fn subject(&self, topic: &str) -> String {
+        if reserved(topic) {
+            return topic.to_owned();
+        }
+        let subject = self.subjects.get(topic).map_or(topic, String::as_str);
+        self.prefix
+            .as_ref()
+            .map_or_else(|| subject.to_owned(), |prefix| format!("{prefix}.{subject}"))
+    }
+
+    /// The guest topic for NATS `subject`.
+    pub fn topic(&self, subject: &str) -> String {
+        if reserved(subject) {
+            return subject.to_owned();
+        }
+        let unprefixed = self
+            .prefix
+            .as_ref()
+            .and_then(|prefix| subject.strip_prefix(prefix.as_str()))
+            .and_then(|rest| rest.strip_prefix('.'))
+            .unwrap_or(subject);
+        self.topics.get(unprefixed).cloned().unwrap_or_else(|| unprefixed.to_owned())
+    }
+
+    /// The NATS name for guest bucket or container `name`.
+    ///
+    /// Bucket names allow only letters, digits, `-`, and `_`, so the prefix
+    /// is encoded: `.` becomes `-`, and any other character that is not a
+    /// letter or digit becomes `_` followed by the two hex digits of each of
+    /// its bytes. The encoded prefix never contains `__`, which separates it
+    /// from `name`, so distinct prefixes and names never share a bucket.
+    pub fn bucket(&self, name: &str) -> String {
+        self.prefix
+            .as_ref()
+            .map_or_else(|| name.to_owned(), |prefix| format!("{}__{name}", encode(prefix)))
+    }
+
+    /// The NATS name for JetStream stream `name`, encoded as bucket names are.
+    pub fn stream(&self, name: &str) -> String {
+        self.bucket(name)
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+/// Encode a subject prefix using only the characters bucket names allow.
+fn encode(prefix: &str) -> String {
+    let mut encoded = String::with_capacity(prefix.len());
+    for byte in prefix.bytes() {
+        match byte {
+            b'.' => encoded.push('-'),
+            b if b.is_ascii_alphanumeric() => encoded.push(char::from(b)),
+            b => {
+                encoded.push('_');
+                encoded.push(char::from(HEX[usize::from(b >> 4)]));
+                encoded.push(char::from(HEX[usize::from(b & 0xF)]));
+            }
+        }
+    }
+    encoded
+}
+
+/// Reply inboxes and system subjects pass through unchanged.
+pub fn reserved(subject: &str) -> bool {
+    subject.starts_with("_INBOX.") || subject.starts_with('$')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant() -> Namespace {
+        Namespace::new(Some("acme.prod".to_owned()), &["orders=sales.orders".to_owned()]).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let ns = tenant();
+        assert_eq!(ns.subject("orders"), "acme.prod.sales.orders");
+        assert_eq!(ns.topic("acme.prod.sales.orders"), "orders");
+        assert_eq!(ns.subject("events.*"), "acme.prod.events.*");
+        assert_eq!(ns.topic("acme.prod.events.created"), "events.created");
+    }
+
+    #[test]
+    fn reserved_subjects() {
+        let ns = tenant();
+        assert_eq!(ns.subject("_INBOX.abc"), "_INBOX.abc");
+        assert_eq!(ns.topic("$KV.config.key"), "$KV.config.key");
+    }
+
+    #[test]
+    fn bucket_names() {
+        assert_eq!(tenant().bucket("sessions"), "acme-prod__sessions");
+        assert_eq!(tenant().stream("EVENTS"), "acme-prod__EVENTS");
+        assert_eq!(Namespace::default().bucket("sessions"), "sessions");
+    }
+
+    #[test]
+    fn bucket_names_are_distinct() {
+        let bucket = |prefix: &str, name: &str| {
+            Namespace::new(Some(prefix.to_owned()), &[]).unwrap().bucket(name)
+        };
+        assert_eq!(bucket("a_b", "c"), "a_5Fb__c");
+        assert_eq!(bucket("a-b", "c"), "a_2Db__c");
+        assert_ne!(bucket("a.b", "c"), bucket("a_b", "c"));
+        assert_ne!(bucket("a.b", "c"), bucket("a-b", "c"));
+        assert_ne!(bucket("a", "b__c"), bucket("a__b", "c"));
+    }
+
+    #[test]
+    fn invalid_mapping() {
+        let err = Namespace::new(None, &["orders".to_owned()]).unwrap_err();
+        assert!(err.to_string().contains("expected topic=subject"), "{err}");
+    }
+}
@@ -0,0 +1,173 @@
+//! NATS micro service mode for `wasi-messaging`.
+//!
+//! Configured subjects are registered as endpoints of a NATS service, so the
+//! service answers `$SRV.PING`, `$SRV.INFO`, and `$SRV.STATS` discovery and
+//! monitoring requests. Requests flow to the guest through `subscribe` with
+//! their reply inbox as the reply topic; the guest's reply to that inbox is
+//! routed back through the endpoint, which records request counts, errors,
+//! and processing time. Requests left unanswered past the timeout are failed
+//! so callers are not left waiting and the failure is counted.
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_nats::service::endpoint::Endpoint;
+use async_nats::service::error::Error as ServiceError;
+use async_nats::service::{
+    NATS_SERVICE_ERROR, NATS_SERVICE_ERROR_CODE, Request, Service, ServiceExt,
+};
+use futures::future;
+use futures::stream::{self, SelectAll, Stream, StreamExt};
+use omnia_wasi_messaging::{Message, Metadata, Subscriptions};
+
+use crate::messaging::{from_nats, nats_headers};
+use crate::{Client, ServiceOptions};
+
+/// Status code sent when the guest does not reply in time.
+const TIMED_OUT: usize = 504;
+
+/// Requests awaiting a reply from the guest, keyed by reply inbox.
+#[derive(Debug, Default)]
+pub struct Pending {
+    requests: Mutex<HashMap<String, Request>>,
+}
+
+impl Pending {
+    fn insert(&self, reply: String, request: Request) {
+        self.requests.lock().unwrap_or_else(PoisonError::into_inner).insert(reply, request);
+    }
+
+    fn take(&self, reply: &str) -> Option<Request> {
+        self.requests.lock().unwrap_or_else(PoisonError::into_inner).remove(reply)
+    }
+}
+
+impl Client {
+    /// Start the configured service and stream its requests as messages.
+    pub(crate) async fn serve(&self, options: &ServiceOptions) -> anyhow::Result<Subscriptions> {
+        let mut builder = self.inner.service_builder();
+        if let Some(description) = &options.description {
+            builder = builder.description(description);
+        }
+        if let Some(queue_group) = &options.queue_group {
+            builder = builder.queue_group(queue_group);
+        }
+        let service = builder
+            .start(&options.name, &options.version)
+            .await
+            .map_err(|e| anyhow!("starting service {}: {e}", options.name))?;
+
+        let mut endpoints = vec![];
+        for subject in &options.endpoints {
+            let endpoint = service
+                .endpoint(self.namespace.subject(subject))
+                .await
+                .map_err(|e| anyhow!("adding service endpoint {subject}: {e}"))?;
+            endpoints.push(endpoint);
+        }
+        tracing::info!("serving {:?} as {} {}", options.endpoints, options.name, options.version);
+
+        let client = self.clone();
+        let timeout = Duration::from_secs(options.timeout);
+        let requests = Requests {
+            _service: service,
+            endpoints: stream::select_all(endpoints),
+        };
+        let stream = requests
+            .filter_map(move |request| future::ready(client.accept_request(request, timeout)));
+        Ok(Box::pin(stream) as Subscriptions)
+    }
+
+    fn accept_request(&self, request: Request, timeout: Duration) -> Option<Message> {
+        let Some(reply) = request.message.reply.as_ref().map(ToString::to_string) else {
+            tracing::warn!("dropping service request without a reply subject");
+            return None;
+        };
+        let message = from_nats(request.message.clone(), &self.namespace);
+        self.pending.insert(reply.clone(), request);
+
+        let pending = Arc::clone(&self.pending);
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            if let Some(request) = pending.take(&reply) {
+                let error = ServiceError {
+                    status: "request timed out".to_owned(),
+                    code: TIMED_OUT,
+                };
+                if let Err(e) = request.respond(Err(error)).await {
+                    tracing::warn!("failed to time out service request: {e}");
+                }
+            }
+        });
+        Some(message)
+    }
+
+    /// Answer the pending service request replying on `topic`, if any, with
+    /// `message`. Returns the message back when there is no such request.
+    pub(crate) async fn respond(&self, topic: &str, message: Message) -> Option<Message> {
+        let Some(request) = self.pending.take(topic) else {
+            return Some(message);
+        };
+
+        let headers = message.metadata.as_ref().map(nats_headers).unwrap_or_default();
+        let response = match message.metadata.as_ref().and_then(service_error) {
+            Some(error) => Err(error),
+            None => Ok(message.payload.into()),
+        };
+        if let Err(e) = request.respond_with_headers(response, headers).await {
+            tracing::warn!("failed to respond to service request: {e}");
+        }
+        None
+    }
+}
+
+/// The error a guest reports by setting the `Nats-Service-Error` and,
+/// optionally, `Nats-Service-Error-Code` metadata on its reply.
+fn service_error(metadata: &Metadata) -> Option<ServiceError> {
+    let status = metadata.get(NATS_SERVICE_ERROR)?;
+    let code = metadata.get(NATS_SERVICE_ERROR_CODE).and_then(|c| c.parse().ok()).unwrap_or(500);
+    Some(ServiceError {
+        status: status.clone(),
+        code,
+    })
+}
+
+/// Requests to a running service. The service stops when this is dropped.
+struct Requests {
+    _service: Service,
+    endpoints: SelectAll<Endpoint>,
+}
+
+impl Stream for Requests {
+    type Item = Request;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Request>> {
+        self.endpoints.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guest_error() {
+        let metadata = Metadata {
+            inner: HashMap::from([
+                (NATS_SERVICE_ERROR.to_owned(), "invalid order".to_owned()),
+                (NATS_SERVICE_ERROR_CODE.to_owned(), "400".to_owned()),
+            ]),
+        };
+        let error = service_error(&metadata).unwrap();
+        assert_eq!(error.status, "invalid order");
+        assert_eq!(error.code, 400);
+    }
+
+    #[test]
+    fn successful_reply() {
+        assert!(service_error(&Metadata::new()).is_none());
+    }
+}
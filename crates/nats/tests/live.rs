@@ -1,7 +1,6 @@
-//! Live publish for the NATS backend, driven through the `omnia:messaging` host
-//! boundary (`WasiMessagingCtx` + the `Client` producer proxy). NATS also serves
-//! `wasi:keyvalue` and `wasi:blobstore` from the same client; a dedicated
-//! ignored test per surface can be added here as those live envs are set up.
+//! Live publish, key-value atomics, and blob range reads for the NATS backend,
+//! driven through the `omnia:messaging` host boundary (`WasiMessagingCtx` + the
+//! `Client` producer proxy), `WasiKeyValueCtx`, and `WasiBlobstoreCtx`.
 //!
 //! `#[ignore]`d so it never touches the network in CI. Run against a reachable
 //! server (`NATS_ADDR`, default `demo.nats.io`):
@@ -12,6 +11,8 @@ use std::sync::Arc;
 use anyhow::Result;
 use omnia::Backend;
 use omnia_nats::Client;
+use omnia_wasi_blobstore::{Bytes, WasiBlobstoreCtx};
+use omnia_wasi_keyvalue::{Cas, WasiKeyValueCtx};
 use omnia_wasi_messaging::{Client as MessagingClient, Message, WasiMessagingCtx};
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -23,3 +24,51 @@ async fn publishes_message() -> Result<()> {
     producer.send("omnia.live".to_owned(), Message::new(b"omnia-live".to_vec())).await?;
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable NATS server (NATS_ADDR); run with --run-ignored"]
+async fn keyvalue_atomics() -> Result<()> {
+    let backend = <Client as Backend>::connect().await?;
+    let bucket = WasiKeyValueCtx::open_bucket(&backend, "omnia-live".to_owned()).await?;
+    let key = format!("counter-{}", std::process::id());
+
+    assert_eq!(bucket.increment(key.clone(), 2).await?, 2);
+    assert_eq!(bucket.increment(key.clone(), -5).await?, -3);
+    assert_eq!(bucket.get(key.clone()).await?, Some((-3_i64).to_be_bytes().to_vec()));
+
+    let cas = Cas {
+        bucket: Arc::clone(&bucket),
+        key: key.clone(),
+        current: Some(b"stale".to_vec()),
+    };
+    let Err(fresh) = bucket.swap(cas, b"lost".to_vec()).await? else {
+        panic!("swap against a stale value succeeded");
+    };
+    assert_eq!(
+        fresh.current,
+        Some((-3_i64).to_be_bytes().to_vec()),
+        "refreshed to the stored value"
+    );
+    assert!(bucket.swap(fresh, b"won".to_vec()).await?.is_ok(), "swap against the stored value");
+    assert_eq!(bucket.get(key.clone()).await?, Some(b"won".to_vec()));
+
+    bucket.delete(key).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable NATS server (NATS_ADDR); run with --run-ignored"]
+async fn reads_past_the_end() -> Result<()> {
+    let backend = <Client as Backend>::connect().await?;
+    let name = format!("omnia-live-{}", std::process::id());
+    let container = backend.create_container(name.clone()).await?;
+    container.write_data("obj".to_owned(), Bytes::from_static(b"0123456789")).await?;
+
+    let tail = container.get_data("obj".to_owned(), 8, 0).await?;
+    assert_eq!(tail.as_deref(), Some(&b"89"[..]));
+    let past = container.get_data("obj".to_owned(), 20, 30).await?;
+    assert_eq!(past.as_deref(), Some(&b""[..]), "a range past the end is empty");
+
+    backend.delete_container(name).await?;
+    Ok(())
+}
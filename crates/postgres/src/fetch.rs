@@ -0,0 +1,342 @@
+//! Bounded fetching of query results.
+//!
+//! A `wasi-sql` query returns all its rows at once, so the host holds the
+//! whole result while converting it. To keep that bounded, rows are
+//! converted as they arrive from the server and collection stops with an
+//! error once a pool's row or byte limit is exceeded.
+//!
+//! Queries stream their rows with `query_raw`, one round trip however many
+//! rows there are. Past a limit the server still sends the rest of the result,
+//! which the client discards. A pool with a `fetch_size` opts plain reads
+//! (`SELECT`, `VALUES`, `TABLE`) into a server-side cursor fetched that many
+//! rows at a time instead, so the server stops producing rows as soon as a
+//! limit is hit.
+//!
+//! Rows arrive in binary, so a query is prepared first to see its columns.
+//! When some have a type with no conversion (see [`converts`]), the query is
+//! wrapped to select those columns as `text` instead, so the guest gets their
+//! text form rather than opaque bytes.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result, bail};
+use futures::TryStreamExt;
+use omnia_wasi_sql::{DataType, Row};
+use tokio_postgres::types::Type;
+use tokio_postgres::{Row as PgRow, Statement};
+
+use crate::sql::into_wasi_row;
+use crate::transaction::Session;
+use crate::types::{ParamRef, converts};
+
+/// Cursor names only need to be unique per client; a process-wide counter
+/// is simplest.
+static CURSORS: AtomicU64 = AtomicU64::new(0);
+
+/// Limits on the rows a query may return. Zero disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Rows fetched from a cursor at a time; zero, the default, streams
+    /// without one.
+    pub fetch_size: usize,
+    /// Maximum rows returned by one query.
+    pub max_rows: usize,
+    /// Maximum approximate size, in bytes, of the rows returned by one query.
+    pub max_bytes: usize,
+}
+
+/// Run `query`, collecting its rows within `limits`.
+pub async fn fetch(
+    session: &Session, query: &str, params: &[ParamRef<'_>], limits: Limits,
+) -> Result<Vec<Row>> {
+    let mut rows = Collector::new(limits);
+    let (wrapped, statement) = prepare(session, query).await?;
+    if limits.fetch_size > 0
+        && let Some(read) = cursor_query(query)
+    {
+        fetch_cursor(session, wrapped.as_deref().unwrap_or(read), params, &mut rows).await?;
+    } else {
+        let stream = session
+            .track(query, session.client().query_raw(&statement, params.iter().copied()))
+            .await
+            .context("query failed")?;
+        futures::pin_mut!(stream);
+        while let Some(row) = stream.try_next().await.context("query failed")? {
+            rows.push(&row)?;
+        }
+    }
+    tracing::debug!("query returned {} rows", rows.rows.len());
+    Ok(rows.rows)
+}
+
+/// Prepare `query`, or the query wrapping it to select columns without a
+/// conversion as text if it has any, returning the wrapper's text alongside.
+/// Only reads and writes that return rows are wrapped; the columns of other
+/// statements, such as `FETCH` or a `WITH` that modifies data, arrive in
+/// binary.
+async fn prepare(session: &Session, query: &str) -> Result<(Option<String>, Statement)> {
+    let statement = session.client().prepare(query).await.context("query failed")?;
+    let columns: Vec<_> = statement.columns().iter().map(|c| (c.name(), c.type_())).collect();
+    let Some(text) = text_query(query, &columns).filter(|_| wrappable(query)) else {
+        return Ok((None, statement));
+    };
+    let wrapped = session
+        .client()
+        .prepare(&text)
+        .await
+        .context("query failed selecting columns without a conversion as text")?;
+    Ok((Some(text), wrapped))
+}
+
+/// Whether `query` can run inside a `WITH`: a read, or a write at the top
+/// level. Words are matched without parsing, so a `WITH` mentioning a write
+/// anywhere, or a query with a `;` before its end, is left as it is.
+fn wrappable(query: &str) -> bool {
+    const WRITES: [&str; 3] = ["insert", "update", "delete"];
+    if trim(query).contains(';') {
+        return false;
+    }
+    let is = |word: &str, of: &[&str]| of.iter().any(|w| word.eq_ignore_ascii_case(w));
+    let mut words =
+        query.split(|c: char| !(c.is_alphanumeric() || c == '_')).filter(|w| !w.is_empty());
+    match words.next() {
+        Some(first) if is(first, &["with"]) => !words.any(|word| is(word, &WRITES)),
+        Some(first) => is(first, &["select", "values", "table"]) || is(first, &WRITES),
+        None => false,
+    }
+}
+
+/// `query` wrapped to select its `columns` (names and types) that have no
+/// conversion as `text`, or `None` when every column converts. The columns
+/// are renamed positionally inside the wrapper, so duplicate and unnamed
+/// columns keep their place and name.
+fn text_query(query: &str, columns: &[(&str, &Type)]) -> Option<String> {
+    if columns.iter().all(|(_, ty)| converts(ty)) {
+        return None;
+    }
+    let query = trim(query);
+    let positions = (1..=columns.len()).map(|i| format!("c{i}")).collect::<Vec<_>>().join(", ");
+    let selected = columns
+        .iter()
+        .enumerate()
+        .map(|(i, (name, ty))| {
+            let cast = if converts(ty) { "" } else { "::text" };
+            format!("c{}{cast} AS \"{}\"", i + 1, name.replace('"', "\"\""))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    // the query goes on its own lines so a trailing comment ends with it
+    Some(format!(
+        "WITH omnia_result ({positions}) AS (\n{query}\n) SELECT {selected} FROM omnia_result"
+    ))
+}
+
+/// Declare a cursor for `query` and fetch from it in batches. Outside a
+/// transaction, the cursor gets one of its own.
+async fn fetch_cursor(
+    session: &Session, query: &str, params: &[ParamRef<'_>], rows: &mut Collector,
+) -> Result<()> {
+    let cursor = format!("omnia_cursor_{}", CURSORS.fetch_add(1, Ordering::Relaxed));
+    let own_transaction = !session.in_transaction().await?;
+    if own_transaction {
+        session.run("BEGIN").await?;
+    }
+
+    let result = async {
+        let declare = format!("DECLARE {cursor} NO SCROLL CURSOR FOR {query}");
+        session.client().execute(&declare, params).await.context("query failed")?;
+
+        let fetch = format!("FETCH FORWARD {} FROM {cursor}", rows.limits.fetch_size);
+        loop {
+            let batch = session.client().query(&fetch, &[]).await.context("fetching rows")?;
+            for row in &batch {
+                rows.push(row)?;
+            }
+            if batch.len() < rows.limits.fetch_size {
+                break;
+            }
+        }
+        session.client().batch_execute(&format!("CLOSE {cursor}")).await.context("closing cursor")
+    }
+    .await;
+
+    if own_transaction {
+        if result.is_ok() {
+            session.run("COMMIT").await?;
+        } else if let Err(e) = session.run("ROLLBACK").await {
+            // the query's error says what went wrong; keep it
+            tracing::warn!("{e:#}");
+        }
+    } else if result.is_err()
+        && let Err(e) = session.client().batch_execute(&format!("CLOSE {cursor}")).await
+    {
+        // the guest's transaction has usually been aborted by the error
+        tracing::debug!("failed to close cursor {cursor}: {e}");
+    }
+    result
+}
+
+/// The statement to declare a cursor for, if `query` is a plain read.
+pub fn cursor_query(query: &str) -> Option<&str> {
+    let query = trim(query);
+    let keyword = query.split(|c: char| c.is_whitespace() || c == '(').next()?;
+    ["select", "values", "table"]
+        .iter()
+        .any(|read| keyword.eq_ignore_ascii_case(read))
+        .then_some(query)
+        .filter(|query| !locks_or_creates(query))
+}
+
+/// `query` without surrounding whitespace or trailing semicolons.
+fn trim(query: &str) -> &str {
+    query.trim().trim_end_matches(';').trim_end()
+}
+
+/// Whether a read locks rows (`FOR UPDATE`, `FOR SHARE`, ...) or creates a
+/// table (`SELECT INTO`). Words are matched without parsing, so one inside a
+/// literal or identifier only sends the read to the primary.
+fn locks_or_creates(query: &str) -> bool {
+    let mut previous = "";
+    query.split(|c: char| !(c.is_alphanumeric() || c == '_')).filter(|w| !w.is_empty()).any(
+        |word| {
+            let locks = previous.eq_ignore_ascii_case("for")
+                && ["update", "share", "no", "key"].iter().any(|w| word.eq_ignore_ascii_case(w));
+            previous = word;
+            locks || word.eq_ignore_ascii_case("into")
+        },
+    )
+}
+
+/// Rows converted so far, and their approximate size.
+struct Collector {
+    rows: Vec<Row>,
+    bytes: usize,
+    limits: Limits,
+}
+
+impl Collector {
+    const fn new(limits: Limits) -> Self {
+        Self {
+            rows: Vec::new(),
+            bytes: 0,
+            limits,
+        }
+    }
+
+    fn push(&mut self, pg_row: &PgRow) -> Result<()> {
+        let Limits {
+            max_rows, max_bytes, ..
+        } = self.limits;
+        if max_rows > 0 && self.rows.len() >= max_rows {
+            bail!(
+                "query returned more than {max_rows} rows; add a LIMIT or raise POSTGRES_MAX_ROWS"
+            );
+        }
+        let row = into_wasi_row(pg_row, self.rows.len())?;
+        self.bytes += row_size(&row);
+        if max_bytes > 0 && self.bytes > max_bytes {
+            bail!(
+                "query returned more than {max_bytes} bytes; add a LIMIT or raise POSTGRES_MAX_BYTES"
+            );
+        }
+        self.rows.push(row);
+        Ok(())
+    }
+}
+
+/// The approximate size of a row: its field names and values.
+fn row_size(row: &Row) -> usize {
+    row.fields
+        .iter()
+        .map(|field| {
+            let value = match &field.value {
+                DataType::Int32(_) | DataType::Uint32(_) | DataType::Float(_) => 4,
+                DataType::Int64(_) | DataType::Uint64(_) | DataType::Double(_) => 8,
+                DataType::Boolean(_) => 1,
+                DataType::Str(v)
+                | DataType::Date(v)
+                | DataType::Time(v)
+                | DataType::Timestamp(v) => v.as_ref().map_or(0, String::len),
+                DataType::Binary(v) => v.as_ref().map_or(0, Vec::len),
+            };
+            field.name.len() + value
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use omnia_wasi_sql::Field;
+
+    use super::*;
+
+    #[test]
+    fn cursor_queries() {
+        assert_eq!(cursor_query("SELECT * FROM t;"), Some("SELECT * FROM t"));
+        assert_eq!(cursor_query("  values (1), (2)"), Some("values (1), (2)"));
+        assert_eq!(cursor_query("(SELECT 1) UNION (SELECT 2)"), None);
+        assert_eq!(cursor_query("TABLE t"), Some("TABLE t"));
+        assert_eq!(cursor_query("select(1)"), Some("select(1)"));
+        assert_eq!(cursor_query("INSERT INTO t VALUES (1) RETURNING id"), None);
+        assert_eq!(cursor_query("WITH d AS (DELETE FROM t RETURNING *) SELECT * FROM d"), None);
+        assert_eq!(cursor_query("selection"), None);
+        assert_eq!(cursor_query("SELECT * FROM t WHERE id = 1 FOR UPDATE"), None);
+        assert_eq!(cursor_query("select * from t for no key update skip locked"), None);
+        assert_eq!(cursor_query("SELECT * FROM t FOR KEY SHARE"), None);
+        assert_eq!(cursor_query("SELECT * INTO copy FROM t"), None);
+        assert_eq!(
+            cursor_query("SELECT substring(name FROM 1 FOR 2) FROM t"),
+            Some("SELECT substring(name FROM 1 FOR 2) FROM t")
+        );
+    }
+
+    #[test]
+    fn wrappable_queries() {
+        assert!(wrappable("select(1)"));
+        assert!(wrappable("INSERT INTO t VALUES (1) RETURNING mac"));
+        assert!(wrappable("WITH h AS (SELECT mac FROM t) SELECT * FROM h"));
+        assert!(!wrappable("WITH d AS (DELETE FROM t RETURNING mac) SELECT * FROM d"));
+        assert!(!wrappable("FETCH ALL FROM c"));
+        assert!(wrappable("SELECT mac FROM t;"));
+        assert!(!wrappable("SELECT mac FROM t; -- hosts"));
+        assert!(wrappable("(SELECT mac FROM t) UNION (SELECT mac FROM u)"));
+    }
+
+    #[test]
+    fn text_queries() {
+        assert_eq!(text_query("SELECT 1", &[("?column?", &Type::INT4)]), None);
+        assert_eq!(
+            text_query(
+                "SELECT id, mac, mac FROM t -- hosts",
+                &[("id", &Type::INT8), ("mac", &Type::MACADDR), ("m\"ac", &Type::MACADDR)]
+            )
+            .as_deref(),
+            Some(
+                "WITH omnia_result (c1, c2, c3) AS (\nSELECT id, mac, mac FROM t -- hosts\n) \
+                 SELECT c1 AS \"id\", c2::text AS \"mac\", c3::text AS \"m\"\"ac\" FROM omnia_result"
+            )
+        );
+    }
+
+    #[test]
+    fn row_sizes() {
+        let row = Row {
+            index: "0".into(),
+            fields: vec![
+                Field {
+                    name: "id".into(),
+                    value: DataType::Int64(Some(1)),
+                },
+                Field {
+                    name: "body".into(),
+                    value: DataType::Str(Some("hello".into())),
+                },
+                Field {
+                    name: "blob".into(),
+                    value: DataType::Binary(None),
+                },
+            ],
+        };
+        assert_eq!(row_size(&row), 2 + 8 + 4 + 5 + 4);
+    }
+}
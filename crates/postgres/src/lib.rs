@@ -1,9 +1,15 @@
 #![doc = include_str!("../README.md")]
 
+mod fetch;
+mod replica;
 mod sql;
+mod transaction;
 mod types;
 
 use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{Context as _, Result, anyhow};
 use deadpool_postgres::{Pool, PoolConfig, Runtime};
@@ -15,9 +21,23 @@ use tokio_postgres_rustls::MakeRustlsConnect;
 use tracing::instrument;
 use webpki_roots::TLS_SERVER_ROOTS;
 
+use crate::fetch::Limits;
+use crate::replica::Replicas;
+pub use crate::replica::{Balance, PRIMARY_HINT};
+pub use crate::transaction::{IsolationLevel, Transaction};
+
 /// Postgres client
 #[derive(Clone, Debug)]
-pub struct Client(HashMap<String, Pool>);
+pub struct Client(HashMap<String, NamedPool>);
+
+/// A connection pool, the limits on its query results, and its read
+/// replicas.
+#[derive(Clone, Debug)]
+struct NamedPool {
+    pool: Pool,
+    limits: Limits,
+    replicas: Option<Arc<Replicas>>,
+}
 
 /// Postgres resource builder
 impl Backend for Client {
@@ -27,52 +47,11 @@ impl Backend for Client {
     #[instrument]
     async fn connect_with(options: Self::ConnectOptions) -> Result<Self> {
         let mut pools = HashMap::new();
-        let runtime = Some(Runtime::Tokio1);
         let mut tls_factory: Option<MakeRustlsConnect> = None; // factory is cheaper to clone
 
         for entry in std::iter::once(&options.default_pool).chain(&options.additional_pools) {
-            // deadpool parses `url` itself (via tokio_postgres); parse here only
-            // to decide whether the connection needs TLS.
-            let tokio: tokio_postgres::Config =
-                entry.uri.parse().context("parsing Postgres URI")?;
-            let use_tls = matches!(tokio.get_ssl_mode(), SslMode::Require | SslMode::Prefer);
-
-            let mut pool_config = deadpool_postgres::Config::new();
-            pool_config.url = Some(entry.uri.clone());
-            pool_config.pool = Some(PoolConfig {
-                max_size: entry.pool_size,
-                ..PoolConfig::default()
-            });
-
-            let pool = if use_tls {
-                let factory = if let Some(f) = &tls_factory {
-                    f.clone()
-                } else {
-                    ring::default_provider()
-                        .install_default()
-                        .map_err(|_e| anyhow!("Failed to install rustls crypto provider"))?;
-
-                    let mut cert_store = RootCertStore::empty();
-                    cert_store.extend(TLS_SERVER_ROOTS.iter().cloned());
-
-                    let client_config = ClientConfig::builder()
-                        .with_root_certificates(cert_store)
-                        .with_no_client_auth();
-
-                    let factory = MakeRustlsConnect::new(client_config);
-                    tls_factory = Some(factory.clone());
-
-                    factory
-                };
-
-                pool_config
-                    .create_pool(runtime, factory)
-                    .context(format!("failed to create postgres pool: '{}'", entry.name))?
-            } else {
-                pool_config
-                    .create_pool(runtime, tokio_postgres::NoTls)
-                    .context(format!("failed to create postgres pool: '{}'", entry.name))?
-            };
+            let (pool, tokio, use_tls) = create_pool(&entry.uri, entry.pool_size, &mut tls_factory)
+                .with_context(|| format!("failed to create postgres pool: '{}'", entry.name))?;
 
             // Check pool is usable
             let cnn = pool.get().await;
@@ -86,13 +65,105 @@ impl Backend for Client {
                 entry.name,
                 use_tls
             );
-            pools.insert(entry.name.clone(), pool);
+            let limits = Limits {
+                fetch_size: entry.fetch_size,
+                max_rows: entry.max_rows,
+                max_bytes: entry.max_bytes,
+            };
+            let replicas = connect_replicas(entry, &mut tls_factory).await?;
+            pools.insert(
+                entry.name.clone(),
+                NamedPool {
+                    pool,
+                    limits,
+                    replicas,
+                },
+            );
         }
 
         Ok(Self(pools))
     }
 }
 
+/// Create a pool of up to `size` connections to `uri`, using TLS when the URI
+/// asks for it. Also returns the parsed URI and whether TLS is used.
+fn create_pool(
+    uri: &str, size: usize, tls_factory: &mut Option<MakeRustlsConnect>,
+) -> Result<(Pool, tokio_postgres::Config, bool)> {
+    // deadpool parses `url` itself (via tokio_postgres); parse here only
+    // to decide whether the connection needs TLS.
+    let tokio: tokio_postgres::Config = uri.parse().context("parsing Postgres URI")?;
+    let use_tls = matches!(tokio.get_ssl_mode(), SslMode::Require | SslMode::Prefer);
+
+    let mut pool_config = deadpool_postgres::Config::new();
+    pool_config.url = Some(uri.to_owned());
+    pool_config.pool = Some(PoolConfig {
+        max_size: size,
+        ..PoolConfig::default()
+    });
+    let runtime = Some(Runtime::Tokio1);
+
+    let pool = if use_tls {
+        let factory = if let Some(f) = tls_factory {
+            f.clone()
+        } else {
+            ring::default_provider()
+                .install_default()
+                .map_err(|_e| anyhow!("Failed to install rustls crypto provider"))?;
+
+            let mut cert_store = RootCertStore::empty();
+            cert_store.extend(TLS_SERVER_ROOTS.iter().cloned());
+
+            let client_config =
+                ClientConfig::builder().with_root_certificates(cert_store).with_no_client_auth();
+
+            let factory = MakeRustlsConnect::new(client_config);
+            *tls_factory = Some(factory.clone());
+
+            factory
+        };
+
+        pool_config.create_pool(runtime, factory)?
+    } else {
+        pool_config.create_pool(runtime, tokio_postgres::NoTls)?
+    };
+    Ok((pool, tokio, use_tls))
+}
+
+/// Create pools for `entry`'s read replicas, if it has any, check them, and
+/// keep checking them in the background.
+async fn connect_replicas(
+    entry: &PoolEntry, tls_factory: &mut Option<MakeRustlsConnect>,
+) -> Result<Option<Arc<Replicas>>> {
+    if entry.replica_uris.is_empty() {
+        return Ok(None);
+    }
+
+    let mut pools = Vec::with_capacity(entry.replica_uris.len());
+    for (i, uri) in entry.replica_uris.iter().enumerate() {
+        let label = format!("replica {i} of pool '{}'", entry.name);
+        let (pool, ..) = create_pool(uri, entry.pool_size, tls_factory)
+            .with_context(|| format!("failed to create postgres pool for {label}"))?;
+        pools.push((label, pool));
+    }
+
+    // an unreachable replica is ejected rather than failing the connection
+    let interval = entry.replica_check_interval.max(Duration::from_secs(1));
+    let replicas = Arc::new(Replicas::new(pools, entry.replica_balance, entry.replica_max_lag));
+    replicas.check(interval).await;
+    replicas.monitor(interval);
+    tracing::info!("pool '{}' has {} read replicas", entry.name, entry.replica_uris.len());
+    Ok(Some(replicas))
+}
+
+impl Client {
+    fn pool(&self, name: &str) -> Result<&NamedPool> {
+        self.0
+            .get(&name.to_ascii_uppercase())
+            .ok_or_else(|| anyhow!("unknown postgres pool '{name}'"))
+    }
+}
+
 /// A named connection pool entry.
 #[derive(Debug, Clone)]
 pub struct PoolEntry {
@@ -102,6 +173,22 @@ pub struct PoolEntry {
     pub uri: String,
     /// Maximum number of connections in the pool.
     pub pool_size: usize,
+    /// Rows a plain read fetches at a time through a server-side cursor; zero
+    /// streams every query's rows without a cursor.
+    pub fetch_size: usize,
+    /// Maximum rows returned by one query; zero for no limit.
+    pub max_rows: usize,
+    /// Maximum approximate size, in bytes, of one query's rows; zero for no
+    /// limit.
+    pub max_bytes: usize,
+    /// Read replica URIs; reads go to the primary when empty.
+    pub replica_uris: Vec<String>,
+    /// How reads are spread across healthy replicas.
+    pub replica_balance: Balance,
+    /// Replication lag beyond which a replica is ejected.
+    pub replica_max_lag: Duration,
+    /// Time between replica health checks.
+    pub replica_check_interval: Duration,
 }
 
 #[allow(missing_docs)]
@@ -125,13 +212,20 @@ impl omnia::FromEnv for ConnectOptions {
     fn from_env() -> Result<Self> {
         // default pool (required)
         let default_uri = std::env::var("POSTGRES_URL").context("POSTGRES_URL must be set");
-        let default_size =
-            std::env::var("POSTGRES_POOL_SIZE").unwrap_or_default().parse().unwrap_or(10);
-
         let default = PoolEntry {
             name: "default".to_ascii_uppercase(),
             uri: default_uri?,
-            pool_size: default_size,
+            pool_size: env_or("POSTGRES_POOL_SIZE", 10),
+            fetch_size: env_or("POSTGRES_FETCH_SIZE", 0),
+            max_rows: env_or("POSTGRES_MAX_ROWS", 0),
+            max_bytes: env_or("POSTGRES_MAX_BYTES", 0),
+            replica_uris: env_uris("POSTGRES_REPLICA_URLS"),
+            replica_balance: env_balance("POSTGRES_REPLICA_BALANCE", Balance::default())?,
+            replica_max_lag: Duration::from_secs(env_or("POSTGRES_REPLICA_MAX_LAG", 10)),
+            replica_check_interval: Duration::from_secs(env_or(
+                "POSTGRES_REPLICA_CHECK_INTERVAL",
+                5,
+            )),
         };
 
         // optional extra pools: POSTGRES_POOLS=eventstore
@@ -143,16 +237,40 @@ impl omnia::FromEnv for ConnectOptions {
             .map(|name| -> anyhow::Result<PoolEntry> {
                 let name = name.to_ascii_uppercase();
                 let uri_key = format!("POSTGRES_URL__{name}");
-                let size_key = format!("POSTGRES_POOL_SIZE__{name}");
 
                 let uri = std::env::var(&uri_key)
                     .with_context(|| format!("missing {uri_key} for pool {name}"))?;
-                let pool_size = std::env::var(&size_key)
-                    .ok()
-                    .and_then(|v| v.parse().ok())
-                    .unwrap_or(default.pool_size);
+                let pool_size = env_or(&format!("POSTGRES_POOL_SIZE__{name}"), default.pool_size);
+                let fetch_size =
+                    env_or(&format!("POSTGRES_FETCH_SIZE__{name}"), default.fetch_size);
+                let max_rows = env_or(&format!("POSTGRES_MAX_ROWS__{name}"), default.max_rows);
+                let max_bytes = env_or(&format!("POSTGRES_MAX_BYTES__{name}"), default.max_bytes);
+                let replica_uris = env_uris(&format!("POSTGRES_REPLICA_URLS__{name}"));
+                let replica_balance = env_balance(
+                    &format!("POSTGRES_REPLICA_BALANCE__{name}"),
+                    default.replica_balance,
+                )?;
+                let replica_max_lag = env_or(
+                    &format!("POSTGRES_REPLICA_MAX_LAG__{name}"),
+                    default.replica_max_lag.as_secs(),
+                );
+                let replica_check_interval = env_or(
+                    &format!("POSTGRES_REPLICA_CHECK_INTERVAL__{name}"),
+                    default.replica_check_interval.as_secs(),
+                );
 
-                Ok(PoolEntry { name, uri, pool_size })
+                Ok(PoolEntry {
+                    name,
+                    uri,
+                    pool_size,
+                    fetch_size,
+                    max_rows,
+                    max_bytes,
+                    replica_uris,
+                    replica_balance,
+                    replica_max_lag: Duration::from_secs(replica_max_lag),
+                    replica_check_interval: Duration::from_secs(replica_check_interval),
+                })
             })
             .collect::<Result<Vec<_>, _>>()?;
 
@@ -163,3 +281,21 @@ impl omnia::FromEnv for ConnectOptions {
         // Self::from_env().finalize().context("issue loading connection options")
     }
 }
+
+/// The value of the environment variable `key`, or `default` when it is unset
+/// or does not parse.
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    std::env::var(key).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// The space-separated URIs in the environment variable `key`. Spaces rather
+/// than commas, which separate the hosts of a multi-host URI.
+fn env_uris(key: &str) -> Vec<String> {
+    std::env::var(key).unwrap_or_default().split_whitespace().map(str::to_owned).collect()
+}
+
+/// The replica balance in the environment variable `key`, or `default` when
+/// it is unset.
+fn env_balance(key: &str, default: Balance) -> Result<Balance> {
+    std::env::var(key).map_or(Ok(default), |v| v.parse().with_context(|| format!("parsing {key}")))
+}
@@ -0,0 +1,254 @@
+//! Read replicas for the Postgres backend.
+//!
+//! A pool may declare read replicas. Plain reads on a connection outside a
+//! transaction go to a healthy replica, picked round-robin or by fewest
+//! connections in use; everything else goes to the primary. Once a
+//! connection has written, its reads go to the primary as well so it reads
+//! its own writes, and a single query can ask for the primary by starting with
+//! [`PRIMARY_HINT`].
+//!
+//! Reads that lock rows (`FOR UPDATE`, `FOR SHARE`) or create a table
+//! (`SELECT INTO`) are sent to the primary, but a read that calls a function
+//! is routed like any other. One that writes, such as `nextval`, is refused by
+//! the replica and retried on the primary, and counts as the connection's
+//! write. Reads calling volatile functions that must run on the primary
+//! should start with [`PRIMARY_HINT`].
+//!
+//! Replicas are checked in the background. One that cannot be reached, whose
+//! WAL receiver is not streaming from the primary, or that lags the primary
+//! by more than the pool's maximum, is ejected until a later check finds it
+//! healthy again.
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow, bail};
+use deadpool_postgres::Pool;
+
+/// A query starting with this comment is sent to the primary.
+pub const PRIMARY_HINT: &str = "/* omnia:primary */";
+
+/// Whether the replica is streaming from the primary, and seconds it has
+/// yet to replay. A replica that has replayed everything it received is up
+/// to date, however long ago that was, but only while its WAL receiver is
+/// connected: a disconnected one receives nothing and so looks idle. Roles
+/// without `pg_read_all_stats` cannot see the receiver's status, only that
+/// it is running.
+const LAG_QUERY: &str = "SELECT
+    NOT pg_is_in_recovery() OR EXISTS (
+        SELECT 1 FROM pg_stat_wal_receiver WHERE coalesce(status, 'streaming') = 'streaming'
+    ),
+    (CASE
+        WHEN NOT pg_is_in_recovery() OR pg_last_wal_receive_lsn() = pg_last_wal_replay_lsn() THEN 0
+        ELSE extract(epoch FROM now() - pg_last_xact_replay_timestamp())
+    END)::float8";
+
+/// How reads are spread across healthy replicas.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Balance {
+    /// Each replica in turn.
+    #[default]
+    RoundRobin,
+    /// The replica with the fewest connections in use.
+    LeastConnections,
+}
+
+impl FromStr for Balance {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-connections" => Ok(Self::LeastConnections),
+            _ => bail!("invalid replica balance {s:?}: expected round-robin or least-connections"),
+        }
+    }
+}
+
+/// The `query` to send to the primary, if it starts with [`PRIMARY_HINT`].
+pub fn primary_hint(query: &str) -> Option<&str> {
+    query.trim_start().strip_prefix(PRIMARY_HINT)
+}
+
+/// A pool's read replicas.
+#[derive(Debug)]
+pub struct Replicas {
+    members: Vec<Arc<Replica>>,
+    balance: Balance,
+    max_lag: Duration,
+    next: AtomicUsize,
+}
+
+impl Replicas {
+    /// Replicas are ejected until first [`check`](Self::check)ed.
+    pub fn new(pools: Vec<(String, Pool)>, balance: Balance, max_lag: Duration) -> Self {
+        let members = pools
+            .into_iter()
+            .map(|(label, pool)| {
+                Arc::new(Replica {
+                    label,
+                    pool,
+                    healthy: AtomicBool::new(false),
+                })
+            })
+            .collect();
+        Self {
+            members,
+            balance,
+            max_lag,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// A healthy replica to read from, if there is one.
+    pub fn pick(&self) -> Option<Arc<Replica>> {
+        let healthy: Vec<&Arc<Replica>> =
+            self.members.iter().filter(|r| r.healthy.load(Ordering::Acquire)).collect();
+        let replica = match self.balance {
+            Balance::RoundRobin => {
+                if healthy.is_empty() {
+                    return None;
+                }
+                healthy[self.next.fetch_add(1, Ordering::Relaxed) % healthy.len()]
+            }
+            Balance::LeastConnections => healthy.into_iter().min_by_key(|r| {
+                let status = r.pool.status();
+                status.size.saturating_sub(status.available) + status.waiting
+            })?,
+        };
+        Some(Arc::clone(replica))
+    }
+
+    /// Check every replica, ejecting those that are unreachable or lag by
+    /// more than the maximum and restoring the rest. A check taking longer
+    /// than `timeout` fails.
+    pub async fn check(&self, timeout: Duration) {
+        futures::future::join_all(
+            self.members.iter().map(|replica| replica.check(self.max_lag, timeout)),
+        )
+        .await;
+    }
+
+    /// Check the replicas every `interval` until they are dropped.
+    pub fn monitor(self: &Arc<Self>, interval: Duration) {
+        let replicas: Weak<Self> = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let Some(replicas) = replicas.upgrade() else {
+                    return;
+                };
+                replicas.check(interval).await;
+            }
+        });
+    }
+}
+
+/// A read replica's pool and whether it is in use.
+#[derive(Debug)]
+pub struct Replica {
+    label: String,
+    pool: Pool,
+    healthy: AtomicBool,
+}
+
+impl Replica {
+    pub const fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Stop reading from the replica until its next successful check.
+    pub fn eject(&self, reason: &str) {
+        if self.healthy.swap(false, Ordering::AcqRel) {
+            tracing::warn!("ejecting {}: {reason}", self.label);
+        }
+    }
+
+    async fn check(&self, max_lag: Duration, timeout: Duration) {
+        let lag = async {
+            let client = self.pool.get().await.context("issue getting connection")?;
+            let row = client.query_one(LAG_QUERY, &[]).await.context("checking lag")?;
+            if !row.try_get::<_, bool>(0)? {
+                bail!("replica is not streaming from the primary");
+            }
+            row.try_get::<_, Option<f64>>(1)?
+                .ok_or_else(|| anyhow!("replica has not replayed any transactions"))
+        };
+        let lag = match tokio::time::timeout(timeout, lag).await {
+            Ok(Ok(lag)) => lag,
+            Ok(Err(e)) => return self.eject(&format!("{e:#}")),
+            Err(_elapsed) => return self.eject("check timed out"),
+        };
+
+        if lag > max_lag.as_secs_f64() {
+            self.eject(&format!("{lag:.1}s behind the primary"));
+        } else if !self.healthy.swap(true, Ordering::AcqRel) {
+            tracing::info!("reading from {}", self.label);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use deadpool_postgres::{Config, Runtime};
+    use tokio_postgres::NoTls;
+
+    use super::*;
+
+    fn replicas(count: usize, balance: Balance) -> Replicas {
+        let pools = (0..count)
+            .map(|i| {
+                let config = Config {
+                    url: Some(format!("postgresql://replica-{i}/db")),
+                    ..Config::default()
+                };
+                (format!("replica {i}"), config.create_pool(Some(Runtime::Tokio1), NoTls).unwrap())
+            })
+            .collect();
+        Replicas::new(pools, balance, Duration::from_secs(10))
+    }
+
+    fn restore(replicas: &Replicas) {
+        for replica in &replicas.members {
+            replica.healthy.store(true, Ordering::Release);
+        }
+    }
+
+    #[test]
+    fn round_robin_skips_ejected() {
+        let replicas = replicas(3, Balance::RoundRobin);
+        assert!(replicas.pick().is_none(), "unchecked replicas are not used");
+
+        restore(&replicas);
+        let picked: Vec<String> =
+            (0..3).map(|_| replicas.pick().unwrap().label().to_owned()).collect();
+        assert_eq!(picked, ["replica 0", "replica 1", "replica 2"]);
+
+        replicas.members[1].eject("test");
+        let picked: Vec<String> =
+            (0..4).map(|_| replicas.pick().unwrap().label().to_owned()).collect();
+        assert!(picked.iter().all(|label| label != "replica 1"), "{picked:?}");
+    }
+
+    #[test]
+    fn least_connections() {
+        let replicas = replicas(2, Balance::LeastConnections);
+        restore(&replicas);
+        replicas.members[0].eject("test");
+        assert_eq!(replicas.pick().unwrap().label(), "replica 1");
+    }
+
+    #[test]
+    fn hints_and_balances() {
+        assert_eq!(primary_hint(" /* omnia:primary */ SELECT 1"), Some(" SELECT 1"));
+        assert_eq!(primary_hint("SELECT 1 /* omnia:primary */"), None);
+        assert_eq!("least-connections".parse::<Balance>().unwrap(), Balance::LeastConnections);
+        "random".parse::<Balance>().unwrap_err();
+    }
+}
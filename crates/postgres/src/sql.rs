@@ -1,45 +1,82 @@
 use std::convert::TryFrom;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::{Context, anyhow, bail};
+use anyhow::{Context, anyhow};
 use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
-use deadpool_postgres::Object;
 use futures::future::FutureExt;
 use omnia_wasi_sql::{Connection, DataType, Field, FutureResult, Row, WasiSqlCtx};
+use tokio_postgres::error::SqlState;
 use tokio_postgres::row::Row as PgRow;
 
 use crate::Client;
-use crate::types::{Param, ParamRef, PgType};
+use crate::fetch::{Limits, cursor_query, fetch};
+use crate::replica::{Replicas, primary_hint};
+use crate::transaction::Session;
+use crate::types::{Column, Param, ParamRef, PgType};
 
 /// `wasi-sql` implementation backed by `deadpool-postgres` connection pools.
 impl WasiSqlCtx for Client {
     fn open(&self, name: String) -> FutureResult<Arc<dyn Connection>> {
         tracing::debug!("getting connection {name}");
 
-        let pool = match self.0.get(&name.to_ascii_uppercase()) {
-            Some(p) => p.clone(),
-            None => {
-                return futures::future::ready(Err(anyhow!("unknown postgres pool '{name}'")))
-                    .boxed();
-            }
+        let (pool, limits, replicas) = match self.pool(&name) {
+            Ok(p) => (p.pool.clone(), p.limits, p.replicas.clone()),
+            Err(e) => return futures::future::ready(Err(e)).boxed(),
         };
         async move {
             let cnn = pool.get().await.context("issue getting connection")?;
-            Ok(Arc::new(PostgresConnection(Arc::new(cnn))) as Arc<dyn Connection>)
+            let connection = PostgresConnection::new(Session::new(cnn), limits, replicas);
+            Ok(Arc::new(connection) as Arc<dyn Connection>)
         }
         .boxed()
     }
 }
 
 /// A pooled `PostgreSQL` connection implementing the `wasi-sql` `Connection` trait.
+///
+/// The pooled client is held until the connection is dropped, so a
+/// transaction begun on it spans later statements (see [`crate::transaction`]).
+/// Query results are bounded by the pool's [`Limits`], and reads may go to
+/// one of its [`Replicas`] instead (see [`crate::replica`]).
 #[derive(Debug)]
-pub struct PostgresConnection(Arc<Object>);
+pub struct PostgresConnection {
+    session: Arc<Session>,
+    limits: Limits,
+    replicas: Option<Arc<Replicas>>,
+    /// Set once the connection writes, after which it reads from the primary.
+    wrote: Arc<AtomicBool>,
+}
+
+impl PostgresConnection {
+    pub fn new(session: Session, limits: Limits, replicas: Option<Arc<Replicas>>) -> Self {
+        Self {
+            session: Arc::new(session),
+            limits,
+            replicas,
+            wrote: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+}
 
 impl Connection for PostgresConnection {
     fn query(&self, query: String, params: Vec<DataType>) -> FutureResult<Vec<Row>> {
         tracing::debug!("query: {query}, params: {params:?}");
-        let cnn = Arc::clone(&self.0);
+        let hinted = primary_hint(&query).map(str::to_owned);
+        let (query, hinted) = hinted.map_or((query, false), |rest| (rest, true));
+        let read = cursor_query(&query).is_some();
+        let replicas = self
+            .replicas
+            .clone()
+            .filter(|_| read && !hinted && !self.wrote.load(Ordering::Acquire));
+        let session = Arc::clone(&self.session);
+        let limits = self.limits;
+        let wrote = Arc::clone(&self.wrote);
 
         async move {
             let mut pg_params: Vec<Param> = Vec::new();
@@ -49,35 +86,41 @@ impl Connection for PostgresConnection {
             let param_refs: Vec<ParamRef> =
                 pg_params.iter().map(|b| b.as_ref() as ParamRef).collect();
 
-            let pg_rows = cnn
-                .query(&query, &param_refs)
-                .await
-                .inspect_err(|e| {
-                    dbg!(e);
-                })
-                .context("query failed")?;
-            tracing::debug!("query returned {} rows", pg_rows.len());
-
-            let mut wasi_rows = Vec::new();
-            for (idx, r) in pg_rows.iter().enumerate() {
-                let row = match into_wasi_row(r, idx) {
-                    Ok(row) => row,
-                    Err(e) => {
-                        tracing::error!("failed to convert row: {e:?}");
-                        return Err(anyhow!("failed to convert row: {e:?}"));
+            let replica = match replicas {
+                Some(replicas) if !session.in_transaction().await? => replicas.pick(),
+                _ => None,
+            };
+            let mut wrote_on_replica = false;
+            if let Some(replica) = replica {
+                match replica.pool().get().await {
+                    Ok(client) => {
+                        tracing::debug!("reading from {}", replica.label());
+                        match fetch(&Session::new(client), &query, &param_refs, limits).await {
+                            // a read calling a function that writes, such as nextval
+                            Err(e) if read_only(&e) => {
+                                tracing::debug!("retrying on the primary: {e:#}");
+                                wrote_on_replica = true;
+                            }
+                            result => return result,
+                        }
                     }
-                };
-                wasi_rows.push(row);
+                    Err(e) => replica.eject(&format!("issue getting connection: {e}")),
+                }
             }
 
-            Ok(wasi_rows)
+            let rows = fetch(&session, &query, &param_refs, limits).await?;
+            if !read || wrote_on_replica {
+                wrote.store(true, Ordering::Release);
+            }
+            Ok(rows)
         }
         .boxed()
     }
 
     fn exec(&self, query: String, params: Vec<DataType>) -> FutureResult<u32> {
         tracing::debug!("exec: {query}, params: {params:?}");
-        let cnn = Arc::clone(&self.0);
+        let session = Arc::clone(&self.session);
+        let wrote = Arc::clone(&self.wrote);
 
         async move {
             let mut pg_params: Vec<Param> = Vec::new();
@@ -87,19 +130,29 @@ impl Connection for PostgresConnection {
             let param_refs: Vec<ParamRef> =
                 pg_params.iter().map(|b| b.as_ref() as ParamRef).collect();
 
-            let affected = match cnn.execute(&query, &param_refs).await {
-                Ok(count) => count,
-                Err(e) => {
-                    tracing::error!("exec failed: {e}");
-                    return Err(anyhow!("exec failed: {e}"));
-                }
-            };
+            let affected =
+                match session.track(&query, session.client().execute(&query, &param_refs)).await {
+                    Ok(count) => count,
+                    Err(e) => {
+                        tracing::error!("exec failed: {e}");
+                        return Err(anyhow!("exec failed: {e}"));
+                    }
+                };
+            wrote.store(true, Ordering::Release);
             Ok(u32::try_from(affected).unwrap_or(u32::MAX))
         }
         .boxed()
     }
 }
 
+/// Whether `error` is a write refused by a read-only server.
+fn read_only(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .filter_map(|e| e.downcast_ref::<tokio_postgres::Error>())
+        .any(|e| e.code() == Some(&SqlState::READ_ONLY_SQL_TRANSACTION))
+}
+
 fn parse_date(source: Option<&str>) -> anyhow::Result<Option<NaiveDate>> {
     source.map(|s| NaiveDate::from_str(s).context("invalid date format")).transpose()
 }
@@ -160,80 +213,20 @@ fn into_param(value: &DataType) -> anyhow::Result<Param> {
     Ok(Box::new(pg_value) as Param)
 }
 
-/// Converts a ``PostgreSQL`` row to WASI SQL format.
+/// Converts a ``PostgreSQL`` row to WASI SQL format. Columns of any type
+/// convert (see [`Column`]).
 ///
 /// # Testing
 /// This function will have to tested via integration tests with a real database
 /// due to the difficulty of mocking `tokio_postgres::Row`.
-fn into_wasi_row(pg_row: &PgRow, idx: usize) -> anyhow::Result<Row> {
+pub fn into_wasi_row(pg_row: &PgRow, idx: usize) -> anyhow::Result<Row> {
     let mut fields = Vec::new();
     for (i, col) in pg_row.columns().iter().enumerate() {
         let name = col.name().to_string();
         tracing::debug!("attempting to convert column '{name}' with type '{:?}'", col.type_());
-        tracing::debug!("column type name: {}", col.type_().name());
-        let value = match col.type_().name() {
-            "int4" => {
-                let v: Option<i32> = pg_row.try_get(i)?;
-                DataType::Int32(v)
-            }
-            "int8" => {
-                let v: Option<i64> = pg_row.try_get(i)?;
-                DataType::Int64(v)
-            }
-            "oid" => {
-                let v: Option<u32> = pg_row.try_get(i)?;
-                DataType::Uint32(v)
-            }
-            "float4" => {
-                let v: Option<f32> = pg_row.try_get(i)?;
-                DataType::Float(v)
-            }
-            "float8" => {
-                let v: Option<f64> = pg_row.try_get(i)?;
-                DataType::Double(v)
-            }
-            "text" | "varchar" | "name" | "_char" => {
-                let v: Option<String> = pg_row.try_get(i)?;
-                DataType::Str(v)
-            }
-            "bool" => {
-                let v: Option<bool> = pg_row.try_get(i)?;
-                DataType::Boolean(v)
-            }
-            "date" => {
-                let v: Option<NaiveDate> = pg_row.try_get(i)?;
-                let formatted = v.map(|date| date.to_string());
-                DataType::Date(formatted)
-            }
-            "time" => {
-                let v: Option<NaiveTime> = pg_row.try_get(i)?;
-                let formatted = v.map(|time| time.to_string());
-                DataType::Time(formatted)
-            }
-            "timestamp" => {
-                let v: Option<NaiveDateTime> = pg_row.try_get(i)?;
-                let formatted = v.map(|dt| dt.to_string());
-                DataType::Timestamp(formatted)
-            }
-            "timestamptz" => {
-                let v: Option<DateTime<Utc>> = pg_row.try_get(i)?;
-                let formatted = v.map(|dtz| dtz.to_rfc3339());
-                DataType::Timestamp(formatted)
-            }
-            "json" | "jsonb" => {
-                let v: Option<tokio_postgres::types::Json<serde_json::Value>> =
-                    pg_row.try_get(i)?;
-                let as_str = v.map(|json| json.0.to_string());
-                DataType::Str(as_str)
-            }
-            "bytea" => {
-                let v: Option<Vec<u8>> = pg_row.try_get(i)?;
-                DataType::Binary(v)
-            }
-            other => {
-                bail!("unsupported column type: {other}");
-            }
-        };
+        let Column(value) = pg_row
+            .try_get(i)
+            .with_context(|| format!("converting column '{name}' of type '{}'", col.type_()))?;
         tracing::debug!("converted column '{name}' to value '{:?}'", value);
         fields.push(Field { name, value });
     }
@@ -0,0 +1,261 @@
+//! Transactions for the Postgres backend.
+//!
+//! A `wasi-sql` connection holds one pooled client for its whole lifetime, so
+//! a guest groups statements atomically by running `BEGIN` (optionally with an
+//! isolation level) and `COMMIT` or `ROLLBACK` on the same connection. One
+//! dropped while a transaction is still open is rolled back before its client
+//! returns to the pool.
+//!
+//! `tokio-postgres` does not expose the transaction status the server reports
+//! after each statement, so the connection asks the server instead. Any
+//! statement other than a plain read may open or end a transaction, so after
+//! one the state is unknown until it is next needed, when a probe sets a
+//! transaction-local setting in one statement and reads it back in the next:
+//! the setting survives only inside a transaction block.
+//!
+//! Host code can open a transaction directly with [`Client::transaction`].
+
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use anyhow::{Context, Result, bail};
+use deadpool_postgres::Object;
+use omnia_wasi_sql::{Connection, DataType, FutureResult, Row};
+use tokio_postgres::SimpleQueryMessage;
+use tokio_postgres::error::SqlState;
+
+use crate::Client;
+use crate::fetch::cursor_query;
+use crate::sql::PostgresConnection;
+
+/// Transaction isolation level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// Each statement sees data committed before it began.
+    #[default]
+    ReadCommitted,
+    /// Every statement sees data committed before the transaction began.
+    RepeatableRead,
+    /// As if transactions ran one at a time; conflicting transactions fail
+    /// with a serialization error and should be retried.
+    Serializable,
+}
+
+impl IsolationLevel {
+    const fn as_sql(self) -> &'static str {
+        match self {
+            Self::ReadCommitted => "READ COMMITTED",
+            Self::RepeatableRead => "REPEATABLE READ",
+            Self::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+impl FromStr for IsolationLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read-committed" => Ok(Self::ReadCommitted),
+            "repeatable-read" => Ok(Self::RepeatableRead),
+            "serializable" => Ok(Self::Serializable),
+            _ => bail!(
+                "invalid isolation level {s:?}: expected read-committed, repeatable-read, \
+                 or serializable"
+            ),
+        }
+    }
+}
+
+impl Client {
+    /// Begin a transaction on a client from pool `name`. The client stays
+    /// pinned to the returned [`Transaction`] until it is committed, rolled
+    /// back, or dropped; dropping it rolls back.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pool is unknown, no client is available, or the
+    /// transaction cannot be started.
+    pub async fn transaction(&self, name: &str, isolation: IsolationLevel) -> Result<Transaction> {
+        let pool = self.pool(name)?;
+        let client = pool.pool.get().await.context("issue getting connection")?;
+        let session = Session::new(client);
+        let begin = format!("BEGIN ISOLATION LEVEL {}", isolation.as_sql());
+        session.track(&begin, session.client().batch_execute(&begin)).await?;
+        Ok(Transaction(PostgresConnection::new(session, pool.limits, None)))
+    }
+}
+
+/// A transaction pinned to one pooled client.
+///
+/// Implements [`Connection`], so it can be used wherever a connection is
+/// expected. Dropping it without calling [`commit`](Self::commit) rolls the
+/// transaction back.
+#[derive(Debug)]
+pub struct Transaction(PostgresConnection);
+
+impl Transaction {
+    /// Commit the transaction.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the commit fails, in which case the transaction has
+    /// been rolled back.
+    pub async fn commit(self) -> Result<()> {
+        self.0.session().run("COMMIT").await
+    }
+
+    /// Roll the transaction back.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the rollback statement fails.
+    pub async fn rollback(self) -> Result<()> {
+        self.0.session().run("ROLLBACK").await
+    }
+}
+
+impl Connection for Transaction {
+    fn query(&self, query: String, params: Vec<DataType>) -> FutureResult<Vec<Row>> {
+        self.0.query(query, params)
+    }
+
+    fn exec(&self, query: String, params: Vec<DataType>) -> FutureResult<u32> {
+        self.0.exec(query, params)
+    }
+}
+
+/// No transaction is open.
+const IDLE: u8 = 0;
+/// A transaction is open, possibly aborted by an error.
+const OPEN: u8 = 1;
+/// A statement that may have changed the state has run since it was checked.
+const UNKNOWN: u8 = 2;
+
+/// Marks the probe's setting in a transaction block.
+const PROBE_SET: &str = "SELECT set_config('omnia.transaction_probe', 'open', true)";
+/// Reads the probe's setting back and clears it.
+const PROBE_GET: &str = "SELECT current_setting('omnia.transaction_probe', true) = 'open', \
+                         set_config('omnia.transaction_probe', '', true)";
+
+/// A pooled client held for the lifetime of a connection, and whether a
+/// transaction is open on it.
+#[derive(Debug)]
+pub struct Session {
+    /// Only taken when the session is dropped.
+    client: Option<Object>,
+    state: AtomicU8,
+}
+
+impl Session {
+    pub const fn new(client: Object) -> Self {
+        Self {
+            client: Some(client),
+            state: AtomicU8::new(IDLE),
+        }
+    }
+
+    pub const fn client(&self) -> &Object {
+        self.client.as_ref().expect("client is only taken on drop")
+    }
+
+    /// Await `run`, the execution of `statement`. Anything but a plain read
+    /// may open or end a transaction, even when it fails, so afterwards the
+    /// state is checked again when next needed.
+    pub async fn track<T, E>(
+        &self, statement: &str, run: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let result = run.await;
+        if cursor_query(statement).is_none() {
+            self.state.store(UNKNOWN, Ordering::Release);
+        }
+        result
+    }
+
+    /// Whether a transaction is open, asking the server if a statement run
+    /// since it was last checked may have changed that.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the server cannot be asked.
+    pub async fn in_transaction(&self) -> Result<bool> {
+        match self.state.load(Ordering::Acquire) {
+            IDLE => Ok(false),
+            OPEN => Ok(true),
+            _ => {
+                let open = probe(self.client()).await?;
+                self.state.store(if open { OPEN } else { IDLE }, Ordering::Release);
+                Ok(open)
+            }
+        }
+    }
+
+    /// Execute the transaction control `statement`, tracking its effect.
+    pub async fn run(&self, statement: &str) -> Result<()> {
+        self.track(statement, self.client().batch_execute(statement))
+            .await
+            .with_context(|| format!("{statement} failed"))
+    }
+}
+
+/// Ask the server whether a transaction is open on `client`.
+///
+/// A transaction-local setting outlives the statement setting it only inside
+/// a transaction block. In an aborted transaction both statements fail.
+async fn probe(client: &Object) -> Result<bool> {
+    let (set, get) = futures::join!(client.simple_query(PROBE_SET), client.simple_query(PROBE_GET));
+    match set.and(get) {
+        Ok(messages) => Ok(messages.iter().any(
+            |message| matches!(message, SimpleQueryMessage::Row(row) if row.get(0) == Some("t")),
+        )),
+        Err(e) if e.code() == Some(&SqlState::IN_FAILED_SQL_TRANSACTION) => Ok(true),
+        Err(e) => Err(e).context("checking transaction state"),
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let state = *self.state.get_mut();
+        if state == IDLE {
+            return;
+        }
+        let Some(client) = self.client.take() else {
+            return;
+        };
+
+        // The rollback cannot be awaited here, so it runs on a task that holds
+        // the client until it is done. A client that cannot be rolled back is
+        // removed from the pool rather than handed out mid-transaction.
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            drop(Object::take(client));
+            return;
+        };
+        runtime.spawn(async move {
+            if state == UNKNOWN {
+                match probe(&client).await {
+                    Ok(false) => return,
+                    Ok(true) => {}
+                    Err(e) => tracing::debug!("{e:#}; rolling back in case"),
+                }
+            }
+            tracing::warn!("rolling back transaction left open on a dropped connection");
+            if let Err(e) = client.batch_execute("ROLLBACK").await {
+                tracing::error!("failed to roll back abandoned transaction: {e}");
+                drop(Object::take(client));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn isolation_levels() {
+        assert_eq!("serializable".parse::<IsolationLevel>().unwrap(), IsolationLevel::Serializable);
+        assert_eq!(IsolationLevel::default().as_sql(), "READ COMMITTED");
+        "snapshot".parse::<IsolationLevel>().unwrap_err();
+    }
+}
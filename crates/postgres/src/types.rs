@@ -1,15 +1,30 @@
+mod column;
+mod wire;
+
 use std::error::Error;
 
 use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
 use tokio_postgres::types::private::BytesMut;
-use tokio_postgres::types::{IsNull, ToSql, Type, to_sql_checked};
+use tokio_postgres::types::{IsNull, Kind, ToSql, Type, to_sql_checked};
+
+pub use self::column::{Column, converts};
 
 /// An owned, type-erased SQL parameter.
 pub type Param = Box<dyn ToSql + Send + Sync>;
 /// A borrowed reference to a SQL parameter.
 pub type ParamRef<'a> = &'a (dyn ToSql + Sync);
 
-/// `PgType` to wrap around wasi-sql `DataType` to help implement `ToSql` trait
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// `PgType` to wrap around wasi-sql `DataType` to help implement `ToSql` trait.
+///
+/// Values are encoded for the parameter type Postgres infers from the
+/// statement rather than by variant, so integers fit `int2`, `int4`, `int8`, `oid`, `numeric`, and
+/// `money` columns (failing if out of range), and text fits text-like
+/// (including `citext` and `ltree`), `json`, `numeric`, `uuid`, `inet`,
+/// `cidr`, and enum columns. Other
+/// conversions, such as text to `interval`, need an explicit cast in the SQL
+/// (`$1::text::interval`).
 #[derive(Debug)]
 pub enum PgType {
     Int32(Option<i32>),
@@ -29,27 +44,22 @@ pub enum PgType {
 impl ToSql for PgType {
     to_sql_checked!();
 
-    fn to_sql(
-        &self, ty: &Type, out: &mut BytesMut,
-    ) -> Result<IsNull, Box<dyn Error + Send + Sync>> {
+    fn to_sql(&self, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
         match self {
-            Self::Int32(value) => write_optional(value.as_ref(), ty, out),
-            Self::Int64(value) => write_optional(value.as_ref(), ty, out),
-            Self::Uint32(value) => write_optional(value.as_ref(), ty, out),
-            Self::Float(value) => write_optional(value.as_ref(), ty, out),
-            Self::Double(value) => write_optional(value.as_ref(), ty, out),
+            Self::Int32(value) => write_int(value.map(i64::from), ty, out),
+            Self::Int64(value) => write_int(*value, ty, out),
+            Self::Uint32(value) => write_int(value.map(i64::from), ty, out),
+            Self::Float(value) => match value {
+                Some(v) if *ty == Type::NUMERIC => write_text(&v.to_string(), ty, out),
+                Some(v) if *ty == Type::FLOAT8 => f64::from(*v).to_sql(ty, out),
+                _ => write_optional(value.as_ref(), ty, out),
+            },
+            Self::Double(value) => match value {
+                Some(v) if *ty == Type::NUMERIC => write_text(&v.to_string(), ty, out),
+                _ => write_optional(value.as_ref(), ty, out),
+            },
             Self::Text(value) => {
-                if *ty == Type::JSON || *ty == Type::JSONB {
-                    match value.as_ref() {
-                        Some(raw) => {
-                            let parsed: serde_json::Value = serde_json::from_str(raw)?;
-                            tokio_postgres::types::Json(parsed).to_sql(ty, out)
-                        }
-                        None => Ok(IsNull::Yes),
-                    }
-                } else {
-                    write_optional(value.as_ref(), ty, out)
-                }
+                value.as_deref().map_or(Ok(IsNull::Yes), |text| write_text(text, ty, out))
             }
             Self::Bool(value) => write_optional(value.as_ref(), ty, out),
             Self::Date(value) => write_optional(value.as_ref(), ty, out),
@@ -69,15 +79,67 @@ impl ToSql for PgType {
     }
 }
 
-fn write_optional<T>(
-    value: Option<&T>, ty: &Type, out: &mut BytesMut,
-) -> Result<IsNull, Box<dyn Error + Send + Sync>>
+fn write_optional<T>(value: Option<&T>, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError>
 where
     T: ToSql + Sync,
 {
     value.map_or_else(|| Ok(IsNull::Yes), |inner| inner.to_sql(ty, out))
 }
 
+/// Write an integer as the integer type `ty`, failing if it is out of range.
+fn write_int(value: Option<i64>, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+    let Some(value) = value else {
+        return Ok(IsNull::Yes);
+    };
+    match *ty {
+        Type::INT2 => i16::try_from(value)?.to_sql(ty, out),
+        Type::INT4 => i32::try_from(value)?.to_sql(ty, out),
+        Type::OID => u32::try_from(value)?.to_sql(ty, out),
+        Type::NUMERIC => write_text(&value.to_string(), ty, out),
+        Type::MONEY => {
+            out.extend_from_slice(&value.to_be_bytes());
+            Ok(IsNull::No)
+        }
+        _ => match ty.kind() {
+            Kind::Domain(base) => write_int(Some(value), base, out),
+            _ => value.to_sql(ty, out),
+        },
+    }
+}
+
+/// Write text as `ty`, parsing it for the types whose binary form differs
+/// from their text.
+fn write_text(text: &str, ty: &Type, out: &mut BytesMut) -> Result<IsNull, BoxError> {
+    match *ty {
+        Type::JSON | Type::JSONB => {
+            let parsed: serde_json::Value = serde_json::from_str(text)?;
+            tokio_postgres::types::Json(parsed).to_sql(ty, out)
+        }
+        Type::NUMERIC => {
+            wire::numeric_from_str(text, out)?;
+            Ok(IsNull::No)
+        }
+        Type::UUID => {
+            out.extend_from_slice(&wire::uuid_from_str(text)?);
+            Ok(IsNull::No)
+        }
+        Type::INET | Type::CIDR => {
+            wire::inet_from_str(text, *ty == Type::CIDR, out)?;
+            Ok(IsNull::No)
+        }
+        _ if let Kind::Domain(base) = ty.kind() => write_text(text, base, out),
+        // an enum's binary form is its label
+        _ if matches!(ty.kind(), Kind::Enum(_)) => {
+            out.extend_from_slice(text.as_bytes());
+            Ok(IsNull::No)
+        }
+        _ if <&str as ToSql>::accepts(ty) => text.to_sql(ty, out),
+        _ => {
+            Err(format!("cannot send text as {ty}; cast the parameter, e.g. $1::text::{ty}").into())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use chrono::TimeZone;
@@ -175,6 +237,53 @@ mod tests {
         assert_serializes_as_null(&PgType::Binary(None), &Type::BYTEA);
     }
 
+    #[test]
+    fn integers_fit_target_type() {
+        assert_serializes_successfully(&PgType::Int32(Some(7)), &Type::INT2);
+        assert_serializes_successfully(&PgType::Int64(Some(7)), &Type::NUMERIC);
+        assert_serializes_successfully(&PgType::Uint32(Some(7)), &Type::INT8);
+        assert!(PgType::Int32(Some(70_000)).to_sql(&Type::INT2, &mut BytesMut::new()).is_err());
+
+        let mut buf = BytesMut::new();
+        PgType::Int32(Some(7)).to_sql(&Type::INT8, &mut buf).unwrap();
+        assert_eq!(&*buf, 7_i64.to_be_bytes());
+    }
+
+    #[test]
+    fn text_as_other_types() {
+        assert_serializes_successfully(&PgType::Text(Some("-12.340".into())), &Type::NUMERIC);
+        assert_serializes_successfully(
+            &PgType::Text(Some("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11".into())),
+            &Type::UUID,
+        );
+        assert_serializes_successfully(&PgType::Text(Some("10.0.0.0/8".into())), &Type::CIDR);
+
+        let mood =
+            Type::new("mood".into(), 90_000, Kind::Enum(vec!["happy".into()]), "public".into());
+        let mut buf = BytesMut::new();
+        PgType::Text(Some("happy".into())).to_sql(&mood, &mut buf).unwrap();
+        assert_eq!(&*buf, b"happy");
+
+        let ltree = Type::new("ltree".into(), 90_003, Kind::Simple, "public".into());
+        let mut buf = BytesMut::new();
+        PgType::Text(Some("a.b".into())).to_sql(&ltree, &mut buf).unwrap();
+        assert_eq!(&*buf, b"\x01a.b", "ltree is versioned");
+
+        let hstore = Type::new("hstore".into(), 90_002, Kind::Simple, "public".into());
+        let Err(err) = PgType::Text(Some("a=>1".into())).to_sql(&hstore, &mut BytesMut::new())
+        else {
+            panic!("text is not sent as an hstore");
+        };
+        assert!(err.to_string().contains("$1::text::hstore"), "{err}");
+
+        let Err(err) =
+            PgType::Text(Some("1 day".into())).to_sql(&Type::INTERVAL, &mut BytesMut::new())
+        else {
+            panic!("text is not sent as an interval");
+        };
+        assert!(err.to_string().contains("$1::text::interval"), "{err}");
+    }
+
     #[test]
     fn pgtype_binary_format() {
         let value = PgType::Int32(Some(42));
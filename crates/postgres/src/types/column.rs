@@ -0,0 +1,317 @@
+//! Conversion of result columns of any Postgres type into `wasi-sql` values.
+//!
+//! Types with a natural `wasi-sql` counterpart map to it; the rest become
+//! text (see [`wire`](super::wire) for the exact forms):
+//! - `int2` widens to `int32`, and `money` is an `int64` count of the
+//!   currency's minor unit, since its scale depends on `lc_monetary`.
+//! - Enums are their label, and the extension types `citext`, `ltree`,
+//!   `lquery`, and `ltxtquery` their text, decoded from each type's binary
+//!   form; domains convert as their base type.
+//! - Arrays become JSON arrays and composite types JSON objects keyed by field
+//!   name, with elements converted the same way and `json` elements embedded.
+//! - Any other type, such as `hstore`, `geometry`, or `macaddr`, has no
+//!   conversion (see [`converts`]). Queries select such columns as `text`
+//!   (see [`fetch`](crate::fetch)), so they arrive in their text form; a
+//!   column that could not be selected that way is the raw bytes of its
+//!   binary form.
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use omnia_wasi_sql::DataType;
+use serde_json::{Map, Value};
+use tokio_postgres::types::{FromSql, Json, Kind, Type};
+
+use super::BoxError;
+use super::wire::{self, Reader};
+
+/// A result column converted to a `wasi-sql` value.
+pub struct Column(pub DataType);
+
+impl<'a> FromSql<'a> for Column {
+    fn from_sql(ty: &Type, raw: &'a [u8]) -> Result<Self, BoxError> {
+        decode(ty, raw).map(Self)
+    }
+
+    fn from_sql_null(ty: &Type) -> Result<Self, BoxError> {
+        Ok(Self(null(ty)))
+    }
+
+    fn accepts(_: &Type) -> bool {
+        true
+    }
+}
+
+fn decode(ty: &Type, raw: &[u8]) -> Result<DataType, BoxError> {
+    let value = match *ty {
+        Type::INT2 => DataType::Int32(Some(i16::from_sql(ty, raw)?.into())),
+        Type::INT4 => DataType::Int32(Some(i32::from_sql(ty, raw)?)),
+        Type::INT8 => DataType::Int64(Some(i64::from_sql(ty, raw)?)),
+        Type::OID => DataType::Uint32(Some(u32::from_sql(ty, raw)?)),
+        Type::MONEY => DataType::Int64(Some(Reader::new(raw).i64()?)),
+        Type::FLOAT4 => DataType::Float(Some(f32::from_sql(ty, raw)?)),
+        Type::FLOAT8 => DataType::Double(Some(f64::from_sql(ty, raw)?)),
+        Type::BOOL => DataType::Boolean(Some(bool::from_sql(ty, raw)?)),
+        Type::CHAR => {
+            DataType::Str(Some(char::from(i8::from_sql(ty, raw)?.cast_unsigned()).into()))
+        }
+        Type::NUMERIC => DataType::Str(Some(wire::numeric_to_string(raw)?)),
+        Type::UUID => DataType::Str(Some(wire::uuid_to_string(raw)?)),
+        Type::INTERVAL => DataType::Str(Some(wire::interval_to_string(raw)?)),
+        Type::INET | Type::CIDR => DataType::Str(Some(wire::inet_to_string(raw)?)),
+        Type::DATE => DataType::Date(Some(NaiveDate::from_sql(ty, raw)?.to_string())),
+        Type::TIME => DataType::Time(Some(NaiveTime::from_sql(ty, raw)?.to_string())),
+        Type::TIMESTAMP => DataType::Timestamp(Some(NaiveDateTime::from_sql(ty, raw)?.to_string())),
+        Type::TIMESTAMPTZ => {
+            DataType::Timestamp(Some(DateTime::<Utc>::from_sql(ty, raw)?.to_rfc3339()))
+        }
+        Type::JSON | Type::JSONB => {
+            DataType::Str(Some(Json::<Value>::from_sql(ty, raw)?.0.to_string()))
+        }
+        Type::BYTEA => DataType::Binary(Some(raw.to_vec())),
+        _ if <&str as FromSql>::accepts(ty) => {
+            DataType::Str(Some(<&str>::from_sql(ty, raw)?.into()))
+        }
+        _ => match ty.kind() {
+            Kind::Domain(base) => decode(base, raw)?,
+            Kind::Array(_) | Kind::Composite(_) => DataType::Str(Some(json(ty, raw)?.to_string())),
+            Kind::Enum(_) => DataType::Str(Some(<&str>::from_sql(&Type::TEXT, raw)?.into())),
+            _ => fallback(ty, raw),
+        },
+    };
+    Ok(value)
+}
+
+/// Whether values of `ty` convert to a `wasi-sql` value other than the raw
+/// bytes of their binary form. Arrays and composites convert when their
+/// elements do.
+pub fn converts(ty: &Type) -> bool {
+    let known = matches!(
+        *ty,
+        Type::INT2
+            | Type::INT4
+            | Type::INT8
+            | Type::OID
+            | Type::MONEY
+            | Type::FLOAT4
+            | Type::FLOAT8
+            | Type::BOOL
+            | Type::CHAR
+            | Type::NUMERIC
+            | Type::UUID
+            | Type::INTERVAL
+            | Type::INET
+            | Type::CIDR
+            | Type::DATE
+            | Type::TIME
+            | Type::TIMESTAMP
+            | Type::TIMESTAMPTZ
+            | Type::JSON
+            | Type::JSONB
+            | Type::BYTEA
+    );
+    known
+        || <&str as FromSql>::accepts(ty)
+        || match ty.kind() {
+            Kind::Domain(base) | Kind::Array(base) => converts(base),
+            Kind::Composite(fields) => fields.iter().all(|field| converts(field.type_())),
+            Kind::Enum(_) => true,
+            _ => false,
+        }
+}
+
+/// The `NULL` value of the `wasi-sql` type `ty` converts to.
+fn null(ty: &Type) -> DataType {
+    match *ty {
+        Type::INT2 | Type::INT4 => DataType::Int32(None),
+        Type::INT8 | Type::MONEY => DataType::Int64(None),
+        Type::OID => DataType::Uint32(None),
+        Type::FLOAT4 => DataType::Float(None),
+        Type::FLOAT8 => DataType::Double(None),
+        Type::BOOL => DataType::Boolean(None),
+        Type::DATE => DataType::Date(None),
+        Type::TIME => DataType::Time(None),
+        Type::TIMESTAMP | Type::TIMESTAMPTZ => DataType::Timestamp(None),
+        Type::BYTEA => DataType::Binary(None),
+        _ => match ty.kind() {
+            Kind::Domain(base) => null(base),
+            _ => DataType::Str(None),
+        },
+    }
+}
+
+/// The raw binary form of a type with no known conversion, when the query
+/// could not select it as text. Binary forms are type-specific, so they are
+/// not assumed to be text even when they happen to be valid UTF-8.
+fn fallback(ty: &Type, raw: &[u8]) -> DataType {
+    tracing::debug!("returning column of type '{ty}' as raw bytes");
+    DataType::Binary(Some(raw.to_vec()))
+}
+
+/// A value as JSON, for the elements of arrays and composites.
+fn json(ty: &Type, raw: &[u8]) -> Result<Value, BoxError> {
+    match ty.kind() {
+        Kind::Array(element) => array(element, raw),
+        Kind::Composite(fields) => {
+            let mut reader = Reader::new(raw);
+            let count = reader.i32()?;
+            if usize::try_from(count)? != fields.len() {
+                return Err(format!("composite '{ty}' has {count} fields").into());
+            }
+            let mut object = Map::new();
+            for field in fields {
+                let _oid = reader.u32()?;
+                let value =
+                    reader.value()?.map_or(Ok(Value::Null), |raw| json(field.type_(), raw))?;
+                object.insert(field.name().to_owned(), value);
+            }
+            Ok(Value::Object(object))
+        }
+        Kind::Domain(base) => json(base, raw),
+        _ if matches!(*ty, Type::JSON | Type::JSONB) => Ok(Json::<Value>::from_sql(ty, raw)?.0),
+        _ => Ok(match decode(ty, raw)? {
+            DataType::Int32(v) => v.into(),
+            DataType::Int64(v) => v.into(),
+            DataType::Uint32(v) => v.into(),
+            DataType::Uint64(v) => v.into(),
+            DataType::Float(v) => v.map(f64::from).into(),
+            DataType::Double(v) => v.into(),
+            DataType::Boolean(v) => v.into(),
+            DataType::Str(v) | DataType::Date(v) | DataType::Time(v) | DataType::Timestamp(v) => {
+                v.into()
+            }
+            // bytea's text form
+            DataType::Binary(v) => v
+                .map(|bytes| {
+                    bytes.iter().fold("\\x".to_owned(), |hex, b| hex + &format!("{b:02x}"))
+                })
+                .into(),
+        }),
+    }
+}
+
+/// A binary array as nested JSON arrays, one level per dimension.
+fn array(element: &Type, raw: &[u8]) -> Result<Value, BoxError> {
+    let mut reader = Reader::new(raw);
+    let ndim = usize::try_from(reader.i32()?)?;
+    if ndim == 0 {
+        return Ok(Value::Array(vec![]));
+    }
+    let _has_nulls = reader.i32()?;
+    let _element_oid = reader.u32()?;
+    let mut dims = Vec::with_capacity(ndim);
+    for _ in 0..ndim {
+        dims.push(usize::try_from(reader.i32()?)?);
+        let _lower_bound = reader.i32()?;
+    }
+
+    let count: usize = dims.iter().product();
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        values.push(reader.value()?.map_or(Ok(Value::Null), |raw| json(element, raw))?);
+    }
+    // nest from the innermost dimension outwards
+    for &len in dims.iter().skip(1).rev() {
+        let mut rows = Vec::with_capacity(values.len() / len.max(1));
+        let mut values_iter = values.into_iter();
+        while values_iter.len() > 0 {
+            rows.push(Value::Array(values_iter.by_ref().take(len).collect()));
+        }
+        values = rows;
+    }
+    Ok(Value::Array(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_postgres::types::private::BytesMut;
+    use tokio_postgres::types::{Field, ToSql};
+
+    use super::*;
+
+    fn raw<T: ToSql>(value: &T, ty: &Type) -> BytesMut {
+        let mut buf = BytesMut::new();
+        value.to_sql(ty, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn scalars() {
+        let Column(v) = Column::from_sql(&Type::INT2, &raw(&7_i16, &Type::INT2)).unwrap();
+        assert!(matches!(v, DataType::Int32(Some(7))), "{v:?}");
+        let Column(v) = Column::from_sql(&Type::MONEY, &1234_i64.to_be_bytes()).unwrap();
+        assert!(matches!(v, DataType::Int64(Some(1234))), "{v:?}");
+        let Column(v) = Column::from_sql(&Type::BPCHAR, b"ab ").unwrap();
+        assert!(matches!(v, DataType::Str(Some(ref s)) if s == "ab "), "{v:?}");
+        let Column(v) = Column::from_sql_null(&Type::INT2).unwrap();
+        assert!(matches!(v, DataType::Int32(None)), "{v:?}");
+    }
+
+    #[test]
+    fn enums_and_unknown_types() {
+        let mood =
+            Type::new("mood".into(), 90_000, Kind::Enum(vec!["happy".into()]), "public".into());
+        let Column(v) = Column::from_sql(&mood, b"happy").unwrap();
+        assert!(matches!(v, DataType::Str(Some(ref s)) if s == "happy"), "{v:?}");
+
+        let Column(v) = Column::from_sql(&Type::POINT, &[0xff; 16]).unwrap();
+        assert!(matches!(v, DataType::Binary(Some(ref b)) if b.len() == 16), "{v:?}");
+
+        // a binary form that happens to be UTF-8 is still returned as bytes
+        let hstore = Type::new("hstore".into(), 90_002, Kind::Simple, "public".into());
+        let Column(v) = Column::from_sql(&hstore, b"\0\0\0\0").unwrap();
+        assert!(matches!(v, DataType::Binary(Some(ref b)) if b == b"\0\0\0\0"), "{v:?}");
+
+        // ltree's binary form starts with a version byte
+        let ltree = Type::new("ltree".into(), 90_003, Kind::Simple, "public".into());
+        let Column(v) = Column::from_sql(&ltree, b"\x01a.b").unwrap();
+        assert!(matches!(v, DataType::Str(Some(ref s)) if s == "a.b"), "{v:?}");
+    }
+
+    #[test]
+    fn conversions() {
+        assert!(converts(&Type::INT2));
+        assert!(converts(&Type::TEXT_ARRAY));
+        assert!(!converts(&Type::MACADDR));
+        assert!(!converts(&Type::POINT_ARRAY), "arrays convert when their elements do");
+        let hstore = Type::new("hstore".into(), 90_002, Kind::Simple, "public".into());
+        assert!(!converts(&hstore));
+        let fields = vec![Field::new("at".into(), Type::POINT)];
+        let ty = Type::new("place".into(), 90_004, Kind::Composite(fields), "public".into());
+        assert!(!converts(&ty));
+    }
+
+    #[test]
+    fn arrays() {
+        let ty = Type::INT4_ARRAY;
+        let Column(v) = Column::from_sql(&ty, &raw(&vec![Some(1), None, Some(3)], &ty)).unwrap();
+        assert!(matches!(v, DataType::Str(Some(ref s)) if s == "[1,null,3]"), "{v:?}");
+
+        // a 2x2 text array
+        let mut buf =
+            [&2_i32.to_be_bytes()[..], &0_i32.to_be_bytes(), &25_u32.to_be_bytes()].concat();
+        for _ in 0..2 {
+            buf.extend([2_i32.to_be_bytes(), 1_i32.to_be_bytes()].concat());
+        }
+        for s in ["a", "b", "c", "d"] {
+            buf.extend(1_i32.to_be_bytes());
+            buf.extend(s.as_bytes());
+        }
+        let Column(v) = Column::from_sql(&Type::TEXT_ARRAY, &buf).unwrap();
+        assert!(
+            matches!(v, DataType::Str(Some(ref s)) if s == r#"[["a","b"],["c","d"]]"#),
+            "{v:?}"
+        );
+    }
+
+    #[test]
+    fn composites() {
+        let fields =
+            vec![Field::new("id".into(), Type::INT8), Field::new("tags".into(), Type::TEXT)];
+        let ty = Type::new("item".into(), 90_001, Kind::Composite(fields), "public".into());
+        let mut buf = 2_i32.to_be_bytes().to_vec();
+        buf.extend([20_u32.to_be_bytes(), 8_i32.to_be_bytes()].concat());
+        buf.extend(5_i64.to_be_bytes());
+        buf.extend([25_u32.to_be_bytes(), (-1_i32).to_be_bytes()].concat());
+        let Column(v) = Column::from_sql(&ty, &buf).unwrap();
+        assert!(matches!(v, DataType::Str(Some(ref s)) if s == r#"{"id":5,"tags":null}"#), "{v:?}");
+    }
+}
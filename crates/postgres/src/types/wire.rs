@@ -0,0 +1,362 @@
+//! Binary wire formats for the Postgres types `tokio-postgres` has no built-in
+//! conversion for. Values cross the `wasi-sql` boundary as text:
+//! - `numeric` as an exact decimal string (`-12.340`, `NaN`, `Infinity`),
+//!   keeping the column's scale.
+//! - `uuid` as a lowercase hyphenated string.
+//! - `interval` as an ISO 8601 duration (`P1Y2M3DT4H5M6.5S`), in the style of
+//!   Postgres's `iso_8601` interval output.
+//! - `inet` and `cidr` in Postgres's text form (`10.0.0.1`, `10.0.0.0/8`).
+
+use std::fmt::Write as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use tokio_postgres::types::private::BytesMut;
+
+use super::BoxError;
+
+const NUMERIC_POS: u16 = 0x0000;
+const NUMERIC_NEG: u16 = 0x4000;
+const NUMERIC_NAN: u16 = 0xC000;
+const NUMERIC_PINF: u16 = 0xD000;
+const NUMERIC_NINF: u16 = 0xF000;
+
+/// Address family codes in the `inet` wire format.
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 3;
+
+/// Reads big-endian fields from a binary value.
+pub struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub const fn new(raw: &'a [u8]) -> Self {
+        Self(raw)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], BoxError> {
+        let (head, rest) = self.0.split_at_checked(len).ok_or("value is truncated")?;
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], BoxError> {
+        Ok(self.bytes(N)?.try_into()?)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, BoxError> {
+        Ok(self.array::<1>()?[0])
+    }
+
+    pub fn i16(&mut self) -> Result<i16, BoxError> {
+        Ok(i16::from_be_bytes(self.array()?))
+    }
+
+    pub fn u16(&mut self) -> Result<u16, BoxError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, BoxError> {
+        Ok(i32::from_be_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, BoxError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, BoxError> {
+        Ok(i64::from_be_bytes(self.array()?))
+    }
+
+    /// A length-prefixed value, `None` for SQL `NULL`.
+    pub fn value(&mut self) -> Result<Option<&'a [u8]>, BoxError> {
+        match self.i32()? {
+            -1 => Ok(None),
+            len => Ok(Some(self.bytes(usize::try_from(len)?)?)),
+        }
+    }
+}
+
+/// Decode a binary `numeric` into its exact decimal text.
+pub fn numeric_to_string(raw: &[u8]) -> Result<String, BoxError> {
+    let mut reader = Reader::new(raw);
+    let ndigits = usize::try_from(reader.i16()?)?;
+    let weight = i32::from(reader.i16()?);
+    let sign = reader.u16()?;
+    let dscale = usize::from(reader.u16()?);
+    let digits = (0..ndigits).map(|_| reader.i16()).collect::<Result<Vec<_>, _>>()?;
+    // base-10000 digit `i` has weight `weight - i`
+    let digit = |i: i32| usize::try_from(i).ok().and_then(|i| digits.get(i)).copied().unwrap_or(0);
+
+    let mut text = match sign {
+        NUMERIC_POS => String::new(),
+        NUMERIC_NEG => "-".to_owned(),
+        NUMERIC_NAN => return Ok("NaN".to_owned()),
+        NUMERIC_PINF => return Ok("Infinity".to_owned()),
+        NUMERIC_NINF => return Ok("-Infinity".to_owned()),
+        _ => return Err(format!("invalid numeric sign {sign:#x}").into()),
+    };
+    if weight < 0 {
+        text.push('0');
+    } else {
+        write!(text, "{}", digit(0))?;
+        for i in 1..=weight {
+            write!(text, "{:04}", digit(i))?;
+        }
+    }
+    if dscale > 0 {
+        let mut fraction = String::with_capacity(dscale + 4);
+        let mut i = weight + 1;
+        while fraction.len() < dscale {
+            write!(fraction, "{:04}", digit(i))?;
+            i += 1;
+        }
+        fraction.truncate(dscale);
+        text.push('.');
+        text.push_str(&fraction);
+    }
+    Ok(text)
+}
+
+/// Encode decimal text (`[-+]digits[.digits]`, `NaN`, or `[-+]Infinity`) as a
+/// binary `numeric`.
+pub fn numeric_from_str(text: &str, out: &mut BytesMut) -> Result<(), BoxError> {
+    let special = match text {
+        "NaN" => Some(NUMERIC_NAN),
+        "Infinity" | "+Infinity" => Some(NUMERIC_PINF),
+        "-Infinity" => Some(NUMERIC_NINF),
+        _ => None,
+    };
+    if let Some(sign) = special {
+        return write_numeric(out, &[], 0, sign, 0);
+    }
+
+    let invalid = || format!("invalid numeric {text:?}");
+    let (negative, unsigned) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    let (int, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    if int.is_empty() && fraction.is_empty()
+        || !int.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit())
+    {
+        return Err(invalid().into());
+    }
+    let int = int.trim_start_matches('0');
+
+    // group into base-10000 digits aligned on the decimal point
+    let mut decimal = "0".repeat((4 - int.len() % 4) % 4);
+    decimal.push_str(int);
+    let int_groups = decimal.len() / 4;
+    decimal.push_str(fraction);
+    decimal.push_str(&"0".repeat((4 - fraction.len() % 4) % 4));
+    let mut digits: Vec<i16> = decimal
+        .as_bytes()
+        .chunks(4)
+        .map(|group| group.iter().fold(0, |n, d| n * 10 + i16::from(d - b'0')))
+        .collect();
+
+    let mut weight = i32::try_from(int_groups)? - 1;
+    let leading = digits.iter().take_while(|&&d| d == 0).count();
+    digits.drain(..leading);
+    weight -= i32::try_from(leading)?;
+    while digits.last() == Some(&0) {
+        digits.pop();
+    }
+    if digits.is_empty() {
+        weight = 0;
+    }
+
+    let sign = if negative && !digits.is_empty() { NUMERIC_NEG } else { NUMERIC_POS };
+    let weight = i16::try_from(weight).map_err(|_e| invalid())?;
+    let dscale = u16::try_from(fraction.len()).map_err(|_e| invalid())?;
+    write_numeric(out, &digits, weight, sign, dscale)
+}
+
+fn write_numeric(
+    out: &mut BytesMut, digits: &[i16], weight: i16, sign: u16, dscale: u16,
+) -> Result<(), BoxError> {
+    let ndigits = i16::try_from(digits.len()).map_err(|_e| "numeric has too many digits")?;
+    out.extend_from_slice(&ndigits.to_be_bytes());
+    out.extend_from_slice(&weight.to_be_bytes());
+    out.extend_from_slice(&sign.to_be_bytes());
+    out.extend_from_slice(&dscale.to_be_bytes());
+    for digit in digits {
+        out.extend_from_slice(&digit.to_be_bytes());
+    }
+    Ok(())
+}
+
+/// Decode a binary `uuid` into its hyphenated form.
+pub fn uuid_to_string(raw: &[u8]) -> Result<String, BoxError> {
+    let bytes: [u8; 16] = raw.try_into().map_err(|_e| "uuid must be 16 bytes")?;
+    let mut text = String::with_capacity(36);
+    for (i, byte) in bytes.iter().enumerate() {
+        if matches!(i, 4 | 6 | 8 | 10) {
+            text.push('-');
+        }
+        write!(text, "{byte:02x}")?;
+    }
+    Ok(text)
+}
+
+/// Parse a UUID, with or without hyphens, into its 16 bytes.
+pub fn uuid_from_str(text: &str) -> Result<[u8; 16], BoxError> {
+    let hex: Vec<u8> = text.bytes().filter(|&b| b != b'-').collect();
+    let invalid = || format!("invalid uuid {text:?}");
+    if hex.len() != 32 {
+        return Err(invalid().into());
+    }
+    let mut bytes = [0; 16];
+    for (byte, pair) in bytes.iter_mut().zip(hex.chunks(2)) {
+        let pair = std::str::from_utf8(pair).map_err(|_e| invalid())?;
+        *byte = u8::from_str_radix(pair, 16).map_err(|_e| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// Decode a binary `interval` into an ISO 8601 duration.
+pub fn interval_to_string(raw: &[u8]) -> Result<String, BoxError> {
+    let mut reader = Reader::new(raw);
+    let micros = reader.i64()?;
+    let days = reader.i32()?;
+    let months = reader.i32()?;
+
+    let mut text = "P".to_owned();
+    let (years, months) = (months / 12, months % 12);
+    for (value, unit) in [(years, 'Y'), (months, 'M'), (days, 'D')] {
+        if value != 0 {
+            write!(text, "{value}{unit}")?;
+        }
+    }
+    if micros != 0 {
+        // the time fields share the sign of the whole time part
+        let sign = if micros < 0 { "-" } else { "" };
+        let micros = micros.unsigned_abs();
+        let (hours, minutes) = (micros / 3_600_000_000, micros / 60_000_000 % 60);
+        let (seconds, fraction) = (micros / 1_000_000 % 60, micros % 1_000_000);
+        text.push('T');
+        if hours != 0 {
+            write!(text, "{sign}{hours}H")?;
+        }
+        if minutes != 0 {
+            write!(text, "{sign}{minutes}M")?;
+        }
+        if seconds != 0 || fraction != 0 {
+            write!(text, "{sign}{seconds}")?;
+            if fraction != 0 {
+                let fraction = format!("{fraction:06}");
+                write!(text, ".{}", fraction.trim_end_matches('0'))?;
+            }
+            text.push('S');
+        }
+    }
+    if text == "P" {
+        text.push_str("T0S");
+    }
+    Ok(text)
+}
+
+/// Decode a binary `inet` or `cidr`. The prefix length is shown for `cidr`
+/// values and for `inet` values that are not a single host.
+pub fn inet_to_string(raw: &[u8]) -> Result<String, BoxError> {
+    let mut reader = Reader::new(raw);
+    let family = reader.u8()?;
+    let bits = reader.u8()?;
+    let is_cidr = reader.u8()? != 0;
+    let len = usize::from(reader.u8()?);
+    let bytes = reader.bytes(len)?;
+
+    let (addr, max_bits) = match family {
+        AF_INET => (IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes)?)), 32),
+        AF_INET6 => (IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes)?)), 128),
+        _ => return Err(format!("invalid inet family {family}").into()),
+    };
+    if is_cidr || bits != max_bits {
+        return Ok(format!("{addr}/{bits}"));
+    }
+    Ok(addr.to_string())
+}
+
+/// Encode `addr[/bits]` as a binary `inet`, or `cidr` when `is_cidr`.
+pub fn inet_from_str(text: &str, is_cidr: bool, out: &mut BytesMut) -> Result<(), BoxError> {
+    let (addr, bits) = text.split_once('/').map_or((text, None), |(a, b)| (a, Some(b)));
+    let addr: IpAddr = addr.parse().map_err(|_e| format!("invalid inet {text:?}"))?;
+    let (family, max_bits, bytes) = match addr {
+        IpAddr::V4(v4) => (AF_INET, 32, v4.octets().to_vec()),
+        IpAddr::V6(v6) => (AF_INET6, 128, v6.octets().to_vec()),
+    };
+    let bits = bits
+        .map_or(Some(max_bits), |bits| bits.parse().ok().filter(|&b| b <= max_bits))
+        .ok_or_else(|| format!("invalid inet prefix in {text:?}"))?;
+
+    out.extend_from_slice(&[family, bits, u8::from(is_cidr), u8::try_from(bytes.len())?]);
+    out.extend_from_slice(&bytes);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numeric_round_trip(text: &str) -> String {
+        let mut buf = BytesMut::new();
+        numeric_from_str(text, &mut buf).unwrap();
+        numeric_to_string(&buf).unwrap()
+    }
+
+    #[test]
+    fn numerics() {
+        for text in ["0", "1", "-12.340", "12345678.9", "0.0001", "0.00001234", "10000", "NaN"] {
+            assert_eq!(numeric_round_trip(text), text);
+        }
+        assert_eq!(numeric_round_trip("+007.50"), "7.50");
+        assert_eq!(numeric_round_trip("-0.0"), "0.0");
+        assert_eq!(numeric_round_trip(".5"), "0.5");
+        for text in ["", "-", "1e5", "1.2.3", "abc"] {
+            numeric_from_str(text, &mut BytesMut::new()).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn numeric_wire_format() {
+        // 12345.678 is digits [1, 2345, 6780], weight 1, dscale 3
+        let raw = [0, 3, 0, 1, 0, 0, 0, 3, 0, 1, 0x09, 0x29, 0x1a, 0x7c];
+        assert_eq!(numeric_to_string(&raw).unwrap(), "12345.678");
+        let mut buf = BytesMut::new();
+        numeric_from_str("12345.678", &mut buf).unwrap();
+        assert_eq!(&*buf, raw);
+    }
+
+    #[test]
+    fn uuids() {
+        let text = "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11";
+        let bytes = uuid_from_str(text).unwrap();
+        assert_eq!(uuid_to_string(&bytes).unwrap(), text);
+        assert_eq!(uuid_from_str("A0EEBC999C0B4EF8BB6D6BB9BD380A11").unwrap(), bytes);
+        uuid_from_str("not-a-uuid").unwrap_err();
+    }
+
+    #[test]
+    fn intervals() {
+        let raw = |micros: i64, days: i32, months: i32| {
+            [&micros.to_be_bytes()[..], &days.to_be_bytes(), &months.to_be_bytes()].concat()
+        };
+        assert_eq!(interval_to_string(&raw(14_706_500_000, 3, 14)).unwrap(), "P1Y2M3DT4H5M6.5S");
+        assert_eq!(interval_to_string(&raw(-90_000_000, 0, 0)).unwrap(), "PT-1M-30S");
+        assert_eq!(interval_to_string(&raw(0, -1, 0)).unwrap(), "P-1D");
+        assert_eq!(interval_to_string(&raw(0, 0, 0)).unwrap(), "PT0S");
+    }
+
+    #[test]
+    fn inets() {
+        for (text, is_cidr) in [("10.0.0.1", false), ("10.0.0.0/8", true), ("::1/64", false)] {
+            let mut buf = BytesMut::new();
+            inet_from_str(text, is_cidr, &mut buf).unwrap();
+            assert_eq!(inet_to_string(&buf).unwrap(), text);
+        }
+        let mut buf = BytesMut::new();
+        inet_from_str("10.0.0.0", true, &mut buf).unwrap();
+        assert_eq!(inet_to_string(&buf).unwrap(), "10.0.0.0/32");
+        inet_from_str("10.0.0.1/33", false, &mut BytesMut::new()).unwrap_err();
+    }
+}
@@ -9,7 +9,7 @@
 
 use anyhow::Result;
 use omnia::Backend;
-use omnia_postgres::Client;
+use omnia_postgres::{Client, ConnectOptions, IsolationLevel};
 use omnia_wasi_sql::{Connection, DataType, WasiSqlCtx};
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
@@ -31,3 +31,209 @@ async fn query() -> Result<()> {
     );
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Postgres (POSTGRES_URL); run with --run-ignored"]
+async fn transactions() -> Result<()> {
+    let client = <Client as Backend>::connect().await?;
+    let conn = client.open("default".to_owned()).await?;
+
+    // the connection keeps one pooled client, so the temp table and the
+    // transaction span statements
+    conn.exec("CREATE TEMP TABLE tx_test (n int4)".to_owned(), vec![]).await?;
+    conn.exec("BEGIN".to_owned(), vec![]).await?;
+    conn.exec("INSERT INTO tx_test VALUES ($1)".to_owned(), vec![DataType::Int32(Some(1))]).await?;
+    conn.exec("ROLLBACK".to_owned(), vec![]).await?;
+    let rows = conn.query("SELECT n FROM tx_test".to_owned(), vec![]).await?;
+    assert!(rows.is_empty(), "rolled back insert is gone");
+
+    let tx = client.transaction("default", IsolationLevel::Serializable).await?;
+    let rows = tx.query("SHOW transaction_isolation".to_owned(), vec![]).await?;
+    assert!(
+        matches!(&rows[0].fields[0].value, DataType::Str(Some(level)) if level == "serializable"),
+        "isolation level applied: {:?}",
+        rows[0].fields[0].value
+    );
+    tx.commit().await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Postgres (POSTGRES_URL); run with --run-ignored"]
+async fn abandoned_transactions() -> Result<()> {
+    // one client, so the reopened connection gets the one that was abandoned
+    let mut options = <ConnectOptions as omnia::FromEnv>::from_env()?;
+    options.default_pool.pool_size = 1;
+    let client = Client::connect_with(options).await?;
+
+    let conn = client.open("default".to_owned()).await?;
+    conn.exec("/* guest */ BEGIN".to_owned(), vec![]).await?;
+    conn.exec("CREATE TEMP TABLE abandoned_test (n int4)".to_owned(), vec![]).await?;
+    drop(conn);
+
+    let conn = client.open("default".to_owned()).await?;
+    let rows = conn
+        .query("SELECT to_regclass('pg_temp.abandoned_test') IS NULL".to_owned(), vec![])
+        .await?;
+    assert!(
+        matches!(rows[0].fields[0].value, DataType::Boolean(Some(true))),
+        "abandoned transaction rolled back"
+    );
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Postgres (POSTGRES_URL); run with --run-ignored"]
+async fn types() -> Result<()> {
+    let client = <Client as Backend>::connect().await?;
+    let conn = client.open("default".to_owned()).await?;
+
+    let rows = conn
+        .query(
+            "SELECT 7::int2 AS small, $1::numeric AS exact, $2::uuid AS id, \
+             ARRAY[[1, 2], [3, NULL]]::int4[] AS grid, interval '1 day 2 hours' AS span, \
+             '10.0.0.0/8'::cidr AS net, '{\"a\": 1}'::jsonb AS doc, \
+             '08:00:2b:01:02:03'::macaddr AS mac, 1 AS mac -- trailing comment"
+                .to_owned(),
+            vec![
+                DataType::Str(Some("-12.340".to_owned())),
+                DataType::Str(Some("a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11".to_owned())),
+            ],
+        )
+        .await?;
+
+    let text = |i: usize| match &rows[0].fields[i].value {
+        DataType::Str(Some(text)) => text.clone(),
+        other => panic!("column {i} is not text: {other:?}"),
+    };
+    assert!(matches!(rows[0].fields[0].value, DataType::Int32(Some(7))), "int2 widens");
+    assert_eq!(text(1), "-12.340", "numeric keeps its scale");
+    assert_eq!(text(2), "a0eebc99-9c0b-4ef8-bb6d-6bb9bd380a11");
+    assert_eq!(text(3), "[[1,2],[3,null]]");
+    assert_eq!(text(4), "P1DT2H");
+    assert_eq!(text(5), "10.0.0.0/8");
+    assert_eq!(text(6), r#"{"a":1}"#);
+    assert_eq!(text(7), "08:00:2b:01:02:03", "types without a conversion are text");
+    assert_eq!(rows[0].fields[7].name, "mac");
+    assert!(
+        matches!(rows[0].fields[8].value, DataType::Int32(Some(1))),
+        "other columns keep theirs"
+    );
+    assert_eq!(rows[0].fields[8].name, "mac");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Postgres (POSTGRES_URL); run with --run-ignored"]
+async fn fetch_limits() -> Result<()> {
+    let mut options = <ConnectOptions as omnia::FromEnv>::from_env()?;
+    options.default_pool.fetch_size = 2;
+    options.default_pool.max_rows = 5;
+    let client = Client::connect_with(options).await?;
+    let conn = client.open("default".to_owned()).await?;
+
+    // fetched through a cursor, two rows at a time
+    let rows = conn.query("SELECT generate_series(1, 5) AS n".to_owned(), vec![]).await?;
+    assert_eq!(rows.len(), 5, "rows across batches");
+    assert_eq!(rows[4].index, "4", "indexes continue across batches");
+
+    let Err(e) = conn.query("SELECT generate_series(1, 6)".to_owned(), vec![]).await else {
+        panic!("row limit exceeded");
+    };
+    assert!(e.to_string().contains("more than 5 rows"), "{e}");
+
+    // the limit applies inside a guest transaction, which stays usable
+    conn.exec("BEGIN".to_owned(), vec![]).await?;
+    conn.query("VALUES (1), (2), (3), (4), (5), (6)".to_owned(), vec![]).await.unwrap_err();
+    let rows = conn.query("SELECT 1".to_owned(), vec![]).await?;
+    assert_eq!(rows.len(), 1, "transaction still usable");
+    conn.exec("COMMIT".to_owned(), vec![]).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Postgres (POSTGRES_URL); run with --run-ignored"]
+async fn streamed_limits() -> Result<()> {
+    let mut options = <ConnectOptions as omnia::FromEnv>::from_env()?;
+    options.default_pool.fetch_size = 0;
+    options.default_pool.max_rows = 5;
+    options.default_pool.max_bytes = 1024;
+    let client = Client::connect_with(options).await?;
+    let conn = client.open("default".to_owned()).await?;
+
+    let rows = conn.query("SELECT generate_series(1, 5) AS n".to_owned(), vec![]).await?;
+    assert_eq!(rows.len(), 5, "rows up to the limit");
+
+    let Err(e) = conn.query("SELECT generate_series(1, 100000)".to_owned(), vec![]).await else {
+        panic!("row limit exceeded");
+    };
+    assert!(e.to_string().contains("more than 5 rows"), "{e}");
+
+    let Err(e) = conn.query("SELECT repeat('x', 2048) AS big".to_owned(), vec![]).await else {
+        panic!("byte limit exceeded");
+    };
+    assert!(e.to_string().contains("more than 1024 bytes"), "{e}");
+
+    // the abandoned results are drained, so the connection stays usable
+    let rows = conn.query("SELECT 1".to_owned(), vec![]).await?;
+    assert_eq!(rows.len(), 1, "connection still usable");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Postgres (POSTGRES_URL); run with --run-ignored"]
+async fn replicas() -> Result<()> {
+    // the primary stands in for its own replica; routes differ by backend
+    let mut options = <ConnectOptions as omnia::FromEnv>::from_env()?;
+    options.default_pool.replica_uris = vec![options.default_pool.uri.clone()];
+    let client = Client::connect_with(options).await?;
+    let conn = client.open("default".to_owned()).await?;
+
+    let pid = async |query: &str| -> Result<i32> {
+        let rows = conn.query(query.to_owned(), vec![]).await?;
+        match rows[0].fields[0].value {
+            DataType::Int32(Some(pid)) => Ok(pid),
+            ref other => anyhow::bail!("unexpected pid {other:?}"),
+        }
+    };
+    let primary = pid("/* omnia:primary */ SELECT pg_backend_pid()").await?;
+    assert_ne!(pid("SELECT pg_backend_pid()").await?, primary, "read goes to the replica");
+
+    conn.exec("CREATE TEMP TABLE replica_test (n int4)".to_owned(), vec![]).await?;
+    assert_eq!(pid("SELECT pg_backend_pid()").await?, primary, "reads follow writes");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Postgres (POSTGRES_URL); run with --run-ignored"]
+async fn replica_writes() -> Result<()> {
+    // a read-only session on the primary stands in for a replica
+    let mut options = <ConnectOptions as omnia::FromEnv>::from_env()?;
+    let uri = &options.default_pool.uri;
+    let separator = if uri.contains('?') { '&' } else { '?' };
+    let replica = format!("{uri}{separator}options=-c%20default_transaction_read_only%3Don");
+    options.default_pool.replica_uris = vec![replica];
+    let client = Client::connect_with(options).await?;
+    client
+        .open("default".to_owned())
+        .await?
+        .exec("CREATE SEQUENCE IF NOT EXISTS replica_seq".to_owned(), vec![])
+        .await?;
+    let conn = client.open("default".to_owned()).await?;
+    let pid = async |query: &str| -> Result<i32> {
+        let rows = conn.query(query.to_owned(), vec![]).await?;
+        match rows[0].fields[0].value {
+            DataType::Int32(Some(pid)) => Ok(pid),
+            ref other => anyhow::bail!("unexpected pid {other:?}"),
+        }
+    };
+    let primary = pid("/* omnia:primary */ SELECT pg_backend_pid()").await?;
+    assert_ne!(pid("SELECT pg_backend_pid()").await?, primary, "read goes to the replica");
+
+    // refused by the replica and retried on the primary
+    conn.query("SELECT nextval('replica_seq')".to_owned(), vec![]).await?;
+    assert_eq!(pid("SELECT pg_backend_pid()").await?, primary, "retried reads are writes");
+
+    conn.exec("DROP SEQUENCE replica_seq".to_owned(), vec![]).await?;
+    Ok(())
+}
@@ -0,0 +1,451 @@
+//! Blobstore implementation for the Redis backend.
+//!
+//! Each container is a key namespace under `omnia:blob:{len}:{container}`,
+//! where `len` is the name's length in bytes so that no container's namespace
+//! contains another's (`a:b` would otherwise sit inside `a`). An object
+//! is split into fixed-size chunks stored as separate string values, with a
+//! metadata hash recording its size, creation time, chunk count and chunk size.
+//! Range reads fetch only the chunks overlapping the requested bytes.
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, anyhow, bail};
+use futures::{FutureExt, TryStreamExt};
+use omnia_wasi_blobstore::{
+    Bytes, Container, ContainerMetadata, FutureResult, ObjectMetadata, WasiBlobstoreCtx,
+};
+use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
+
+use crate::Client;
+
+const PREFIX: &str = "omnia:blob:";
+
+/// `wasi-blobstore` implementation backed by Redis.
+impl WasiBlobstoreCtx for Client {
+    fn create_container(&self, name: String) -> FutureResult<Arc<dyn Container>> {
+        tracing::trace!("creating container: {name}");
+        let mut conn = self.conn.clone();
+        let chunk_size = self.chunk_size;
+
+        async move {
+            let keys = Keys::new(&name);
+            let _: bool = conn
+                .set_nx(keys.container(), now_unix_secs())
+                .await
+                .context("creating container")?;
+            let created_at: u64 = conn.get(keys.container()).await.context("getting container")?;
+
+            Ok(Arc::new(RedisContainer {
+                name,
+                created_at,
+                chunk_size,
+                conn,
+            }) as Arc<dyn Container>)
+        }
+        .boxed()
+    }
+
+    fn get_container(&self, name: String) -> FutureResult<Arc<dyn Container>> {
+        tracing::trace!("getting container: {name}");
+        let mut conn = self.conn.clone();
+        let chunk_size = self.chunk_size;
+
+        async move {
+            let created_at: Option<u64> =
+                conn.get(Keys::new(&name).container()).await.context("getting container")?;
+            let created_at = created_at.ok_or_else(|| anyhow!("container {name} not found"))?;
+
+            Ok(Arc::new(RedisContainer {
+                name,
+                created_at,
+                chunk_size,
+                conn,
+            }) as Arc<dyn Container>)
+        }
+        .boxed()
+    }
+
+    fn delete_container(&self, name: String) -> FutureResult<()> {
+        tracing::trace!("deleting container: {name}");
+        let mut conn = self.conn.clone();
+
+        async move {
+            let keys = Keys::new(&name);
+            let mut found: Vec<String> = conn
+                .scan_match::<_, String>(keys.pattern())
+                .await
+                .context("scanning container")?
+                .try_collect()
+                .await
+                .context("scanning container")?;
+            found.push(keys.container());
+
+            for batch in found.chunks(1000) {
+                let _: usize = conn.del(batch).await.context("deleting container")?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn container_exists(&self, name: String) -> FutureResult<bool> {
+        tracing::trace!("checking existence of container: {name}");
+        let mut conn = self.conn.clone();
+
+        async move {
+            conn.exists(Keys::new(&name).container()).await.context("checking container existence")
+        }
+        .boxed()
+    }
+}
+
+/// A blobstore container stored as a Redis key namespace.
+struct RedisContainer {
+    name: String,
+    created_at: u64,
+    chunk_size: usize,
+    conn: ConnectionManager,
+}
+
+impl Debug for RedisContainer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RedisContainer").field("name", &self.name).finish_non_exhaustive()
+    }
+}
+
+impl Container for RedisContainer {
+    fn name(&self) -> anyhow::Result<String> {
+        tracing::trace!("getting container name");
+        Ok(self.name.clone())
+    }
+
+    fn info(&self) -> anyhow::Result<ContainerMetadata> {
+        tracing::trace!("getting container info");
+        Ok(ContainerMetadata {
+            name: self.name.clone(),
+            created_at: self.created_at,
+        })
+    }
+
+    fn get_data(&self, name: String, start: u64, end: u64) -> FutureResult<Option<Bytes>> {
+        tracing::trace!("getting object data: {name}");
+        let keys = Keys::new(&self.name);
+        let mut conn = self.conn.clone();
+
+        async move {
+            let Some(meta) = ObjectMeta::load(&mut conn, &keys.meta(&name)).await? else {
+                return Ok(None);
+            };
+            let Some(span) = Span::new(meta.size, meta.chunk_size, start, end)? else {
+                return Ok(Some(Bytes::new()));
+            };
+
+            let chunk_keys: Vec<String> =
+                (span.first..=span.last).map(|i| keys.chunk(&name, i)).collect();
+            let chunks: Vec<Option<Vec<u8>>> =
+                conn.mget(&chunk_keys).await.context("reading object chunks")?;
+
+            let mut data = Vec::with_capacity(span.offset + span.len);
+            for chunk in chunks {
+                // a concurrent overwrite or delete can remove chunks mid-read
+                data.extend(chunk.ok_or_else(|| anyhow!("object {name} changed during read"))?);
+            }
+            if data.len() < span.offset + span.len {
+                bail!("object {name} changed during read");
+            }
+            Ok(Some(Bytes::from(data).slice(span.offset..span.offset + span.len)))
+        }
+        .boxed()
+    }
+
+    fn write_data(&self, name: String, data: Bytes) -> FutureResult<()> {
+        tracing::trace!("writing object data: {name}");
+        let keys = Keys::new(&self.name);
+        let chunk_size = self.chunk_size;
+        let mut conn = self.conn.clone();
+
+        async move {
+            let meta_key = keys.meta(&name);
+            let previous = ObjectMeta::load(&mut conn, &meta_key).await?;
+            let meta = ObjectMeta {
+                size: data.len() as u64,
+                created_at: previous.as_ref().map_or_else(now_unix_secs, |m| m.created_at),
+                chunks: data.len().div_ceil(chunk_size) as u64,
+                chunk_size: chunk_size as u64,
+            };
+
+            let mut pipe = redis::pipe();
+            pipe.atomic();
+            for (i, chunk) in data.chunks(chunk_size).enumerate() {
+                pipe.set(keys.chunk(&name, i as u64), chunk).ignore();
+            }
+            // drop trailing chunks left over from a larger previous version
+            if let Some(previous) = previous {
+                let stale: Vec<String> =
+                    (meta.chunks..previous.chunks).map(|i| keys.chunk(&name, i)).collect();
+                if !stale.is_empty() {
+                    pipe.del(stale).ignore();
+                }
+            }
+            pipe.hset_multiple(&meta_key, &meta.fields()).ignore();
+            pipe.sadd(keys.index(), &name).ignore();
+
+            pipe.query_async::<()>(&mut conn).await.context("writing object")
+        }
+        .boxed()
+    }
+
+    fn list_objects(&self) -> FutureResult<Vec<String>> {
+        tracing::trace!("listing objects");
+        let keys = Keys::new(&self.name);
+        let mut conn = self.conn.clone();
+
+        async move { conn.smembers(keys.index()).await.context("listing objects") }.boxed()
+    }
+
+    fn delete_object(&self, name: String) -> FutureResult<()> {
+        tracing::trace!("deleting object: {name}");
+        let keys = Keys::new(&self.name);
+        let mut conn = self.conn.clone();
+
+        async move {
+            let meta_key = keys.meta(&name);
+            let Some(meta) = ObjectMeta::load(&mut conn, &meta_key).await? else {
+                return Ok(());
+            };
+
+            let mut doomed: Vec<String> = (0..meta.chunks).map(|i| keys.chunk(&name, i)).collect();
+            doomed.push(meta_key);
+
+            redis::pipe()
+                .atomic()
+                .del(doomed)
+                .ignore()
+                .srem(keys.index(), &name)
+                .ignore()
+                .query_async::<()>(&mut conn)
+                .await
+                .context("deleting object")
+        }
+        .boxed()
+    }
+
+    fn has_object(&self, name: String) -> FutureResult<bool> {
+        tracing::trace!("checking existence of object: {name}");
+        let keys = Keys::new(&self.name);
+        let mut conn = self.conn.clone();
+
+        async move { conn.exists(keys.meta(&name)).await.context("checking object existence") }
+            .boxed()
+    }
+
+    fn object_info(&self, name: String) -> FutureResult<ObjectMetadata> {
+        tracing::trace!("getting object info: {name}");
+        let keys = Keys::new(&self.name);
+        let container = self.name.clone();
+        let mut conn = self.conn.clone();
+
+        async move {
+            let meta = ObjectMeta::load(&mut conn, &keys.meta(&name))
+                .await?
+                .ok_or_else(|| anyhow!("object {name} not found"))?;
+
+            Ok(ObjectMetadata {
+                name,
+                container,
+                created_at: meta.created_at,
+                size: meta.size,
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Key layout for a container's namespace.
+struct Keys {
+    base: String,
+}
+
+impl Keys {
+    fn new(container: &str) -> Self {
+        Self {
+            base: format!("{PREFIX}{}:{container}", container.len()),
+        }
+    }
+
+    /// Container marker, holding its creation time.
+    fn container(&self) -> String {
+        self.base.clone()
+    }
+
+    /// Set of object names in the container.
+    fn index(&self) -> String {
+        format!("{}:objects", self.base)
+    }
+
+    fn meta(&self, name: &str) -> String {
+        format!("{}:meta:{name}", self.base)
+    }
+
+    fn chunk(&self, name: &str, index: u64) -> String {
+        format!("{}:chunk:{name}:{index}", self.base)
+    }
+
+    /// Matches every key in the namespace except the container marker.
+    fn pattern(&self) -> String {
+        let mut pattern = String::with_capacity(self.base.len() + 2);
+        for c in self.base.chars() {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern + ":*"
+    }
+}
+
+/// Object metadata hash.
+#[derive(Debug, PartialEq, Eq)]
+struct ObjectMeta {
+    size: u64,
+    created_at: u64,
+    chunks: u64,
+    /// Chunk size at write time, so reads survive a configuration change.
+    chunk_size: u64,
+}
+
+impl ObjectMeta {
+    async fn load(conn: &mut ConnectionManager, key: &str) -> anyhow::Result<Option<Self>> {
+        let hash: HashMap<String, u64> =
+            conn.hgetall(key).await.with_context(|| format!("reading object metadata {key}"))?;
+        if hash.is_empty() {
+            return Ok(None);
+        }
+
+        let field = |name: &str| {
+            hash.get(name).copied().ok_or_else(|| anyhow!("object metadata {key} lacks {name}"))
+        };
+        Ok(Some(Self {
+            size: field("size")?,
+            created_at: field("created_at")?,
+            chunks: field("chunks")?,
+            chunk_size: field("chunk_size")?,
+        }))
+    }
+
+    const fn fields(&self) -> [(&'static str, u64); 4] {
+        [
+            ("size", self.size),
+            ("created_at", self.created_at),
+            ("chunks", self.chunks),
+            ("chunk_size", self.chunk_size),
+        ]
+    }
+}
+
+/// The chunks covering a byte range, and where the range sits within them.
+#[derive(Debug, PartialEq, Eq)]
+struct Span {
+    first: u64,
+    last: u64,
+    /// Offset of the first requested byte within the first chunk.
+    offset: usize,
+    len: usize,
+}
+
+impl Span {
+    /// Resolve `start..=end` against an object of `size` bytes. An `end` of 0
+    /// or `u64::MAX` reads to the end of the object. Returns `None` when the
+    /// range selects no bytes.
+    fn new(size: u64, chunk_size: u64, start: u64, end: u64) -> anyhow::Result<Option<Self>> {
+        let unbounded = end == 0 || end == u64::MAX;
+        if !unbounded && end < start {
+            bail!("invalid byte range: end ({end}) < start ({start})");
+        }
+        if chunk_size == 0 {
+            bail!("invalid object metadata: chunk size is 0");
+        }
+
+        let to = if unbounded { size } else { end.saturating_add(1).min(size) };
+        if start >= to {
+            return Ok(None);
+        }
+        let first = start / chunk_size;
+
+        Ok(Some(Self {
+            first,
+            last: (to - 1) / chunk_size,
+            offset: usize::try_from(start - first * chunk_size)?,
+            len: usize::try_from(to - start)?,
+        }))
+    }
+}
+
+fn now_unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const fn span(first: u64, last: u64, offset: usize, len: usize) -> Span {
+        Span {
+            first,
+            last,
+            offset,
+            len,
+        }
+    }
+
+    #[test]
+    fn full_read() {
+        assert_eq!(Span::new(10, 4, 0, 0).unwrap(), Some(span(0, 2, 0, 10)));
+        assert_eq!(Span::new(10, 4, 0, u64::MAX).unwrap(), Some(span(0, 2, 0, 10)));
+    }
+
+    #[test]
+    fn range_within_one_chunk() {
+        assert_eq!(Span::new(10, 4, 5, 6).unwrap(), Some(span(1, 1, 1, 2)));
+    }
+
+    #[test]
+    fn range_across_chunks() {
+        assert_eq!(Span::new(10, 4, 3, 8).unwrap(), Some(span(0, 2, 3, 6)));
+    }
+
+    #[test]
+    fn end_clamped_to_size() {
+        assert_eq!(Span::new(10, 4, 8, 100).unwrap(), Some(span(2, 2, 0, 2)));
+    }
+
+    #[test]
+    fn empty_selection() {
+        assert_eq!(Span::new(0, 4, 0, 0).unwrap(), None);
+        assert_eq!(Span::new(10, 4, 10, 20).unwrap(), None);
+    }
+
+    #[test]
+    fn nested_container_names() {
+        let (outer, inner) = (Keys::new("a"), Keys::new("a:b"));
+        let outer_prefix = outer.pattern().trim_end_matches('*').to_owned();
+        for key in [inner.container(), inner.index(), inner.meta("x"), inner.chunk("x", 0)] {
+            assert!(!key.starts_with(&outer_prefix), "{key} is inside container a");
+        }
+        assert!(outer.meta("b").starts_with(&outer_prefix));
+    }
+
+    #[test]
+    fn pattern_escapes_glob() {
+        assert_eq!(Keys::new("a*[b]").pattern(), r"omnia:blob:5:a\*\[b\]:*");
+    }
+
+    #[test]
+    fn end_before_start() {
+        let err = Span::new(10, 4, 5, 2).unwrap_err();
+        assert!(err.to_string().contains("invalid byte range"), "{err}");
+    }
+}
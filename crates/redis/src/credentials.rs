@@ -0,0 +1,79 @@
+//! Rotating credentials for token-based Redis authentication.
+use std::fmt::Debug;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use futures::{Stream, stream};
+use redis::{BasicAuth, ErrorKind, RedisError, RedisResult, StreamingCredentialsProvider};
+
+type FetchToken = dyn Fn() -> BoxFuture<'static, anyhow::Result<String>> + Send + Sync;
+
+/// A [`StreamingCredentialsProvider`] that presents a periodically refreshed
+/// token (e.g. from an identity backend) as the ACL password.
+///
+/// The first token is fetched as soon as the connection subscribes; later
+/// tokens are fetched every `refresh` interval and re-authenticate the live
+/// connection.
+#[derive(Clone)]
+pub struct TokenCredentials {
+    username: String,
+    refresh: Duration,
+    fetch: Arc<FetchToken>,
+}
+
+impl Debug for TokenCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenCredentials")
+            .field("username", &self.username)
+            .field("refresh", &self.refresh)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TokenCredentials {
+    /// Authenticate as `username` with tokens returned by `fetch`, refreshed
+    /// every `refresh`.
+    ///
+    /// `refresh` should be comfortably shorter than the token lifetime.
+    pub fn new<F>(username: impl Into<String>, refresh: Duration, fetch: F) -> Self
+    where
+        F: Fn() -> BoxFuture<'static, anyhow::Result<String>> + Send + Sync + 'static,
+    {
+        Self {
+            username: username.into(),
+            refresh,
+            fetch: Arc::new(fetch),
+        }
+    }
+}
+
+impl StreamingCredentialsProvider for TokenCredentials {
+    fn subscribe(&self) -> Pin<Box<dyn Stream<Item = RedisResult<BasicAuth>> + Send + 'static>> {
+        let provider = self.clone();
+
+        let credentials = stream::unfold(true, move |first| {
+            let provider = provider.clone();
+            async move {
+                if !first {
+                    tokio::time::sleep(provider.refresh).await;
+                }
+                let auth = (provider.fetch)()
+                    .await
+                    .map(|token| BasicAuth::new(provider.username.clone(), token))
+                    .map_err(|e| {
+                        tracing::warn!("failed to refresh redis credentials: {e:#}");
+                        RedisError::from((
+                            ErrorKind::AuthenticationFailed,
+                            "failed to fetch redis token",
+                            format!("{e:#}"),
+                        ))
+                    });
+                Some((auth, false))
+            }
+        });
+
+        Box::pin(credentials)
+    }
+}
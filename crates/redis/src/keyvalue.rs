@@ -4,9 +4,10 @@ use std::sync::Arc;
 
 use anyhow::Context;
 use futures::FutureExt;
+use omnia_kv_registry::intern;
 use omnia_wasi_keyvalue::{Bucket, FutureResult, WasiKeyValueCtx};
-use redis::AsyncCommands;
 use redis::aio::ConnectionManager;
+use redis::{AsyncCommands, CommandCacheConfig};
 
 use crate::Client;
 
@@ -16,16 +17,22 @@ const TTL_DAY: u64 = 24 * 60 * 60; // 1 day
 impl WasiKeyValueCtx for Client {
     fn open_bucket(&self, identifier: String) -> FutureResult<Arc<dyn Bucket>> {
         tracing::trace!("opening redis bucket: {}", identifier);
-        let conn = self.0.clone();
-
-        async move {
-            let bucket = RedisBucket {
-                identifier,
-                conn: Conn(conn.clone()),
-            };
-            Ok(Arc::new(bucket) as Arc<dyn Bucket>)
+        if let Some(bucket) = self.buckets.get(&identifier) {
+            return async move { Ok(bucket) }.boxed();
         }
-        .boxed()
+
+        let name = match intern(&identifier) {
+            Ok(name) => name,
+            Err(e) => return async move { Err(e) }.boxed(),
+        };
+        let bucket = RedisBucket {
+            identifier: identifier.clone(),
+            name,
+            conn: Conn(self.conn.clone()),
+            cached: self.cached.contains(&identifier),
+        };
+        let bucket = self.buckets.insert(&identifier, Arc::new(bucket));
+        async move { Ok(bucket) }.boxed()
     }
 }
 
@@ -43,20 +50,33 @@ impl Debug for Conn {
 pub struct RedisBucket {
     /// Bucket identifier used as key prefix.
     pub identifier: String,
+    /// Interned bucket name.
+    pub name: &'static str,
     /// Redis connection.
     pub conn: Conn,
+    /// Whether reads go through the client-side cache.
+    pub cached: bool,
 }
 
 impl Bucket for RedisBucket {
     fn name(&self) -> &'static str {
-        Box::leak(self.identifier.clone().into_boxed_str())
+        self.name
     }
 
     fn get(&self, key: String) -> FutureResult<Option<Vec<u8>>> {
         let key = format!("{}:{key}", self.identifier);
         let mut conn = self.conn.0.clone();
+        let cached = self.cached;
+
         async move {
-            conn.get(key.clone()).await.with_context(|| format!("failed to get value for {key}"))
+            let mut cmd = redis::cmd("GET");
+            cmd.arg(&key);
+            if cached {
+                cmd.set_cache_config(CommandCacheConfig::new());
+            }
+            cmd.query_async(&mut conn)
+                .await
+                .with_context(|| format!("failed to get value for {key}"))
         }
         .boxed()
     }
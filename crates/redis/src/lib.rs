@@ -1,18 +1,46 @@
 #![doc = include_str!("../README.md")]
 
+mod blobstore;
+mod credentials;
 mod keyvalue;
+mod messaging;
 
+use std::collections::HashSet;
 use std::fmt::Debug;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use omnia::Backend;
+use omnia_kv_registry::Buckets;
 use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+pub use redis::caching::CacheStatistics;
+use redis::caching::{CacheConfig, CacheMode};
+pub use redis::{BasicAuth, StreamingCredentialsProvider};
+use redis::{
+    ClientTlsConfig, ConnectionAddr, IntoConnectionInfo, ProtocolVersion, TlsCertificates,
+};
 use tracing::instrument;
 
-/// Redis key-value backend client.
+pub use crate::credentials::TokenCredentials;
+
+/// Redis backend client for key-value, messaging, and blobstore.
 #[derive(Clone)]
-pub struct Client(ConnectionManager);
+pub struct Client {
+    conn: ConnectionManager,
+    /// Used to open dedicated connections for blocking stream reads.
+    redis: redis::Client,
+    config: ConnectionManagerConfig,
+    streams: Option<Arc<StreamOptions>>,
+    /// Consumer name within the group, also used to name its reply stream.
+    consumer: String,
+    /// Blob chunk size in bytes.
+    chunk_size: usize,
+    /// Buckets whose reads go through the client-side cache.
+    cached: Arc<HashSet<String>>,
+    /// Opened key-value buckets, shared by clones of this client.
+    buckets: Arc<Buckets>,
+}
 
 impl Debug for Client {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -25,30 +53,151 @@ impl Backend for Client {
 
     #[instrument(name = "Redis::connect_with")]
     async fn connect_with(options: Self::ConnectOptions) -> Result<Self> {
-        let client =
-            redis::Client::open(options.url.clone()).context("failed to create redis client")?;
-        let config = ConnectionManagerConfig::new()
-            .set_number_of_retries(options.max_retries)
-            .set_max_delay(Duration::from_millis(options.max_delay));
+        let config = manager_config(&options);
+        connect(&options, config).await
+    }
+}
 
-        let conn = client
-            .get_connection_manager_with_config(config)
-            .await
-            .context("issue getting redis connection")?;
+impl Client {
+    /// Connect with explicit options, authenticating with credentials streamed
+    /// from `provider`.
+    ///
+    /// The provider is subscribed to once the connection is established; each
+    /// credential it yields re-authenticates the underlying
+    /// [`ConnectionManager`], so rotating tokens (e.g. Entra ID) are picked up
+    /// without reconnecting. Credentials from the provider take precedence over
+    /// `REDIS_USERNAME` / `REDIS_PASSWORD`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the TLS material cannot be loaded or the connection
+    /// cannot be established.
+    #[instrument(name = "Redis::connect_with_credentials", skip(provider))]
+    pub async fn connect_with_credentials<P>(options: ConnectOptions, provider: P) -> Result<Self>
+    where
+        P: StreamingCredentialsProvider + 'static,
+    {
+        let config = manager_config(&options).set_credentials_provider(provider);
+        connect(&options, config).await
+    }
 
-        tracing::info!("connected to redis");
-        Ok(Self(conn))
+    /// Hit, miss, and invalidation counts for the client-side cache, or `None`
+    /// when caching is not enabled.
+    ///
+    /// Counts cover every cached bucket read through this client.
+    #[must_use]
+    pub fn cache_statistics(&self) -> Option<CacheStatistics> {
+        self.conn.get_cache_statistics()
     }
 }
 
+fn manager_config(options: &ConnectOptions) -> ConnectionManagerConfig {
+    let config = ConnectionManagerConfig::new()
+        .set_number_of_retries(options.max_retries)
+        .set_max_delay(Duration::from_millis(options.max_delay));
+
+    // only commands from cached buckets opt in to the cache
+    let Some(cache) = &options.cache else {
+        return config;
+    };
+    config.set_cache_config(
+        CacheConfig::new()
+            .set_mode(CacheMode::OptIn)
+            .set_size(cache.size)
+            .set_default_client_ttl(Duration::from_secs(cache.ttl)),
+    )
+}
+
+async fn connect(options: &ConnectOptions, config: ConnectionManagerConfig) -> Result<Client> {
+    let redis = client(options)?;
+    let conn = redis
+        .get_connection_manager_with_config(config.clone())
+        .await
+        .context("issue getting redis connection")?;
+
+    // HOSTNAME survives restarts, so a restarted replica picks up its own
+    // pending entries
+    let consumer = options
+        .streams
+        .as_ref()
+        .and_then(|s| s.consumer.clone())
+        .or_else(|| std::env::var("HOSTNAME").ok().filter(|h| !h.is_empty()))
+        .unwrap_or_else(|| {
+            let consumer = process_consumer();
+            if options.streams.is_some() {
+                tracing::warn!(
+                    "neither REDIS_CONSUMER_NAME nor HOSTNAME is set; consuming as {consumer}, \
+                     whose pending entries a restarted process cannot resume"
+                );
+            }
+            consumer
+        });
+
+    tracing::info!("connected to redis");
+    Ok(Client {
+        conn,
+        redis,
+        config,
+        streams: options.streams.clone().map(Arc::new),
+        consumer,
+        chunk_size: options.chunk_size.max(1),
+        cached: Arc::new(cached_buckets(options)),
+        buckets: Arc::default(),
+    })
+}
+
+/// Buckets whose reads go through the client-side cache; none when caching
+/// is not enabled.
+fn cached_buckets(options: &ConnectOptions) -> HashSet<String> {
+    options.cache.as_ref().map(|c| c.buckets.iter().cloned().collect()).unwrap_or_default()
+}
+
+/// A consumer name unique to this process, so replicas never share a
+/// consumer or its reply stream.
+fn process_consumer() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+    format!("omnia-{:x}-{nanos:x}", std::process::id())
+}
+
+/// Build a [`redis::Client`] from the URL, applying ACL credentials and any
+/// custom TLS material.
+fn client(options: &ConnectOptions) -> Result<redis::Client> {
+    let mut info = options.url.as_str().into_connection_info().context("invalid redis url")?;
+
+    let mut settings = info.redis_settings().clone();
+    if let Some(username) = &options.username {
+        settings = settings.set_username(username);
+    }
+    if let Some(password) = &options.password {
+        settings = settings.set_password(password);
+    }
+    // invalidation pushes for the client-side cache require RESP3
+    if options.cache.is_some() {
+        settings = settings.set_protocol(ProtocolVersion::RESP3);
+    }
+    info = info.set_redis_settings(settings);
+
+    let Some(certificates) = options.tls.certificates()? else {
+        return redis::Client::open(info).context("failed to create redis client");
+    };
+    if !matches!(info.addr(), ConnectionAddr::TcpTls { .. }) {
+        bail!("redis TLS certificates are configured but the url does not use `rediss://`");
+    }
+    redis::Client::build_with_tls(info, certificates).context("failed to create redis tls client")
+}
+
 #[allow(missing_docs)]
 mod config {
-    use fromenv::FromEnv;
+    use std::num::NonZeroUsize;
+    use std::str::FromStr;
+
+    use anyhow::bail;
+    use fromenv::{FromEnv, ParseResult};
 
     /// Connection options for the Redis backend.
-    #[derive(Debug, Clone, FromEnv)]
+    #[derive(Clone, FromEnv)]
     pub struct ConnectOptions {
-        /// Redis connection URL.
+        /// Redis connection URL. Use the `rediss://` scheme to connect over TLS.
         #[env(from = "REDIS_URL", default = "redis://localhost:6379")]
         pub url: String,
         /// Maximum number of reconnection retries.
@@ -57,12 +206,262 @@ mod config {
         /// Maximum backoff delay in milliseconds.
         #[env(from = "REDIS_MAX_DELAY", default = "1000")]
         pub max_delay: u64,
+        /// ACL username. Overrides any username in the URL.
+        #[env(from = "REDIS_USERNAME")]
+        pub username: Option<String>,
+        /// ACL password. Overrides any password in the URL.
+        #[env(from = "REDIS_PASSWORD")]
+        pub password: Option<String>,
+        /// Size in bytes of the chunks blobs are split into.
+        #[env(from = "REDIS_BLOB_CHUNK_SIZE", default = "524288")]
+        pub chunk_size: usize,
+        /// Custom TLS material.
+        #[env(nested)]
+        pub tls: TlsOptions,
+        /// Optional Redis Streams consumer configuration.
+        #[env(nested)]
+        pub streams: Option<StreamOptions>,
+        /// Optional client-side cache configuration.
+        #[env(nested)]
+        pub cache: Option<CacheOptions>,
+    }
+
+    impl std::fmt::Debug for ConnectOptions {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("ConnectOptions")
+                .field("url", &self.url)
+                .field("max_retries", &self.max_retries)
+                .field("max_delay", &self.max_delay)
+                .field("username", &self.username)
+                .field("password", &self.password.as_ref().map(|_| "[REDACTED]"))
+                .field("chunk_size", &self.chunk_size)
+                .field("tls", &self.tls)
+                .field("streams", &self.streams)
+                .field("cache", &self.cache)
+                .finish()
+        }
     }
+
+    /// TLS material for `rediss://` connections.
+    ///
+    /// When none is set, the server certificate is verified against the
+    /// platform's native trust store.
+    #[derive(Debug, Clone, Default, FromEnv)]
+    pub struct TlsOptions {
+        /// Path to a PEM bundle of CA certificates used to verify the server.
+        #[env(from = "REDIS_TLS_CA_FILE")]
+        pub ca_cert: Option<String>,
+        /// Path to a PEM client certificate for mutual TLS.
+        #[env(from = "REDIS_TLS_CERT_FILE")]
+        pub client_cert: Option<String>,
+        /// Path to the PEM private key for the client certificate.
+        #[env(from = "REDIS_TLS_KEY_FILE")]
+        pub client_key: Option<String>,
+    }
+
+    /// Redis Streams consumer configuration for `wasi-messaging` subscriptions.
+    #[derive(Debug, Clone, FromEnv)]
+    pub struct StreamOptions {
+        /// Comma-separated streams to consume.
+        #[env(from = "REDIS_STREAMS", with = split)]
+        pub streams: Vec<String>,
+        /// Consumer group shared by all replicas; created when missing.
+        #[env(from = "REDIS_CONSUMER_GROUP", default = "omnia")]
+        pub group: String,
+        /// Consumer name within the group. Defaults to `HOSTNAME`, or a name
+        /// unique to the process when that is unset.
+        #[env(from = "REDIS_CONSUMER_NAME")]
+        pub consumer: Option<String>,
+        /// When entries are acknowledged.
+        #[env(from = "REDIS_STREAM_ACK", default = "delivery", with = parse_enum)]
+        pub ack: AckMode,
+        /// Idle time in milliseconds after which unacknowledged entries are
+        /// claimed and delivered again.
+        #[env(from = "REDIS_CLAIM_IDLE", default = "30000")]
+        pub claim_idle: u64,
+        /// Idle time in milliseconds after which a consumer with no pending
+        /// entries is removed from the group. 0 keeps consumers.
+        #[env(from = "REDIS_CONSUMER_EXPIRY", default = "3600000")]
+        pub consumer_expiry: u64,
+        /// Maximum entries fetched per read.
+        #[env(from = "REDIS_STREAM_BATCH", default = "100")]
+        pub batch_size: usize,
+        /// Milliseconds a read blocks waiting for new entries.
+        #[env(from = "REDIS_STREAM_BLOCK", default = "5000")]
+        pub block: usize,
+    }
+
+    /// When stream entries are acknowledged.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum AckMode {
+        /// Acknowledge once the entry has been handed to the host.
+        Delivery,
+        /// The guest acknowledges each entry by sending to the topic in its
+        /// `ack-topic` metadata once handled; entries left unacknowledged are
+        /// delivered again.
+        Explicit,
+    }
+
+    impl FromStr for AckMode {
+        type Err = anyhow::Error;
+
+        fn from_str(s: &str) -> anyhow::Result<Self> {
+            match s {
+                "explicit" => Ok(Self::Explicit),
+                "delivery" => Ok(Self::Delivery),
+                _ => bail!("invalid ack mode {s:?}: expected explicit or delivery"),
+            }
+        }
+    }
+
+    /// Server-assisted client-side caching for key-value reads.
+    ///
+    /// Cached values are invalidated by RESP3 `CLIENT TRACKING` pushes when
+    /// the key changes on the server.
+    #[derive(Debug, Clone, FromEnv)]
+    pub struct CacheOptions {
+        /// Comma-separated buckets whose reads are cached.
+        #[env(from = "REDIS_CACHE_BUCKETS", with = split)]
+        pub buckets: Vec<String>,
+        /// Maximum number of cached keys across all buckets.
+        #[env(from = "REDIS_CACHE_SIZE", default = "10000")]
+        pub size: NonZeroUsize,
+        /// Maximum seconds a value stays cached, whatever the key's server TTL.
+        #[env(from = "REDIS_CACHE_TTL", default = "60")]
+        pub ttl: u64,
+    }
+
+    // The default `FromEnv` parser needs a `std::error::Error`, which
+    // `anyhow::Error` is not.
+    fn parse_enum<T: FromStr<Err = anyhow::Error>>(s: &str) -> ParseResult<T> {
+        Ok(s.parse()?)
+    }
+
+    // The `FromEnv` `with =` hook requires a `ParseResult` return type.
+    #[allow(clippy::unnecessary_wraps)]
+    pub fn split(s: &str) -> ParseResult<Vec<String>> {
+        Ok(s.split(',').map(str::trim).filter(|v| !v.is_empty()).map(ToOwned::to_owned).collect())
+    }
+}
+pub use config::{AckMode, CacheOptions, ConnectOptions, StreamOptions, TlsOptions};
+
+impl TlsOptions {
+    /// Load the configured TLS material, or `None` when no custom material is
+    /// set.
+    fn certificates(&self) -> Result<Option<TlsCertificates>> {
+        let client_tls = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some(ClientTlsConfig {
+                client_cert: read_pem(cert)?,
+                client_key: read_pem(key)?,
+            }),
+            (None, None) => None,
+            _ => bail!("REDIS_TLS_CERT_FILE and REDIS_TLS_KEY_FILE must be set together"),
+        };
+        let root_cert = self.ca_cert.as_deref().map(read_pem).transpose()?;
+
+        if client_tls.is_none() && root_cert.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(TlsCertificates {
+            client_tls,
+            root_cert,
+        }))
+    }
+}
+
+fn read_pem(path: &str) -> Result<Vec<u8>> {
+    std::fs::read(path).with_context(|| format!("failed to read PEM file {path}"))
 }
-pub use config::ConnectOptions;
 
 impl omnia::FromEnv for ConnectOptions {
     fn from_env() -> Result<Self> {
         Self::from_env().finalize().context("issue loading connection options")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+
+    fn options(cache: Option<CacheOptions>) -> ConnectOptions {
+        ConnectOptions {
+            url: "redis://localhost:6379".to_owned(),
+            max_retries: 3,
+            max_delay: 1000,
+            username: None,
+            password: None,
+            chunk_size: 524_288,
+            tls: TlsOptions::default(),
+            streams: None,
+            cache,
+        }
+    }
+
+    fn cache(buckets: &[&str]) -> CacheOptions {
+        CacheOptions {
+            buckets: buckets.iter().map(ToString::to_string).collect(),
+            size: NonZeroUsize::new(100).unwrap(),
+            ttl: 30,
+        }
+    }
+
+    #[test]
+    fn cache_bucket_lists() {
+        assert_eq!(config::split("sessions, users,").unwrap(), ["sessions", "users"]);
+        assert_eq!(config::split("").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn cache_defaults() {
+        let cache =
+            CacheOptions::from_env().buckets(vec!["sessions".to_owned()]).finalize().unwrap();
+        assert_eq!(cache.size.get(), 10_000);
+        assert_eq!(cache.ttl, 60);
+        "0".parse::<NonZeroUsize>().unwrap_err();
+    }
+
+    #[test]
+    fn cached_bucket_selection() {
+        let cached = cached_buckets(&options(Some(cache(&["sessions", "users"]))));
+        assert!(cached.contains("sessions"));
+        assert!(cached.contains("users"));
+        assert!(!cached.contains("orders"));
+
+        assert_eq!(cached_buckets(&options(None)), HashSet::new());
+        assert_eq!(cached_buckets(&options(Some(cache(&[])))), HashSet::new());
+    }
+
+    #[test]
+    fn cache_uses_resp3() {
+        let protocol =
+            |options| client(&options).unwrap().get_connection_info().redis_settings().protocol();
+        assert_eq!(protocol(options(Some(cache(&["sessions"])))), ProtocolVersion::RESP3);
+        assert_eq!(protocol(options(None)), ProtocolVersion::RESP2);
+    }
+
+    #[test]
+    fn no_tls_material() {
+        assert!(TlsOptions::default().certificates().unwrap().is_none());
+    }
+
+    #[test]
+    fn client_cert_without_key() {
+        let tls = TlsOptions {
+            client_cert: Some("client.pem".to_owned()),
+            ..TlsOptions::default()
+        };
+        let Err(err) = tls.certificates() else { panic!("expected error") };
+        assert!(err.to_string().contains("must be set together"), "{err}");
+    }
+
+    #[test]
+    fn missing_ca_file() {
+        let tls = TlsOptions {
+            ca_cert: Some("/nonexistent/ca.pem".to_owned()),
+            ..TlsOptions::default()
+        };
+        assert!(tls.certificates().is_err());
+    }
+}
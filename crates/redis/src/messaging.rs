@@ -0,0 +1,533 @@
+//! Messaging implementation for the Redis backend, built on Redis Streams.
+//!
+//! `send` appends an entry with `XADD`: the payload is stored in the `payload`
+//! field and each metadata pair becomes a field of its own. Subscriptions read
+//! the configured streams through a consumer group with `XREADGROUP`, and
+//! periodically claim entries left pending for too long with `XAUTOCLAIM`.
+//!
+//! By default each entry is acknowledged once it has been handed to the host.
+//! The host does not report when a guest has handled a message, so in
+//! [`AckMode::Explicit`] each entry instead carries an `ack-topic` the guest
+//! sends to once done; `send` turns that into an `XACK`. Entries the guest
+//! never acknowledges, because it failed or sent `-NAK`, stay pending and are
+//! claimed and delivered again.
+//!
+//! Request/reply uses a per-consumer reply stream. The reply topic handed to
+//! the responder carries a correlation id after [`CORRELATION_SEP`], which
+//! `send` moves into the reply entry so concurrent requests can share the
+//! stream.
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context as _, anyhow, bail};
+use futures::Stream;
+use futures::future::FutureExt;
+use futures::task::{Context, Poll};
+use omnia_wasi_messaging::{
+    Client, FutureResult, Message, Metadata, Reply, RequestOptions, Subscriptions, WasiMessagingCtx,
+};
+use redis::AsyncCommands;
+use redis::aio::ConnectionManager;
+use redis::streams::{
+    StreamAutoClaimOptions, StreamAutoClaimReply, StreamId, StreamInfoConsumersReply, StreamMaxlen,
+    StreamReadOptions,
+};
+use tokio::sync::mpsc;
+
+use crate::{AckMode, StreamOptions};
+
+const CAPACITY: usize = 1024;
+
+/// Stream entry field holding the message payload.
+const PAYLOAD: &str = "payload";
+/// Stream entry field holding the topic a reply should be sent to.
+const REPLY_TO: &str = "reply-to";
+/// Stream entry field correlating a reply with its request.
+const CORRELATION_ID: &str = "correlation-id";
+/// Metadata key exposing the stream entry id to the guest.
+const STREAM_ID: &str = "stream-id";
+/// Metadata key holding the topic that acknowledges the entry.
+const ACK_TOPIC: &str = "ack-topic";
+
+/// Prefix of ack topics, followed by the stream and entry id.
+const ACK_PREFIX: &str = "omnia:ack:";
+/// Payload of a send to an ack topic that leaves the entry pending.
+const NAK: &[u8] = b"-NAK";
+
+/// Key prefix of per-client reply streams.
+const REPLY_PREFIX: &str = "omnia:reply:";
+/// Separates the reply stream from the correlation id in a reply topic.
+const CORRELATION_SEP: char = '#';
+/// Approximate cap on reply stream length, so unread replies cannot grow it
+/// without bound.
+const REPLY_MAXLEN: usize = 1000;
+/// Seconds a reply stream outlives its last reply, so streams of consumers
+/// that have gone away are removed.
+const REPLY_TTL: i64 = 300;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLY_POLL: Duration = Duration::from_millis(25);
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// `wasi-messaging` implementation backed by Redis Streams.
+impl WasiMessagingCtx for crate::Client {
+    fn connect(&self) -> FutureResult<Arc<dyn Client>> {
+        let client = self.clone();
+        async move { Ok(Arc::new(client) as Arc<dyn Client>) }.boxed()
+    }
+}
+
+impl crate::Client {
+    fn reply_stream(&self) -> String {
+        format!("{REPLY_PREFIX}{}", self.consumer)
+    }
+}
+
+/// Translate a stream entry into the host's [`Message`].
+fn from_redis(stream: &str, entry: &StreamId) -> Message {
+    let mut md = HashMap::new();
+    for field in entry.map.keys() {
+        if field == PAYLOAD || field == REPLY_TO {
+            continue;
+        }
+        if let Some(value) = entry.get::<String>(field) {
+            md.insert(field.clone(), value);
+        }
+    }
+    md.insert(STREAM_ID.to_owned(), entry.id.clone());
+    let description = md.get("description").cloned();
+
+    let mut message = Message::new(entry.get(PAYLOAD).unwrap_or_default());
+    stream.clone_into(&mut message.topic);
+    message.metadata = Some(Metadata { inner: md });
+    message.description = description;
+    message.reply = entry.get::<String>(REPLY_TO).map(|topic| Reply {
+        client_name: String::new(),
+        topic,
+    });
+    message
+}
+
+/// Build the field/value pairs of a stream entry for `message`.
+fn fields(message: Message) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    let mut fields = vec![(PAYLOAD.to_owned(), message.payload)];
+    for (k, v) in message.metadata.unwrap_or_default().inner {
+        if [PAYLOAD, REPLY_TO, CORRELATION_ID].contains(&k.as_str()) {
+            bail!("metadata key '{k}' is reserved");
+        }
+        // describe the entry a message was delivered from, not the one sent
+        if [STREAM_ID, ACK_TOPIC].contains(&k.as_str()) {
+            continue;
+        }
+        fields.push((k, v.into_bytes()));
+    }
+    Ok(fields)
+}
+
+impl Client for crate::Client {
+    fn subscribe(&self) -> FutureResult<Subscriptions> {
+        let client = self.clone();
+
+        async move {
+            let Some(options) = client.streams.clone() else {
+                return Err(anyhow!("No topics specified"));
+            };
+
+            // XREADGROUP blocks its connection, so consume on a dedicated one
+            let config = client.config.clone().set_response_timeout(None);
+            let mut conn = client
+                .redis
+                .get_connection_manager_with_config(config)
+                .await
+                .context("issue getting redis consumer connection")?;
+
+            for stream in &options.streams {
+                let created: redis::RedisResult<()> =
+                    conn.xgroup_create_mkstream(stream, &options.group, "$").await;
+                if let Err(e) = created
+                    && e.code() != Some("BUSYGROUP")
+                {
+                    return Err(e).context(format!("failed to create consumer group for {stream}"));
+                }
+            }
+            tracing::info!("subscribed to {:?} streams as {}", options.streams, client.consumer);
+
+            let (sender, receiver) = mpsc::channel::<Message>(CAPACITY);
+            let consumer = Consumer {
+                conn,
+                options,
+                name: client.consumer,
+                sender,
+            };
+            tokio::spawn(consumer.run());
+
+            Ok(Box::pin(Subscriber { receiver }) as Subscriptions)
+        }
+        .boxed()
+    }
+
+    fn send(&self, topic: String, message: Message) -> FutureResult<()> {
+        let mut conn = self.conn.clone();
+        let streams = self.streams.clone();
+
+        async move {
+            if let Some((stream, id)) = ack_target(&topic) {
+                let Some(options) = streams else {
+                    bail!("cannot acknowledge {topic}: no consumer group configured");
+                };
+                if message.payload == NAK {
+                    tracing::debug!("entry {id} on {stream} left pending for redelivery");
+                    return Ok(());
+                }
+                let _: usize = conn
+                    .xack(stream, &options.group, &[id])
+                    .await
+                    .with_context(|| format!("failed to ack entry {id} on {stream}"))?;
+                return Ok(());
+            }
+
+            let mut fields = fields(message)?;
+
+            // replies to a request carry the correlation id in the topic
+            let reply = topic
+                .split_once(CORRELATION_SEP)
+                .filter(|(stream, _)| stream.starts_with(REPLY_PREFIX));
+            if let Some((stream, correlation)) = reply {
+                fields.push((CORRELATION_ID.to_owned(), correlation.as_bytes().to_vec()));
+                let () = redis::pipe()
+                    .xadd_maxlen(stream, StreamMaxlen::Approx(REPLY_MAXLEN), "*", &fields)
+                    .ignore()
+                    .expire(stream, REPLY_TTL)
+                    .ignore()
+                    .query_async(&mut conn)
+                    .await
+                    .with_context(|| format!("failed to send reply to {stream}"))?;
+                return Ok(());
+            }
+
+            let _: Option<String> = conn
+                .xadd(&topic, "*", &fields)
+                .await
+                .with_context(|| format!("failed to send to {topic}"))?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn request(
+        &self, topic: String, message: Message, options: Option<RequestOptions>,
+    ) -> FutureResult<Message> {
+        let mut conn = self.conn.clone();
+        let reply_stream = self.reply_stream();
+
+        async move {
+            let correlation = correlation_id();
+            let mut fields = fields(message)?;
+            fields.push((
+                REPLY_TO.to_owned(),
+                format!("{reply_stream}{CORRELATION_SEP}{correlation}").into_bytes(),
+            ));
+
+            // replies are newer than the request, so its id is the read cursor
+            let sent: Option<String> = conn
+                .xadd(&topic, "*", &fields)
+                .await
+                .with_context(|| format!("failed to send request to {topic}"))?;
+            let mut cursor = sent.unwrap_or_else(|| "0-0".to_owned());
+
+            let timeout = options.and_then(|o| o.timeout).unwrap_or(REQUEST_TIMEOUT);
+            let reply = tokio::time::timeout(timeout, async {
+                // poll rather than block so the shared connection stays free
+                loop {
+                    let read: Option<redis::streams::StreamReadReply> = conn
+                        .xread_options(
+                            &[&reply_stream],
+                            &[&cursor],
+                            &StreamReadOptions::default().count(CAPACITY),
+                        )
+                        .await
+                        .context("failed to read reply stream")?;
+
+                    for entry in read.into_iter().flat_map(|r| r.keys).flat_map(|k| k.ids) {
+                        cursor.clone_from(&entry.id);
+                        if entry.get::<String>(CORRELATION_ID).as_deref() == Some(&correlation) {
+                            let _: usize = conn.xdel(&reply_stream, &[&entry.id]).await?;
+                            return anyhow::Ok(from_redis(&reply_stream, &entry));
+                        }
+                    }
+                    tokio::time::sleep(REPLY_POLL).await;
+                }
+            })
+            .await
+            .map_err(|_elapsed| anyhow!("request to {topic} timed out"))??;
+
+            Ok(reply)
+        }
+        .boxed()
+    }
+}
+
+/// Background task reading a consumer group and forwarding entries to the
+/// subscriber.
+struct Consumer {
+    conn: ConnectionManager,
+    options: Arc<StreamOptions>,
+    name: String,
+    sender: mpsc::Sender<Message>,
+}
+
+impl Consumer {
+    async fn run(mut self) {
+        let claim_idle = Duration::from_millis(self.options.claim_idle);
+        let read_options = StreamReadOptions::default()
+            .group(&self.options.group, &self.name)
+            .block(self.options.block)
+            .count(self.options.batch_size);
+        let ids = vec![">"; self.options.streams.len()];
+        let mut last_claim: Option<Instant> = None;
+
+        loop {
+            if last_claim.is_none_or(|t| t.elapsed() >= claim_idle) {
+                last_claim = Some(Instant::now());
+                if !self.claim(claim_idle).await {
+                    return;
+                }
+            }
+
+            let read: redis::RedisResult<Option<redis::streams::StreamReadReply>> =
+                self.conn.xread_options(&self.options.streams, &ids, &read_options).await;
+            let reply = match read {
+                Ok(reply) => reply,
+                Err(e) => {
+                    tracing::error!("redis stream read error: {e}");
+                    tokio::time::sleep(RETRY_DELAY).await;
+                    continue;
+                }
+            };
+
+            for key in reply.map(|r| r.keys).unwrap_or_default() {
+                for entry in &key.ids {
+                    if !self.deliver(&key.key, entry).await {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Claim entries left pending for longer than `min_idle`, whether by
+    /// another consumer or by a guest that did not acknowledge them, and
+    /// remove consumers that have been idle for too long. Returns `false` once
+    /// the subscriber has gone away.
+    async fn claim(&mut self, min_idle: Duration) -> bool {
+        let min_idle = u64::try_from(min_idle.as_millis()).unwrap_or(u64::MAX);
+
+        for stream in self.options.streams.clone() {
+            let mut start = "0-0".to_owned();
+            loop {
+                let claimed: redis::RedisResult<StreamAutoClaimReply> = self
+                    .conn
+                    .xautoclaim_options(
+                        &stream,
+                        &self.options.group,
+                        &self.name,
+                        min_idle,
+                        &start,
+                        StreamAutoClaimOptions::default().count(self.options.batch_size),
+                    )
+                    .await;
+                let reply = match claimed {
+                    Ok(reply) => reply,
+                    Err(e) => {
+                        tracing::error!("failed to claim pending entries on {stream}: {e}");
+                        break;
+                    }
+                };
+
+                for entry in &reply.claimed {
+                    tracing::debug!("claimed pending entry {} on {stream}", entry.id);
+                    if !self.deliver(&stream, entry).await {
+                        return false;
+                    }
+                }
+                if reply.next_stream_id == "0-0" {
+                    break;
+                }
+                start = reply.next_stream_id;
+            }
+            self.remove_idle(&stream).await;
+        }
+        true
+    }
+
+    /// Remove consumers with no pending entries that have been idle for longer
+    /// than the configured expiry. Their pending entries have already been
+    /// claimed, so only consumers that are gone for good are removed.
+    async fn remove_idle(&mut self, stream: &str) {
+        let expiry = self.options.consumer_expiry;
+        if expiry == 0 {
+            return;
+        }
+        let info: redis::RedisResult<StreamInfoConsumersReply> =
+            self.conn.xinfo_consumers(stream, &self.options.group).await;
+        let consumers = match info {
+            Ok(info) => info.consumers,
+            Err(e) => {
+                tracing::warn!("failed to list consumers on {stream}: {e}");
+                return;
+            }
+        };
+
+        for consumer in consumers {
+            let idle = u64::try_from(consumer.idle).unwrap_or(u64::MAX);
+            if consumer.name == self.name || consumer.pending > 0 || idle < expiry {
+                continue;
+            }
+            let removed: redis::RedisResult<usize> =
+                self.conn.xgroup_delconsumer(stream, &self.options.group, &consumer.name).await;
+            match removed {
+                Ok(_) => tracing::info!("removed idle consumer {} from {stream}", consumer.name),
+                Err(e) => tracing::warn!("failed to remove consumer {}: {e}", consumer.name),
+            }
+        }
+    }
+
+    /// Forward an entry to the subscriber, acknowledging it straight away in
+    /// [`AckMode::Delivery`]. Returns `false` once the subscriber has gone
+    /// away.
+    async fn deliver(&mut self, stream: &str, entry: &StreamId) -> bool {
+        let mut message = from_redis(stream, entry);
+        if self.options.ack == AckMode::Explicit {
+            message
+                .metadata
+                .get_or_insert_default()
+                .insert(ACK_TOPIC.to_owned(), format!("{ACK_PREFIX}{stream}#{}", entry.id));
+        }
+        if self.sender.send(message).await.is_err() {
+            tracing::debug!("subscriber dropped; stopping redis stream consumer");
+            return false;
+        }
+        if self.options.ack == AckMode::Explicit {
+            return true;
+        }
+
+        let acked: redis::RedisResult<usize> =
+            self.conn.xack(stream, &self.options.group, &[&entry.id]).await;
+        if let Err(e) = acked {
+            tracing::warn!("failed to ack entry {} on {stream}: {e}", entry.id);
+        }
+        true
+    }
+}
+
+/// The stream and entry id an ack topic refers to.
+fn ack_target(topic: &str) -> Option<(&str, &str)> {
+    topic.strip_prefix(ACK_PREFIX)?.rsplit_once('#')
+}
+
+/// Correlation ids must be unique within a reply stream, which is shared by
+/// every client with the same consumer name, including earlier runs of this
+/// process.
+fn correlation_id() -> String {
+    static PREFIX: LazyLock<String> = LazyLock::new(|| {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        format!("{:x}-{nanos:x}", std::process::id())
+    });
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    format!("{}-{:x}", *PREFIX, NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Async stream of entries forwarded from a background consumer task.
+#[derive(Debug)]
+pub struct Subscriber {
+    receiver: mpsc::Receiver<Message>,
+}
+
+impl Stream for Subscriber {
+    type Item = Message;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use redis::Value;
+
+    use super::*;
+
+    fn entry(fields: &[(&str, &str)]) -> StreamId {
+        StreamId {
+            id: "1-0".to_owned(),
+            map: fields
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), Value::BulkString(v.as_bytes().to_vec())))
+                .collect(),
+            ..StreamId::default()
+        }
+    }
+
+    #[test]
+    fn entry_to_message() {
+        let entry = entry(&[
+            ("payload", "hello"),
+            ("reply-to", "omnia:reply:a#1"),
+            ("description", "greeting"),
+            ("tenant", "acme"),
+        ]);
+        let message = from_redis("events", &entry);
+
+        assert_eq!(message.topic, "events");
+        assert_eq!(message.payload, b"hello");
+        assert_eq!(message.description.as_deref(), Some("greeting"));
+        assert_eq!(message.reply.map(|r| r.topic).as_deref(), Some("omnia:reply:a#1"));
+
+        let md = message.metadata.unwrap();
+        assert_eq!(md.get("tenant").map(String::as_str), Some("acme"));
+        assert_eq!(md.get(STREAM_ID).map(String::as_str), Some("1-0"));
+        assert!(!md.contains_key(PAYLOAD));
+    }
+
+    #[test]
+    fn reserved_metadata_rejected() {
+        let mut message = Message::new(b"hi".to_vec());
+        message.metadata = Some(Metadata {
+            inner: HashMap::from([(REPLY_TO.to_owned(), "x".to_owned())]),
+        });
+        let err = fields(message).unwrap_err();
+        assert!(err.to_string().contains("reserved"), "{err}");
+    }
+
+    #[test]
+    fn delivery_metadata_dropped() {
+        let mut message = Message::new(b"hi".to_vec());
+        message.metadata = Some(Metadata {
+            inner: HashMap::from([
+                (STREAM_ID.to_owned(), "1-0".to_owned()),
+                (ACK_TOPIC.to_owned(), "omnia:ack:orders#1-0".to_owned()),
+                ("tenant".to_owned(), "acme".to_owned()),
+            ]),
+        });
+        let names: Vec<String> = fields(message).unwrap().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(names, [PAYLOAD, "tenant"]);
+    }
+
+    #[test]
+    fn ack_topics() {
+        assert_eq!(ack_target("omnia:ack:orders#1-0"), Some(("orders", "1-0")));
+        assert_eq!(ack_target("omnia:ack:a#b#2-1"), Some(("a#b", "2-1")));
+        assert_eq!(ack_target("orders#1-0"), None);
+        assert_eq!(ack_target("omnia:ack:orders"), None);
+    }
+
+    #[test]
+    fn correlation_ids_are_unique() {
+        let (first, second) = (correlation_id(), correlation_id());
+        assert_ne!(first, second);
+        assert_eq!(first.rsplit_once('-').map(|(p, _)| p), second.rsplit_once('-').map(|(p, _)| p));
+    }
+}
@@ -1,20 +1,27 @@
-//! Live key-value round-trip for the Redis backend, driven through the
-//! `omnia:keyvalue` host boundary (`WasiKeyValueCtx`).
+//! Live key-value and blob round-trips and stream messaging for the Redis
+//! backend, driven through the `omnia:keyvalue`, `omnia:blobstore`, and
+//! `omnia:messaging` host boundaries.
 //!
 //! `#[ignore]`d so it never touches the network in CI. Run against a reachable
 //! Redis (`REDIS_URL`, default `redis://localhost:6379`):
 //! `cargo nextest run -p omnia-redis --run-ignored all`.
 
-use anyhow::Result;
-use omnia::Backend;
-use omnia_redis::Client;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use omnia::{Backend, FromEnv};
+use omnia_redis::{AckMode, Client, ConnectOptions, StreamOptions};
+use omnia_wasi_blobstore::{Bytes, WasiBlobstoreCtx};
 use omnia_wasi_keyvalue::{Bucket, WasiKeyValueCtx};
+use omnia_wasi_messaging::{Client as MessagingClient, Message, Subscriptions, WasiMessagingCtx};
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
 #[ignore = "live: needs a reachable Redis; run with --run-ignored"]
 async fn set_get_delete() -> Result<()> {
     let client = <Client as Backend>::connect().await?;
-    let bucket: std::sync::Arc<dyn Bucket> = client.open_bucket("omnia-live".to_owned()).await?;
+    let bucket: Arc<dyn Bucket> = client.open_bucket("omnia-live".to_owned()).await?;
 
     let key = unique("k");
     bucket.set(key.clone(), b"payload".to_vec()).await?;
@@ -26,6 +33,42 @@ async fn set_get_delete() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Redis; run with --run-ignored"]
+async fn blob_range_read() -> Result<()> {
+    let client = <Client as Backend>::connect().await?;
+    let container = client.create_container(unique("omnia-live")).await?;
+
+    container.write_data("obj".to_owned(), Bytes::from_static(b"0123456789")).await?;
+    assert_eq!(
+        container.get_data("obj".to_owned(), 3, 5).await?.as_deref(),
+        Some(b"345".as_slice())
+    );
+    assert_eq!(container.object_info("obj".to_owned()).await?.size, 10);
+
+    client.delete_container(container.name()?).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Redis; run with --run-ignored"]
+async fn delete_leaves_nested_container() -> Result<()> {
+    let client = <Client as Backend>::connect().await?;
+    let outer = unique("omnia-live");
+    let inner = format!("{outer}:inner");
+
+    client.create_container(outer.clone()).await?;
+    let nested = client.create_container(inner.clone()).await?;
+    nested.write_data("obj".to_owned(), Bytes::from_static(b"kept")).await?;
+
+    client.delete_container(outer).await?;
+    assert!(client.container_exists(inner.clone()).await?, "nested container survives");
+    assert!(nested.has_object("obj".to_owned()).await?, "nested object survives");
+
+    client.delete_container(inner).await?;
+    Ok(())
+}
+
 /// A collision-resistant suffix so parallel runs never share a live key.
 fn unique(prefix: &str) -> String {
     let nanos = std::time::SystemTime::now()
@@ -34,3 +77,81 @@ fn unique(prefix: &str) -> String {
         .as_nanos();
     format!("{prefix}-{}-{nanos}", std::process::id())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Redis; run with --run-ignored"]
+async fn appends_to_stream() -> Result<()> {
+    let client = <Client as Backend>::connect().await?;
+    let producer: Arc<dyn MessagingClient> = WasiMessagingCtx::connect(&client).await?;
+
+    producer.send(unique("omnia-live"), Message::new(b"omnia-live".to_vec())).await?;
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Redis; run with --run-ignored"]
+async fn unacked_entries_are_redelivered() -> Result<()> {
+    let stream = unique("omnia-live");
+    let (client, mut subscriptions) = subscribe(&stream).await?;
+
+    client.send(stream.clone(), Message::new(b"work".to_vec())).await?;
+    let first = next(&mut subscriptions).await?;
+    assert_eq!(first.payload, b"work");
+
+    // not acknowledged, so claimed and delivered again
+    let again = next(&mut subscriptions).await?;
+    assert_eq!(again.payload, b"work");
+
+    let ack = again.metadata.as_ref().and_then(|md| md.get("ack-topic")).context("ack topic")?;
+    client.send(ack.clone(), Message::new(Vec::new())).await?;
+    let redelivered = tokio::time::timeout(Duration::from_secs(2), subscriptions.next()).await;
+    assert!(redelivered.is_err(), "acknowledged entry was delivered again");
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+#[ignore = "live: needs a reachable Redis; run with --run-ignored"]
+async fn request_reply() -> Result<()> {
+    let stream = unique("omnia-live");
+    let (client, mut subscriptions) = subscribe(&stream).await?;
+
+    let responder = Arc::clone(&client);
+    tokio::spawn(async move {
+        let request = next(&mut subscriptions).await?;
+        let reply = request.reply.context("request has no reply topic")?;
+        let mut response = request.payload;
+        response.reverse();
+        responder.send(reply.topic, Message::new(response)).await
+    });
+
+    let reply = client.request(stream, Message::new(b"ping".to_vec()), None).await?;
+    assert_eq!(reply.payload, b"gnip");
+    Ok(())
+}
+
+/// A messaging client subscribed to `stream`, claiming unacknowledged entries
+/// quickly.
+async fn subscribe(stream: &str) -> Result<(Arc<dyn MessagingClient>, Subscriptions)> {
+    let mut options = <ConnectOptions as FromEnv>::from_env()?;
+    options.streams = Some(StreamOptions {
+        streams: vec![stream.to_owned()],
+        group: "omnia-live".to_owned(),
+        consumer: Some(unique("consumer")),
+        ack: AckMode::Explicit,
+        claim_idle: 500,
+        consumer_expiry: 0,
+        batch_size: 10,
+        block: 100,
+    });
+    let client = Client::connect_with(options).await?;
+    let messaging = WasiMessagingCtx::connect(&client).await?;
+    let subscriptions = messaging.subscribe().await?;
+    Ok((messaging, subscriptions))
+}
+
+async fn next(subscriptions: &mut Subscriptions) -> Result<Message> {
+    tokio::time::timeout(Duration::from_secs(10), subscriptions.next())
+        .await
+        .context("timed out waiting for a message")?
+        .context("subscription ended")
+}